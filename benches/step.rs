@@ -0,0 +1,74 @@
+//! Instruction throughput: `cargo bench --bench step`.
+//!
+//! Medians of nine interleaved runs on one machine with rustc 1.95, in M instructions/s. The
+//! first two rows use the version of this file from the opcode table commit (a single `Machine`
+//! over boxed RAM), which also builds on its parent:
+//!
+//! | Tree                                           | `Machine<RAM>` | `BoxedMachine` |
+//! |------------------------------------------------|----------------|----------------|
+//! | `ops!` mask chain (before the opcode table)    |                | 54.0           |
+//! | precomputed opcode table                       |                | 55.7           |
+//! | hooks, bus log, CPU variants, 8085 interrupts  | 55.7           | 50.2           |
+//! | same, with the fast path in `step_unhooked`    | 56.0           | 51.7           |
+//!
+//! Runs on that machine spread by about 15%, more than any difference in the table, so the
+//! gain of the opcode table measured with older compilers does not show up there.
+//! To compare again, run that version of the file on the commit "Dispatch instructions through
+//! a precomputed opcode table" and on its parent.
+
+use rs580::{BoxedMachine, Machine, Memory, RAM};
+use std::time::Instant;
+
+const STEPS: u64 = 20_000_000;
+
+// A loop with a typical mix of loads, stores, arithmetic, stack and branch instructions.
+const PROGRAM: &[u8] = &[
+    0x31, 0x00, 0x00,   // 0000  LXI SP,0000h
+    0x21, 0x00, 0x10,   // 0003  LXI H,1000h
+    0x11, 0x00, 0x20,   // 0006  LXI D,2000h
+    0x06, 0x00,         // 0009  MVI B,00h
+    0x7E,               // 000B  MOV A,M
+    0x80,               // 000C  ADD B
+    0x12,               // 000D  STAX D
+    0x23,               // 000E  INX H
+    0x13,               // 000F  INX D
+    0xC5,               // 0010  PUSH B
+    0xCD, 0x20, 0x00,   // 0011  CALL 0020h
+    0xC1,               // 0014  POP B
+    0x05,               // 0015  DCR B
+    0xC2, 0x0B, 0x00,   // 0016  JNZ 000Bh
+    0xFB,               // 0019  EI
+    0xC3, 0x03, 0x00,   // 001A  JMP 0003h
+    0x00, 0x00, 0x00,   // 001D
+    0xAF,               // 0020  XRA A
+    0xF6, 0x01,         // 0021  ORI 01h
+    0x27,               // 0023  DAA
+    0xEB,               // 0024  XCHG
+    0xEB,               // 0025  XCHG
+    0xC0,               // 0026  RNZ
+    0xC9,               // 0027  RET
+];
+
+fn run<M: Memory>(name: &str, mut m: Machine<M>) {
+    m.memory.set_range(0, PROGRAM);
+
+    let started = Instant::now();
+    for _ in 0..STEPS {
+        m.step().unwrap();
+    }
+    let elapsed = started.elapsed();
+
+    println!(
+        "{}: {} instructions in {:.3} s: {:.1} M instructions/s, {:.1} MHz equivalent",
+        name,
+        STEPS,
+        elapsed.as_secs_f64(),
+        STEPS as f64 / elapsed.as_secs_f64() / 1e6,
+        m.cycles as f64 / elapsed.as_secs_f64() / 1e6,
+    );
+}
+
+fn main() {
+    run("Machine<RAM>", Machine::new(RAM::default()));
+    run("BoxedMachine", BoxedMachine::new(Box::new(RAM::default())));
+}
@@ -0,0 +1,194 @@
+//! Compile-time Intel 8080 assembly for tests.
+//!
+//! ```
+//! use rs580_macros::asm8080;
+//!
+//! let program = asm8080! {
+//!         ORG 100h;
+//!     start:
+//!         MVI A,9Bh;
+//!         DAA;
+//!         JMP start
+//! };
+//! assert_eq!(program.origin, 0x100);
+//! assert_eq!(program.bytes, [0x3E, 0x9B, 0x27, 0xC3, 0x00, 0x01]);
+//! assert_eq!(program.labels.start, 0x100);
+//! ```
+//!
+//! Every symbol becomes a `u16` field of `labels`, so a misspelt label does not compile.
+//! Characters other than letters, digits and `_`, such as the `.` of local labels, become `_`.
+//!
+//! ```compile_fail
+//! use rs580_macros::asm8080;
+//!
+//! let program = asm8080! { start: NOP };
+//! let _ = program.labels.strat;
+//! ```
+//!
+//! ```
+//! use rs580_macros::asm8080;
+//!
+//! let program = asm8080! {
+//!     outer:
+//!         MVI B,2;
+//!     .loop:
+//!         DCR B;
+//!         JNZ .loop
+//! };
+//! assert_eq!(program.labels.outer_loop, 2);
+//! ```
+//!
+//! The source is written as Rust tokens: `;` separates lines, comments use `//`, and
+//! strings for `DB` must be double-quoted. Rust does not tokenize hexadecimal numbers
+//! which start with a digit and contain `E` followed by a letter, such as `0EDh` or
+//! `0Eh`; write them as `0xED`, or pass the whole source as one string literal, which is
+//! assembled as is with the usual `;` comments:
+//!
+//! ```
+//! use rs580_macros::asm8080;
+//!
+//! let program = asm8080!("
+//!         MVI A,0Eh   ; unmask RST 5.5
+//!         DB 0EDh, 0x0E
+//! ");
+//! assert_eq!(program.bytes, [0x3E, 0x0E, 0xED, 0x0E]);
+//! ```
+
+extern crate proc_macro;
+
+use proc_macro::{Delimiter, Literal, Spacing, TokenStream, TokenTree};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// Assembles 8080 source into a value with `origin: u16`, `bytes: [u8; N]` and `labels`,
+/// a struct with one `u16` field per symbol. Assembly errors are reported as compile errors.
+///
+/// The input is either Rust tokens or a single string literal with the source text.
+#[proc_macro]
+pub fn asm8080(input: TokenStream) -> TokenStream {
+    let trees: Vec<TokenTree> = input.clone().into_iter().collect();
+    let source = match trees.as_slice() {
+        [TokenTree::Literal(literal)] => match string_value(literal) {
+            Some(source) => source,
+            None => return "compile_error!(\"asm8080!: expected a string literal or assembler tokens\")".parse().unwrap(),
+        },
+        _ => {
+            let mut source = String::from(" ");
+            let mut glue = true;
+            write_source(input, &mut source, &mut glue);
+            source
+        },
+    };
+
+    let program = match rs580::asm::assemble(&source) {
+        Ok(program) => program,
+        Err(error) => {
+            let message = format!("asm8080!: line {}: {}", error.line, error.message);
+            return format!("compile_error!({:?})", message).parse().unwrap();
+        },
+    };
+
+    let (origin, bytes) = program.to_bytes();
+    let mut fields = String::new();
+    let mut values = String::new();
+    let mut seen = HashMap::new();
+    for (name, value) in &program.symbols {
+        let field = field_name(name);
+        if let Some(other) = seen.insert(field.clone(), name) {
+            let message = format!("asm8080!: labels {} and {} are both named {} in Rust", other, name, field);
+            return format!("compile_error!({:?})", message).parse().unwrap();
+        }
+        if matches!(field.as_str(), "_" | "self" | "Self" | "super" | "crate") {
+            let message = format!("asm8080!: label {} cannot be a Rust field name", name);
+            return format!("compile_error!({:?})", message).parse().unwrap();
+        }
+        write!(fields, "r#{}: u16, ", field).unwrap();
+        write!(values, "r#{}: {}u16, ", field, value).unwrap();
+    }
+    let bytes: Vec<String> = bytes.iter().map(|b| format!("{}u8", b)).collect();
+    format!(
+        "{{
+            #[allow(dead_code, non_snake_case)]
+            struct Asm8080Labels {{ {fields} }}
+            #[allow(dead_code)]
+            struct Asm8080 {{ origin: u16, bytes: [u8; {len}], labels: Asm8080Labels }}
+            Asm8080 {{ origin: {origin}u16, bytes: [{bytes}], labels: Asm8080Labels {{ {values} }} }}
+        }}",
+        fields = fields,
+        len = bytes.len(),
+        origin = origin,
+        bytes = bytes.join(", "),
+        values = values,
+    ).parse().unwrap()
+}
+
+/// Rust field name of a label.
+fn field_name(label: &str) -> String {
+    label.chars().map(|c| if c.is_alphanumeric() || c == '_' { c } else { '_' }).collect()
+}
+
+/// Value of a plain or raw string literal.
+fn string_value(literal: &Literal) -> Option<String> {
+    let text = literal.to_string();
+    if let Some(raw) = text.strip_prefix('r') {
+        let hashes = raw.len() - raw.trim_start_matches('#').len();
+        return raw.get(hashes + 1..raw.len() - hashes - 1).map(str::to_string);
+    }
+    let body = text.strip_prefix('"')?.strip_suffix('"')?;
+    let mut value = String::new();
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'n' => value.push('\n'),
+            'r' => value.push('\r'),
+            't' => value.push('\t'),
+            '0' => value.push('\0'),
+            '\n' => {
+                while chars.peek().is_some_and(|c| c.is_whitespace()) {
+                    chars.next();
+                }
+            },
+            c @ ('\\' | '"' | '\'') => value.push(c),
+            _ => return None,
+        }
+    }
+    Some(value)
+}
+
+/// Turns tokens back into assembler text. `glue` suppresses the space before the next
+/// token, so `.loop` and `<<` survive tokenization.
+fn write_source(input: TokenStream, source: &mut String, glue: &mut bool) {
+    for tree in input {
+        if !*glue {
+            source.push(' ');
+        }
+        *glue = false;
+        match tree {
+            TokenTree::Punct(punct) if punct.as_char() == ';' => {
+                source.push_str("\n ");
+                *glue = true;
+            },
+            TokenTree::Punct(punct) => {
+                source.push(punct.as_char());
+                *glue = punct.as_char() == '.' || punct.spacing() == Spacing::Joint;
+            },
+            TokenTree::Group(group) => {
+                let (open, close) = match group.delimiter() {
+                    Delimiter::Parenthesis => ("(", ")"),
+                    Delimiter::Bracket => ("[", "]"),
+                    Delimiter::Brace => ("{", "}"),
+                    Delimiter::None => ("", ""),
+                };
+                source.push_str(open);
+                *glue = true;
+                write_source(group.stream(), source, glue);
+                source.push_str(close);
+            },
+            tree => source.push_str(&tree.to_string()),
+        }
+    }
+}
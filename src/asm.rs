@@ -0,0 +1,985 @@
+//! Intel 8080 assembler.
+//!
+//! The 8085 instructions `RIM`, `SIM` and the undocumented `DSUB`, `ARHL`, `RDEL`, `LDHI`,
+//! `LDSI`, `SHLX`, `LHLX`, `RSTV`, `JNK` and `JK` are accepted as well.
+//!
+//! Supports labels (`name:` or a name in the first column), local labels starting with
+//! a dot which belong to the preceding global label, `$` for the current address,
+//! expressions, and the `ORG`, `DB`, `DW`, `DS`, `EQU` and `END` directives.
+//!
+//! ```
+//! let program = rs580::asm::assemble("
+//!         ORG 100h
+//! start:  MVI A,9Bh
+//!         DAA
+//!         HLT
+//! ").unwrap();
+//! assert_eq!(program.symbol("start"), Some(0x100));
+//! assert_eq!(program.to_bytes(), (0x100, vec![0x3E, 0x9B, 0x27, 0x76]));
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+use crate::memory::Memory;
+
+const MAX_PASSES: usize = 16;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsmError {
+    /// 1-based source line.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// Contiguous block of assembled bytes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListingLine {
+    /// 1-based source line.
+    pub line: usize,
+    /// Address of the line, or the value for EQU.
+    pub address: Option<u16>,
+    pub bytes: Vec<u8>,
+    pub source: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Program {
+    pub segments: Vec<Segment>,
+    pub symbols: BTreeMap<String, u16>,
+    pub listing: Vec<ListingLine>,
+}
+
+impl Program {
+    pub fn symbol(&self, name: &str) -> Option<u16> {
+        self.symbols.get(name).cloned()
+    }
+
+    pub fn load_into<M: Memory + ?Sized>(&self, memory: &mut M) {
+        for segment in &self.segments {
+            memory.set_range(segment.address, &segment.bytes);
+        }
+    }
+
+    /// Flat image from the lowest to the highest assembled address with gaps filled by zeros.
+    pub fn to_bytes(&self) -> (u16, Vec<u8>) {
+        let from = match self.segments.iter().map(|s| s.address).min() {
+            Some(from) => from,
+            None => return (0, Vec::new()),
+        };
+        let to = self.segments.iter().map(|s| s.address as usize + s.bytes.len()).max().unwrap_or(0);
+        let mut image = vec![0; to - from as usize];
+        for segment in &self.segments {
+            let offset = (segment.address - from) as usize;
+            image[offset..offset + segment.bytes.len()].copy_from_slice(&segment.bytes);
+        }
+        (from, image)
+    }
+
+    pub fn listing_text(&self) -> String {
+        let mut text = String::new();
+        for line in &self.listing {
+            let address = match line.address {
+                Some(address) => format!("{:04X}", address),
+                None => "    ".to_string(),
+            };
+            let bytes: Vec<String> = line.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            text += format!("{:5}  {}  {:<12} {}", line.line, address, bytes.join(" "), line.source).trim_end();
+            text += "\n";
+        }
+        text
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Program, AsmError> {
+    let mut symbols = BTreeMap::new();
+    for _ in 0..MAX_PASSES {
+        let pass = Pass::run(source, &symbols, false)?;
+        let stable = pass.symbols == symbols;
+        symbols = pass.symbols;
+        if stable {
+            return Pass::run(source, &symbols, true).map(Pass::into_program);
+        }
+    }
+    Err(AsmError { line: 0, message: "symbol values do not settle".to_string() })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(i64),
+    Str(Vec<u8>),
+    Dollar,
+    Colon,
+    Comma,
+    LParen,
+    RParen,
+    Op(&'static str),
+}
+
+fn lex(line: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == ';' {
+            break;
+        } else if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            tokens.push(Token::Number(parse_number(&text)?));
+        } else if c == '$' && i + 1 < chars.len() && chars[i + 1].is_ascii_hexdigit() {
+            let start = i + 1;
+            i += 1;
+            while i < chars.len() && chars[i].is_ascii_hexdigit() {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            tokens.push(Token::Number(i64::from_str_radix(&text, 16).map_err(|_| format!("bad number ${}", text))?));
+        } else if c.is_alphabetic() || c == '_' || c == '.' || c == '?' || c == '@' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || "_.?@$".contains(chars[i])) {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else if c == '\'' || c == '"' {
+            let mut bytes = Vec::new();
+            i += 1;
+            loop {
+                if i >= chars.len() {
+                    return Err("unterminated string".to_string());
+                }
+                if chars[i] == c {
+                    if i + 1 < chars.len() && chars[i + 1] == c {
+                        bytes.push(c as u8);
+                        i += 2;
+                        continue;
+                    }
+                    i += 1;
+                    break;
+                }
+                if !chars[i].is_ascii() {
+                    return Err(format!("non-ASCII character '{}' in string", chars[i]));
+                }
+                bytes.push(chars[i] as u8);
+                i += 1;
+            }
+            tokens.push(Token::Str(bytes));
+        } else {
+            let two: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            let token = match two.as_str() {
+                "<<" => Some(Token::Op("<<")),
+                ">>" => Some(Token::Op(">>")),
+                _ => None,
+            };
+            if let Some(token) = token {
+                tokens.push(token);
+                i += 2;
+                continue;
+            }
+            tokens.push(match c {
+                '$' => Token::Dollar,
+                ':' => Token::Colon,
+                ',' => Token::Comma,
+                '(' => Token::LParen,
+                ')' => Token::RParen,
+                '+' => Token::Op("+"),
+                '-' => Token::Op("-"),
+                '*' => Token::Op("*"),
+                '/' => Token::Op("/"),
+                '%' => Token::Op("%"),
+                '&' => Token::Op("&"),
+                '|' => Token::Op("|"),
+                '^' => Token::Op("^"),
+                '~' => Token::Op("~"),
+                _ => return Err(format!("unexpected character '{}'", c)),
+            });
+            i += 1;
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_number(text: &str) -> Result<i64, String> {
+    let upper = text.to_ascii_uppercase().replace('_', "");
+    let error = || format!("bad number {}", text);
+    if let Some(hex) = upper.strip_prefix("0X") {
+        return i64::from_str_radix(hex, 16).map_err(|_| error());
+    }
+    let (digits, radix) = match upper.as_bytes()[upper.len() - 1] {
+        b'H' => (&upper[..upper.len() - 1], 16),
+        b'B' => (&upper[..upper.len() - 1], 2),
+        b'O' | b'Q' => (&upper[..upper.len() - 1], 8),
+        b'D' => (&upper[..upper.len() - 1], 10),
+        _ => (&upper[..], 10),
+    };
+    i64::from_str_radix(digits, radix).map_err(|_| error())
+}
+
+fn ident_is(token: Option<&Token>, word: &str) -> bool {
+    matches!(token, Some(Token::Ident(name)) if name.eq_ignore_ascii_case(word))
+}
+
+const DIRECTIVES: &[&str] = &["ORG", "DB", "DEFB", "DW", "DEFW", "DS", "DEFS", "EQU", "END"];
+
+const MNEMONICS: &[&str] = &[
+    "NOP", "RLC", "RRC", "RAL", "RAR", "DAA", "CMA", "STC", "CMC", "HLT", "RET", "XTHL", "PCHL",
+    "XCHG", "DI", "EI", "SPHL", "RNZ", "RZ", "RNC", "RC", "RPO", "RPE", "RP", "RM",
+    "INR", "DCR", "ADD", "ADC", "SUB", "SBB", "ANA", "XRA", "ORA", "CMP",
+    "INX", "DCX", "DAD", "LXI", "STAX", "LDAX", "PUSH", "POP", "MOV", "MVI",
+    "ADI", "ACI", "SUI", "SBI", "ANI", "XRI", "ORI", "CPI", "IN", "OUT",
+    "JMP", "JNZ", "JZ", "JNC", "JC", "JPO", "JPE", "JP", "JM",
+    "CALL", "CNZ", "CZ", "CNC", "CC", "CPO", "CPE", "CP", "CM",
+    "LDA", "STA", "LHLD", "SHLD", "RST",
+    "RIM", "SIM", "DSUB", "ARHL", "RDEL", "LDHI", "LDSI", "SHLX", "LHLX", "RSTV", "JNK", "JK",
+];
+
+fn is_keyword(name: &str) -> bool {
+    let upper = name.to_ascii_uppercase();
+    DIRECTIVES.contains(&upper.as_str()) || MNEMONICS.contains(&upper.as_str())
+}
+
+fn condition(suffix: &str) -> Option<u8> {
+    ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"].iter().position(|c| *c == suffix).map(|c| c as u8)
+}
+
+struct Pass<'a> {
+    previous: &'a BTreeMap<String, u16>,
+    strict: bool,
+    address: u32,
+    scope: String,
+    symbols: BTreeMap<String, u16>,
+    segments: Vec<Segment>,
+    listing: Vec<ListingLine>,
+}
+
+impl<'a> Pass<'a> {
+    fn run(source: &str, previous: &'a BTreeMap<String, u16>, strict: bool) -> Result<Self, AsmError> {
+        let mut pass = Pass {
+            previous,
+            strict,
+            address: 0,
+            scope: String::new(),
+            symbols: BTreeMap::new(),
+            segments: Vec::new(),
+            listing: Vec::new(),
+        };
+        for (index, text) in source.lines().enumerate() {
+            let line = index + 1;
+            let start = pass.address;
+            let (address, bytes, end) = pass.line(text)
+                .map_err(|message| AsmError { line, message })?;
+            if start + bytes.len() as u32 > 0x10000 {
+                return Err(AsmError { line, message: "code runs past 0FFFFh".to_string() });
+            }
+            if !bytes.is_empty() {
+                match pass.segments.last_mut() {
+                    Some(segment) if segment.address as u32 + segment.bytes.len() as u32 == start => {
+                        segment.bytes.extend_from_slice(&bytes);
+                    },
+                    _ => pass.segments.push(Segment { address: start as u16, bytes: bytes.clone() }),
+                }
+                pass.address += bytes.len() as u32;
+            }
+            pass.listing.push(ListingLine { line, address, bytes, source: text.to_string() });
+            if end {
+                break;
+            }
+        }
+        Ok(pass)
+    }
+
+    fn into_program(self) -> Program {
+        Program {
+            segments: self.segments,
+            symbols: self.symbols,
+            listing: self.listing,
+        }
+    }
+
+    fn qualify(&self, name: &str) -> String {
+        if name.starts_with('.') {
+            format!("{}{}", self.scope, name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    fn define(&mut self, name: &str, value: u16) -> Result<(), String> {
+        let name = self.qualify(name);
+        if is_keyword(&name) {
+            return Err(format!("{} is a reserved word", name));
+        }
+        if self.symbols.insert(name.clone(), value).is_some() {
+            return Err(format!("{} is already defined", name));
+        }
+        Ok(())
+    }
+
+    /// Assembles one line. Returns the listing address, the emitted bytes and whether END was met.
+    fn line(&mut self, text: &str) -> Result<(Option<u16>, Vec<u8>, bool), String> {
+        let tokens = lex(text)?;
+        let first_column = !text.starts_with(char::is_whitespace);
+
+        let mut i = 0;
+        let mut label = None;
+        if let Some(Token::Ident(name)) = tokens.first() {
+            if tokens.get(1) == Some(&Token::Colon) {
+                label = Some(name.clone());
+                i = 2;
+            } else if (first_column && !is_keyword(name)) || ident_is(tokens.get(1), "EQU") {
+                label = Some(name.clone());
+                i = 1;
+            }
+        }
+
+        let mnemonic = match tokens.get(i) {
+            Some(Token::Ident(name)) => name.to_ascii_uppercase(),
+            None => {
+                if let Some(label) = label {
+                    self.define_label(&label)?;
+                }
+                return Ok((Some(self.address as u16), vec![], false));
+            },
+            Some(token) => return Err(format!("unexpected {:?}", token)),
+        };
+        let operands = split_operands(&tokens[i + 1..])?;
+
+        if mnemonic == "EQU" {
+            let label = label.ok_or("EQU needs a name")?;
+            let value = self.value16(one(&operands)?)?;
+            self.define(&label, value)?;
+            return Ok((Some(value), vec![], false));
+        }
+        if let Some(label) = label {
+            self.define_label(&label)?;
+        }
+
+        let address = Some(self.address as u16);
+        match mnemonic.as_str() {
+            "ORG" => {
+                self.address = self.value16(one(&operands)?)? as u32;
+                Ok((Some(self.address as u16), vec![], false))
+            },
+            "END" => Ok((address, vec![], true)),
+            "DS" | "DEFS" => {
+                let size = self.eval(one(&operands)?)?;
+                if size < 0 || self.address as i64 + size > 0x10000 {
+                    return Err(format!("bad DS size {}", size));
+                }
+                self.address += size as u32;
+                Ok((address, vec![], false))
+            },
+            "DB" | "DEFB" => {
+                let mut bytes = Vec::new();
+                for operand in &operands {
+                    match operand.as_slice() {
+                        [Token::Str(string)] if string.len() != 1 => bytes.extend_from_slice(string),
+                        _ => bytes.push(self.value8(operand)?),
+                    }
+                }
+                Ok((address, bytes, false))
+            },
+            "DW" | "DEFW" => {
+                let mut bytes = Vec::new();
+                for operand in &operands {
+                    let value = self.value16(operand)?;
+                    bytes.push(value as u8);
+                    bytes.push((value >> 8) as u8);
+                }
+                Ok((address, bytes, false))
+            },
+            _ => Ok((address, self.instruction(&mnemonic, &operands)?, false)),
+        }
+    }
+
+    fn define_label(&mut self, label: &str) -> Result<(), String> {
+        if !label.starts_with('.') {
+            self.scope = label.to_string();
+        }
+        self.define(label, self.address as u16)
+    }
+
+    fn instruction(&self, mnemonic: &str, operands: &[Vec<Token>]) -> Result<Vec<u8>, String> {
+        let count = |n: usize| {
+            if operands.len() == n {
+                Ok(())
+            } else {
+                Err(format!("{} takes {} operand(s)", mnemonic, n))
+            }
+        };
+        let implied = match mnemonic {
+            "NOP" => Some(0x00), "RLC" => Some(0x07), "RRC" => Some(0x0F), "RAL" => Some(0x17),
+            "RAR" => Some(0x1F), "DAA" => Some(0x27), "CMA" => Some(0x2F), "STC" => Some(0x37),
+            "CMC" => Some(0x3F), "HLT" => Some(0x76), "RET" => Some(0xC9), "XTHL" => Some(0xE3),
+            "PCHL" => Some(0xE9), "XCHG" => Some(0xEB), "DI" => Some(0xF3), "SPHL" => Some(0xF9),
+            "EI" => Some(0xFB),
+            "DSUB" => Some(0x08), "ARHL" => Some(0x10), "RDEL" => Some(0x18), "RIM" => Some(0x20),
+            "SIM" => Some(0x30), "RSTV" => Some(0xCB), "SHLX" => Some(0xD9), "LHLX" => Some(0xED),
+            _ => None,
+        };
+        if let Some(opcode) = implied {
+            count(0)?;
+            return Ok(vec![opcode]);
+        }
+
+        let alu = ["ADD", "ADC", "SUB", "SBB", "ANA", "XRA", "ORA", "CMP"].iter().position(|m| *m == mnemonic);
+        let alu_immediate = ["ADI", "ACI", "SUI", "SBI", "ANI", "XRI", "ORI", "CPI"].iter().position(|m| *m == mnemonic);
+        let imm8 = |opcode: u8| -> Result<Vec<u8>, String> {
+            count(1)?;
+            Ok(vec![opcode, self.value8(&operands[0])?])
+        };
+        let addr16 = |opcode: u8| -> Result<Vec<u8>, String> {
+            count(1)?;
+            let value = self.value16(&operands[0])?;
+            Ok(vec![opcode, value as u8, (value >> 8) as u8])
+        };
+
+        if let Some(operation) = alu {
+            count(1)?;
+            return Ok(vec![0x80 | (operation as u8) << 3 | register(&operands[0])?]);
+        }
+        if let Some(operation) = alu_immediate {
+            return imm8(0xC6 | (operation as u8) << 3);
+        }
+        if mnemonic.len() >= 2 && mnemonic.starts_with('R') && mnemonic != "RST" {
+            if let Some(cond) = condition(&mnemonic[1..]) {
+                count(0)?;
+                return Ok(vec![0xC0 | cond << 3]);
+            }
+        }
+        if mnemonic.len() >= 2 && mnemonic.starts_with('J') && mnemonic != "JMP" {
+            if let Some(cond) = condition(&mnemonic[1..]) {
+                return addr16(0xC2 | cond << 3);
+            }
+        }
+        if mnemonic.len() >= 2 && mnemonic.starts_with('C') && !["CALL", "CMA", "CMC", "CMP", "CPI"].contains(&mnemonic) {
+            if let Some(cond) = condition(&mnemonic[1..]) {
+                return addr16(0xC4 | cond << 3);
+            }
+        }
+
+        match mnemonic {
+            "INR" | "DCR" => {
+                count(1)?;
+                let base = if mnemonic == "INR" { 0x04 } else { 0x05 };
+                Ok(vec![base | register(&operands[0])? << 3])
+            },
+            "INX" | "DCX" | "DAD" => {
+                count(1)?;
+                let base = match mnemonic { "INX" => 0x03, "DCX" => 0x0B, _ => 0x09 };
+                Ok(vec![base | pair(&operands[0], "SP")? << 4])
+            },
+            "STAX" | "LDAX" => {
+                count(1)?;
+                let rp = pair(&operands[0], "SP")?;
+                if rp > 1 {
+                    return Err(format!("{} takes B or D", mnemonic));
+                }
+                Ok(vec![if mnemonic == "STAX" { 0x02 } else { 0x0A } | rp << 4])
+            },
+            "PUSH" | "POP" => {
+                count(1)?;
+                let base = if mnemonic == "PUSH" { 0xC5 } else { 0xC1 };
+                Ok(vec![base | pair(&operands[0], "PSW")? << 4])
+            },
+            "LXI" => {
+                count(2)?;
+                let value = self.value16(&operands[1])?;
+                Ok(vec![0x01 | pair(&operands[0], "SP")? << 4, value as u8, (value >> 8) as u8])
+            },
+            "MOV" => {
+                count(2)?;
+                let (dst, src) = (register(&operands[0])?, register(&operands[1])?);
+                if dst == 6 && src == 6 {
+                    return Err("MOV M,M is HLT".to_string());
+                }
+                Ok(vec![0x40 | dst << 3 | src])
+            },
+            "MVI" => {
+                count(2)?;
+                Ok(vec![0x06 | register(&operands[0])? << 3, self.value8(&operands[1])?])
+            },
+            "IN" => imm8(0xDB),
+            "OUT" => imm8(0xD3),
+            "JMP" => addr16(0xC3),
+            "CALL" => addr16(0xCD),
+            "LDA" => addr16(0x3A),
+            "STA" => addr16(0x32),
+            "LHLD" => addr16(0x2A),
+            "SHLD" => addr16(0x22),
+            "LDHI" => imm8(0x28),
+            "LDSI" => imm8(0x38),
+            "JNK" => addr16(0xDD),
+            "JK" => addr16(0xFD),
+            "RST" => {
+                count(1)?;
+                let n = self.eval(&operands[0])?;
+                if !(0..8).contains(&n) {
+                    return Err(format!("bad RST number {}", n));
+                }
+                Ok(vec![0xC7 | (n as u8) << 3])
+            },
+            _ => Err(format!("unknown instruction {}", mnemonic)),
+        }
+    }
+
+    fn value8(&self, tokens: &[Token]) -> Result<u8, String> {
+        let value = self.eval(tokens)?;
+        if self.strict && !(-128..=255).contains(&value) {
+            return Err(format!("value {} does not fit in a byte", value));
+        }
+        Ok(value as u8)
+    }
+
+    fn value16(&self, tokens: &[Token]) -> Result<u16, String> {
+        let value = self.eval(tokens)?;
+        if self.strict && !(-32768..=65535).contains(&value) {
+            return Err(format!("value {} does not fit in a word", value));
+        }
+        Ok(value as u16)
+    }
+
+    fn eval(&self, tokens: &[Token]) -> Result<i64, String> {
+        let mut parser = Expr { pass: self, tokens, pos: 0 };
+        let value = parser.or()?;
+        if parser.pos != tokens.len() {
+            return Err(format!("unexpected {:?} in expression", tokens[parser.pos]));
+        }
+        Ok(value)
+    }
+
+    fn lookup(&self, name: &str) -> Result<i64, String> {
+        let name = self.qualify(name);
+        match self.symbols.get(&name).or_else(|| self.previous.get(&name)) {
+            Some(value) => Ok(*value as i64),
+            None if self.strict => Err(format!("undefined symbol {}", name)),
+            None => Ok(0),
+        }
+    }
+}
+
+/// Expressions are evaluated in 64 bits; a result that does not fit is an error, not a silent wrap.
+fn checked(value: Option<i64>) -> Result<i64, String> {
+    value.ok_or_else(|| "arithmetic overflow".to_string())
+}
+
+fn one(operands: &[Vec<Token>]) -> Result<&[Token], String> {
+    match operands {
+        [operand] => Ok(operand),
+        _ => Err("one operand expected".to_string()),
+    }
+}
+
+fn split_operands(tokens: &[Token]) -> Result<Vec<Vec<Token>>, String> {
+    let mut operands = Vec::new();
+    if tokens.is_empty() {
+        return Ok(operands);
+    }
+    let mut current = Vec::new();
+    let mut depth = 0;
+    for token in tokens {
+        match token {
+            Token::LParen => depth += 1,
+            Token::RParen => depth -= 1,
+            Token::Comma if depth == 0 => {
+                operands.push(std::mem::take(&mut current));
+                continue;
+            },
+            _ => {},
+        }
+        current.push(token.clone());
+    }
+    operands.push(current);
+    if operands.iter().any(|operand| operand.is_empty()) {
+        return Err("empty operand".to_string());
+    }
+    Ok(operands)
+}
+
+fn register(tokens: &[Token]) -> Result<u8, String> {
+    if let [Token::Ident(name)] = tokens {
+        let upper = name.to_ascii_uppercase();
+        if let Some(r) = ["B", "C", "D", "E", "H", "L", "M", "A"].iter().position(|r| *r == upper) {
+            return Ok(r as u8);
+        }
+    }
+    Err(format!("register expected, got {}", describe(tokens)))
+}
+
+fn pair(tokens: &[Token], last: &str) -> Result<u8, String> {
+    if let [Token::Ident(name)] = tokens {
+        let upper = name.to_ascii_uppercase();
+        if let Some(rp) = ["B", "D", "H", last].iter().position(|r| *r == upper) {
+            return Ok(rp as u8);
+        }
+    }
+    Err(format!("register pair expected, got {}", describe(tokens)))
+}
+
+fn describe(tokens: &[Token]) -> String {
+    let parts: Vec<String> = tokens.iter().map(|token| match token {
+        Token::Ident(name) => name.clone(),
+        Token::Number(value) => value.to_string(),
+        token => format!("{:?}", token),
+    }).collect();
+    parts.join(" ")
+}
+
+struct Expr<'p, 'a> {
+    pass: &'p Pass<'a>,
+    tokens: &'p [Token],
+    pos: usize,
+}
+
+impl<'p, 'a> Expr<'p, 'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    /// Consumes a binary operator from `ops` (symbols or words).
+    fn operator(&mut self, ops: &[&'static str]) -> Option<&'static str> {
+        let found = match self.peek() {
+            Some(Token::Op(op)) => ops.iter().find(|o| *o == op).cloned(),
+            Some(Token::Ident(word)) => ops.iter().find(|o| o.eq_ignore_ascii_case(word)).cloned(),
+            _ => None,
+        };
+        if found.is_some() {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<i64, String> {
+        let mut value = self.and()?;
+        while let Some(op) = self.operator(&["|", "^", "OR", "XOR"]) {
+            let rhs = self.and()?;
+            value = if op == "|" || op == "OR" { value | rhs } else { value ^ rhs };
+        }
+        Ok(value)
+    }
+
+    fn and(&mut self) -> Result<i64, String> {
+        let mut value = self.shift()?;
+        while self.operator(&["&", "AND"]).is_some() {
+            value &= self.shift()?;
+        }
+        Ok(value)
+    }
+
+    fn shift(&mut self) -> Result<i64, String> {
+        let mut value = self.sum()?;
+        while let Some(op) = self.operator(&["<<", ">>", "SHL", "SHR"]) {
+            let rhs = self.sum()?;
+            if !(0..64).contains(&rhs) {
+                return Err(format!("bad shift {}", rhs));
+            }
+            value = if op == "<<" || op == "SHL" { value << rhs } else { value >> rhs };
+        }
+        Ok(value)
+    }
+
+    fn sum(&mut self) -> Result<i64, String> {
+        let mut value = self.product()?;
+        while let Some(op) = self.operator(&["+", "-"]) {
+            let rhs = self.product()?;
+            value = checked(if op == "+" { value.checked_add(rhs) } else { value.checked_sub(rhs) })?;
+        }
+        Ok(value)
+    }
+
+    fn product(&mut self) -> Result<i64, String> {
+        let mut value = self.unary()?;
+        while let Some(op) = self.operator(&["*", "/", "%", "MOD"]) {
+            let rhs = self.unary()?;
+            value = match op {
+                "*" => checked(value.checked_mul(rhs))?,
+                _ if rhs == 0 => {
+                    if self.pass.strict {
+                        return Err("division by zero".to_string());
+                    }
+                    0
+                },
+                "/" => checked(value.checked_div(rhs))?,
+                _ => checked(value.checked_rem(rhs))?,
+            };
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        match self.operator(&["-", "+", "~", "NOT", "HIGH", "LOW"]) {
+            Some("-") => checked(self.unary()?.checked_neg()),
+            Some("+") => self.unary(),
+            Some("~") | Some("NOT") => Ok(!self.unary()?),
+            Some("HIGH") => Ok((self.unary()? >> 8) & 0xFF),
+            Some("LOW") => Ok(self.unary()? & 0xFF),
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<i64, String> {
+        let token = self.peek().cloned().ok_or("expression expected")?;
+        self.pos += 1;
+        match token {
+            Token::Number(value) => Ok(value),
+            Token::Dollar => Ok(self.pass.address as i64),
+            Token::Str(ref bytes) if bytes.len() == 1 => Ok(bytes[0] as i64),
+            Token::Str(ref bytes) if bytes.len() == 2 => Ok((bytes[0] as i64) << 8 | bytes[1] as i64),
+            Token::Ident(name) => self.pass.lookup(&name),
+            Token::LParen => {
+                let value = self.or()?;
+                if self.peek() != Some(&Token::RParen) {
+                    return Err("')' expected".to_string());
+                }
+                self.pos += 1;
+                Ok(value)
+            },
+            token => Err(format!("unexpected {:?} in expression", token)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Machine;
+    use crate::cpu::CpuVariant;
+    use crate::disasm::disassemble_range;
+    use crate::ram::RAM;
+
+    #[test]
+    fn test_labels_and_directives() {
+        let program = assemble("
+COUNT   EQU 3
+        ORG 100h
+start:  LXI H,data
+        MVI B,COUNT
+.loop:  MOV A,M
+        INX H
+        DCR B
+        JNZ .loop
+        JMP done
+data:   DB 1, 2, 'AB', 'C'+80h
+        DW start, $
+        DS 2
+done:   HLT
+other:
+.loop   JMP .loop
+").unwrap();
+        assert_eq!(program.symbol("COUNT"), Some(3));
+        assert_eq!(program.symbol("start"), Some(0x100));
+        assert_eq!(program.symbol("start.loop"), Some(0x105));
+        assert_eq!(program.symbol("data"), Some(0x10E));
+        assert_eq!(program.symbol("done"), Some(0x119));
+        assert_eq!(program.symbol("other.loop"), Some(0x11A));
+        assert_eq!(program.segments.len(), 2);
+        assert_eq!(program.segments[0].bytes, vec![
+            0x21, 0x0E, 0x01, 0x06, 0x03, 0x7E, 0x23, 0x05, 0xC2, 0x05, 0x01, 0xC3, 0x19, 0x01,
+            0x01, 0x02, 0x41, 0x42, 0xC3, 0x00, 0x01, 0x13, 0x01,
+        ]);
+        assert_eq!(program.segments[1], Segment { address: 0x119, bytes: vec![0x76, 0xC3, 0x1A, 0x01] });
+    }
+
+    #[test]
+    fn test_expressions() {
+        let program = assemble("
+        DB 10 + 2 * 3, (10 + 2) * 3, -1, 0FFh AND 0Fh, 1 SHL 4, 17 MOD 5, 101b, 17o, 0x1F
+        DB HIGH 1234h, LOW 1234h, ~0 & 0FFh, $10, 8 >> 1 | 1
+        DW -2
+").unwrap();
+        assert_eq!(program.to_bytes().1, vec![
+            16, 36, 0xFF, 0x0F, 16, 2, 5, 15, 31,
+            0x12, 0x34, 0xFF, 0x10, 5,
+            0xFE, 0xFF,
+        ]);
+    }
+
+    #[test]
+    fn test_all_instructions_round_trip() {
+        let source = "
+    NOP
+    LXI B,1234H
+    STAX B
+    INX SP
+    INR M
+    DCR A
+    MVI L,07H
+    RLC
+    DAD D
+    LDAX D
+    DCX H
+    RRC
+    RAL
+    RAR
+    SHLD 0001H
+    DAA
+    LHLD 0002H
+    CMA
+    STA 0003H
+    STC
+    LDA 0004H
+    CMC
+    MOV B,C
+    MOV M,A
+    HLT
+    ADD B
+    ADC C
+    SUB D
+    SBB E
+    ANA H
+    XRA L
+    ORA M
+    CMP A
+    RNZ
+    POP PSW
+    JNC 0005H
+    JMP 0006H
+    CPE 0007H
+    PUSH H
+    ADI 01H
+    ACI 02H
+    SUI 03H
+    SBI 04H
+    ANI 05H
+    XRI 06H
+    ORI 07H
+    CPI 08H
+    RST 7
+    RET
+    CALL 0009H
+    OUT 10H
+    IN 11H
+    XTHL
+    PCHL
+    XCHG
+    DI
+    SPHL
+    EI
+";
+        let program = assemble(source).unwrap();
+        let mut memory = RAM::default();
+        program.load_into(&mut memory);
+        let (from, image) = program.to_bytes();
+        let actual: Vec<String> = disassemble_range(&memory, from, from + image.len() as u16, CpuVariant::Intel8080)
+            .iter()
+            .map(|instruction| instruction.to_string())
+            .collect();
+        let expected: Vec<&str> = source.lines().map(str::trim).filter(|line| !line.is_empty()).collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_8085_round_trip() {
+        let source = "
+    RIM
+    SIM
+    DSUB
+    ARHL
+    RDEL
+    LDHI 10H
+    LDSI 20H
+    SHLX
+    LHLX
+    RSTV
+    JNK 1234H
+    JK 5678H
+";
+        let program = assemble(source).unwrap();
+        let mut memory = RAM::default();
+        program.load_into(&mut memory);
+        let (from, image) = program.to_bytes();
+        let actual: Vec<String> = disassemble_range(&memory, from, from + image.len() as u16, CpuVariant::Intel8085)
+            .iter()
+            .map(|instruction| instruction.to_string().trim_start_matches('*').to_string())
+            .collect();
+        let expected: Vec<&str> = source.lines().map(str::trim).filter(|line| !line.is_empty()).collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(assemble("  MOV A").unwrap_err(), AsmError { line: 1, message: "MOV takes 2 operand(s)".to_string() });
+        assert_eq!(assemble("\n  JMP nowhere").unwrap_err().message, "undefined symbol nowhere");
+        assert_eq!(assemble("x: NOP\nx: NOP").unwrap_err().message, "x is already defined");
+        assert_eq!(assemble("  MVI A,300").unwrap_err().message, "value 300 does not fit in a byte");
+        assert_eq!(assemble("  FOO").unwrap_err().message, "unknown instruction FOO");
+        assert_eq!(assemble("  LXI Q,1").unwrap_err().message, "register pair expected, got Q");
+        assert_eq!(assemble("  DB 'abc").unwrap_err().message, "unterminated string");
+    }
+
+    #[test]
+    fn test_overflow() {
+        for source in &[
+            "  DB (1 SHL 62)+(1 SHL 62)",
+            "  DW -(1 SHL 63)",
+            "  DW (1 SHL 63)-1",
+            "  DW (1 SHL 32)*(1 SHL 32)",
+            "  DW (1 SHL 63)/-1",
+            "  DW (1 SHL 63) MOD -1",
+        ] {
+            assert_eq!(assemble(source).unwrap_err().message, "arithmetic overflow", "{}", source);
+        }
+        assert_eq!(assemble("  DW -(1 SHL 62)*2/(1 SHL 62)").unwrap().segments[0].bytes, vec![0xFE, 0xFF]);
+    }
+
+    #[test]
+    fn test_forward_references() {
+        let program = assemble("
+        LXI SP,stack
+        DS size
+size    EQU finish - start
+start:  NOP
+        NOP
+finish:
+stack   EQU 100h
+").unwrap();
+        assert_eq!(program.symbol("size"), Some(2));
+        assert_eq!(program.symbol("start"), Some(5));
+    }
+
+    #[test]
+    fn test_run_assembled_program() {
+        let program = assemble("
+        LXI SP,100h
+        MVI A,9Bh
+        DAA
+        CALL store
+        HLT
+store:  STA result
+        RET
+result: DS 1
+").unwrap();
+        let mut m = Machine::new(RAM::default());
+        program.load_into(&mut m.memory);
+        while !m.halted {
+            m.step().unwrap();
+        }
+        assert_eq!(m.memory.get_u8(program.symbol("result").unwrap()), 1);
+    }
+
+    #[test]
+    fn test_listing() {
+        let program = assemble("        ORG 10h\nstart:  MVI A,1 ; one\n").unwrap();
+        assert_eq!(program.listing_text(), "    1  0010                       ORG 10h\n    2  0010  3E 01        start:  MVI A,1 ; one\n");
+    }
+}
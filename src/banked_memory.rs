@@ -0,0 +1,224 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use crate::memory::Memory;
+use crate::io::Io;
+use crate::snapshot::{SnapshotError, StateReader, StateWriter};
+
+/// Bank select control register.
+///
+/// Clones share the same value, so one clone may be mapped to a port (`Io`) or
+/// to a memory address (`Memory`) and the others handed to `BankedMemory` windows.
+#[derive(Clone, Default)]
+pub struct BankRegister(Rc<Cell<u8>>);
+
+impl BankRegister {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self) -> u8 {
+        self.0.get()
+    }
+
+    pub fn set(&self, value: u8) {
+        self.0.set(value);
+    }
+}
+
+impl Memory for BankRegister {
+    #[inline]
+    fn get_u8(&self, _addr: u16) -> u8 {
+        self.get()
+    }
+
+    #[inline]
+    fn set_u8(&mut self, _addr: u16, value: u8) {
+        self.set(value);
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.get());
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SnapshotError> {
+        self.set(reader.read_u8()?);
+        Ok(())
+    }
+}
+
+impl Io for BankRegister {
+    #[inline]
+    fn inp(&mut self, _port: u8) -> u8 {
+        self.get()
+    }
+
+    #[inline]
+    fn out(&mut self, _port: u8, value: u8) {
+        self.set(value);
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.get());
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SnapshotError> {
+        self.set(reader.read_u8()?);
+        Ok(())
+    }
+}
+
+/// Memory window showing one of several banks.
+/// The active bank is `(register >> shift) & mask`; a missing bank reads as 0xFF.
+pub struct BankedMemory {
+    register: BankRegister,
+    shift: u8,
+    mask: u8,
+    banks: Vec<Box<dyn Memory>>,
+}
+
+impl BankedMemory {
+    pub fn new(register: BankRegister) -> Self {
+        Self {
+            register,
+            shift: 0,
+            mask: 0xFF,
+            banks: Vec::new(),
+        }
+    }
+
+    /// Selects which bits of the control register choose the bank of this window.
+    pub fn select_bits(mut self, shift: u8, mask: u8) -> Self {
+        self.shift = shift;
+        self.mask = mask;
+        self
+    }
+
+    pub fn add_bank(mut self, bank: Box<dyn Memory>) -> Self {
+        self.banks.push(bank);
+        self
+    }
+
+    pub fn active_bank(&self) -> usize {
+        ((self.register.get() >> self.shift) & self.mask) as usize
+    }
+
+    pub fn bank(&self, index: usize) -> Option<&dyn Memory> {
+        self.banks.get(index).map(|bank| bank.as_ref())
+    }
+
+    pub fn bank_mut(&mut self, index: usize) -> Option<&mut (dyn Memory + 'static)> {
+        self.banks.get_mut(index).map(|bank| bank.as_mut())
+    }
+}
+
+impl Memory for BankedMemory {
+    #[inline]
+    fn get_u8(&self, addr: u16) -> u8 {
+        match self.banks.get(self.active_bank()) {
+            Some(bank) => bank.get_u8(addr),
+            None => 0xFF,
+        }
+    }
+
+    #[inline]
+    fn set_u8(&mut self, addr: u16, value: u8) {
+        let index = self.active_bank();
+        if let Some(bank) = self.banks.get_mut(index) {
+            bank.set_u8(addr, value);
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.register.get());
+        for bank in &self.banks {
+            bank.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SnapshotError> {
+        self.register.set(reader.read_u8()?);
+        for bank in self.banks.iter_mut() {
+            bank.load_state(reader)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ram::RAM;
+    use crate::segmented_memory::SegmentedMemory;
+    use crate::cpu::Machine;
+
+    #[test]
+    fn test_banks() {
+        let register = BankRegister::new();
+        let mut memory = SegmentedMemory::new()
+            .add(0x0000, 0x4000, Box::new(
+                BankedMemory::new(register.clone())
+                    .add_bank(Box::new(RAM::new(0x4000)))
+                    .add_bank(Box::new(RAM::new(0x4000)))
+            ))
+            .add(0xF000, 0xF001, Box::new(register.clone()));
+
+        memory.set_u8(0x1234, 1);
+        memory.set_u8(0xF000, 1);
+        assert_eq!(memory.get_u8(0x1234), 0);
+        memory.set_u8(0x1234, 2);
+        memory.set_u8(0xF000, 0);
+        assert_eq!(memory.get_u8(0x1234), 1);
+        register.set(1);
+        assert_eq!(memory.get_u8(0x1234), 2);
+        register.set(5);
+        assert_eq!(memory.get_u8(0x1234), 0xFF);
+    }
+
+    #[test]
+    fn test_windows_share_register() {
+        let register = BankRegister::new();
+        let mut memory = SegmentedMemory::new()
+            .add(0x0000, 0x4000, Box::new(
+                BankedMemory::new(register.clone())
+                    .select_bits(0, 0x03)
+                    .add_bank(Box::new(RAM::new(0x4000)))
+                    .add_bank(Box::new(RAM::new(0x4000)))
+            ))
+            .add(0x4000, 0x8000, Box::new(
+                BankedMemory::new(register.clone())
+                    .select_bits(2, 0x03)
+                    .add_bank(Box::new(RAM::new(0x4000)))
+                    .add_bank(Box::new(RAM::new(0x4000)))
+            ));
+
+        register.set(0b_01_00);
+        memory.set_u8(0x0000, 1);
+        memory.set_u8(0x4000, 2);
+        register.set(0b_00_01);
+        assert_eq!(memory.get_u8(0x0000), 0);
+        assert_eq!(memory.get_u8(0x4000), 0);
+        register.set(0b_01_00);
+        assert_eq!(memory.get_u8(0x0000), 1);
+        assert_eq!(memory.get_u8(0x4000), 2);
+    }
+
+    #[test]
+    fn test_switch_by_port() {
+        let register = BankRegister::new();
+        let memory = BankedMemory::new(register.clone())
+            .add_bank(Box::new(RAM::default()))
+            .add_bank(Box::new(RAM::default()));
+        let mut m = Machine::new(memory).with_io(Box::new(register.clone()));
+        m.memory.bank_mut(1).unwrap().set_range(0x10, &[0x3E, 0x42]); // MVI A,42h
+        m.memory.set_range(0, &[0x3E, 0x01, 0xD3, 0xF9]); // MVI A,01h; OUT 0F9h
+        m.memory.set_u8(0x10, 0x00);
+
+        for _ in 0..2 {
+            m.step().unwrap();
+        }
+        assert_eq!(register.get(), 1);
+        m.registers.pc = 0x10;
+        m.step().unwrap();
+        assert_eq!(m.registers.a, 0x42);
+    }
+}
@@ -0,0 +1,428 @@
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+use rs580::debug::{Debugger, Stop, WatchKind, Watchpoint};
+use rs580::disasm::{disassemble_for, Flow};
+use rs580::history::History;
+use rs580::{ExecutionError, Machine, Memory, SegmentedMemory, RAM, ROM};
+
+const USAGE: &str = "\
+Usage: debugger [--rom FILE@ADDR]... [FILE@ADDR]... [--pc ADDR]
+
+Files given with --rom are mapped read-only, other files are loaded into RAM
+which fills the rest of the address space. Addresses are hexadecimal.";
+
+const HELP: &str = "\
+s, step [N]          execute N instructions (default 1)
+n, next              execute one instruction, running over calls
+finish               run until the current routine returns
+c, continue          run until a breakpoint, watchpoint or HLT
+rs, reverse-step [N] undo N instructions (default 1)
+rc, reverse-continue undo instructions until a breakpoint
+b, break ADDR        set a breakpoint
+d, delete [ADDR]     delete a breakpoint or all of them
+watch FROM [TO]      stop on writes to memory (also rwatch, awatch for reads and any access)
+unwatch ID           delete a watchpoint
+i, info              list breakpoints and watchpoints
+r, regs              show registers
+set REG VALUE        set a register: a b c d e h l pc sp flag_s flag_z flag_ac flag_p flag_c
+x ADDR [LEN]         dump memory
+e ADDR BYTE...       write bytes to memory
+l, list [ADDR] [N]   disassemble N instructions at ADDR (default: around PC)
+bt, backtrace        show the call stack
+q, quit              exit
+Numbers are hexadecimal. An empty line repeats the previous command.
+Up to a million instructions can be undone; the call stack is not restored.";
+
+const HISTORY_STEPS: usize = 1_000_000;
+
+/// Call recorded on the shadow stack.
+struct Frame {
+    call_site: u16,
+    target: u16,
+    /// SP after the return address was pushed.
+    sp: u16,
+}
+
+struct Session {
+    machine: Machine<SegmentedMemory>,
+    debugger: Debugger,
+    frames: Vec<Frame>,
+    history: Rc<RefCell<History>>,
+}
+
+impl Session {
+    fn step(&mut self) -> Result<Option<Stop>, ExecutionError> {
+        let instruction = disassemble_for(&self.machine.memory, self.machine.registers.pc, self.machine.variant);
+        let sp = self.machine.registers.sp;
+        let stop = self.debugger.step(&mut self.machine)?;
+
+        let registers = &self.machine.registers;
+        let pushed = registers.sp == sp.wrapping_sub(2);
+        let call = matches!(instruction.flow, Flow::Call | Flow::ConditionalCall | Flow::Restart);
+        if call && pushed && Some(registers.pc) == instruction.target() {
+            self.frames.push(Frame { call_site: instruction.address, target: registers.pc, sp: registers.sp });
+        } else if pushed && registers.pc != instruction.next_address() && Some(registers.pc) != instruction.target() {
+            // interrupt acknowledge
+            self.frames.push(Frame { call_site: instruction.address, target: registers.pc, sp: registers.sp });
+        }
+        self.drop_returned_frames();
+        Ok(stop)
+    }
+
+    /// Frames of calls undone by stepping back are dropped.
+    fn drop_returned_frames(&mut self) {
+        let sp = self.machine.registers.sp;
+        while self.frames.last().is_some_and(|frame| frame.sp < sp) {
+            self.frames.pop();
+        }
+    }
+
+    /// Runs until a stop condition of the debugger or `done`. The first instruction ignores breakpoints.
+    fn run<F: FnMut(&Session) -> bool>(&mut self, mut done: F) -> Result<Stop, ExecutionError> {
+        loop {
+            if let Some(stop) = self.step()? {
+                return Ok(stop);
+            }
+            if done(self) {
+                return Ok(Stop::Condition);
+            }
+            if self.debugger.has_breakpoint(self.machine.registers.pc) {
+                return Ok(Stop::Breakpoint(self.machine.registers.pc));
+            }
+        }
+    }
+
+    fn registers(&self) -> String {
+        let r = &self.machine.registers;
+        let flag = |set: bool, name: &str| if set { name.to_uppercase() } else { name.to_string() };
+        format!(
+            "A={:02X} B={:02X} C={:02X} D={:02X} E={:02X} H={:02X} L={:02X} SP={:04X} PC={:04X}  {} {} {} {} {}{}  cycles={}",
+            r.a, r.b, r.c, r.d, r.e, r.h, r.l, r.sp, r.pc,
+            flag(r.flag_s, "s"), flag(r.flag_z, "z"), flag(r.flag_ac, "ac"), flag(r.flag_p, "p"), flag(r.flag_c, "c"),
+            if self.machine.halted { "  HALTED" } else { "" },
+            self.machine.cycles,
+        )
+    }
+
+    fn show_position(&self) {
+        let instruction = disassemble_for(&self.machine.memory, self.machine.registers.pc, self.machine.variant);
+        println!("{}", self.registers());
+        println!("=> {}", instruction.listing());
+    }
+
+    fn list(&self, from: Option<u16>, count: usize) {
+        let pc = self.machine.registers.pc;
+        let mut address = from.unwrap_or_else(|| self.start_before(pc));
+        for _ in 0..count {
+            let instruction = disassemble_for(&self.machine.memory, address, self.machine.variant);
+            let marker = if address == pc { "=>" } else { "  " };
+            let breakpoint = if self.debugger.has_breakpoint(address) { "*" } else { " " };
+            println!("{}{} {}", marker, breakpoint, instruction.listing());
+            address = instruction.next_address();
+        }
+    }
+
+    /// Finds an address a few instructions before `pc` from which decoding lands exactly on `pc`.
+    fn start_before(&self, pc: u16) -> u16 {
+        for back in (1..=pc.min(9)).rev() {
+            let start = pc - back;
+            let mut address = start;
+            while address.wrapping_sub(start) < back {
+                address = disassemble_for(&self.machine.memory, address, self.machine.variant).next_address();
+            }
+            if address == pc {
+                return start;
+            }
+        }
+        pc
+    }
+
+    fn backtrace(&self) {
+        println!("#0  {:04X}", self.machine.registers.pc);
+        for (i, frame) in self.frames.iter().rev().enumerate() {
+            println!(
+                "#{}  {:04X}  called {:04X}, returns to {:04X}",
+                i + 1, frame.call_site, frame.target, self.machine.memory.get_u16(frame.sp)
+            );
+        }
+    }
+
+    fn dump(&self, from: u16, len: u16) {
+        let mut address = from;
+        let mut left = len as usize;
+        while left > 0 {
+            let count = left.min(16);
+            let bytes: Vec<u8> = (0..count as u16).map(|i| self.machine.memory.get_u8(address.wrapping_add(i))).collect();
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let text: String = bytes.iter().map(|&b| if (0x20..0x7F).contains(&b) { b as char } else { '.' }).collect();
+            println!("{:04X}  {:<47}  {}", address, hex.join(" "), text);
+            address = address.wrapping_add(count as u16);
+            left -= count;
+        }
+    }
+
+    fn set_register(&mut self, name: &str, value: u16) -> Result<(), String> {
+        let r = &mut self.machine.registers;
+        let byte = || u8::try_from(value).map_err(|_| format!("{:X} does not fit in {}", value, name));
+        let flag = || match value {
+            0 | 1 => Ok(value == 1),
+            _ => Err(format!("{} is 0 or 1", name)),
+        };
+        match name {
+            "a" => r.a = byte()?,
+            "b" => r.b = byte()?,
+            "c" => r.c = byte()?,
+            "d" => r.d = byte()?,
+            "e" => r.e = byte()?,
+            "h" => r.h = byte()?,
+            "l" => r.l = byte()?,
+            "pc" => r.pc = value,
+            "sp" => r.sp = value,
+            "flag_s" => r.flag_s = flag()?,
+            "flag_z" => r.flag_z = flag()?,
+            "flag_ac" => r.flag_ac = flag()?,
+            "flag_p" => r.flag_p = flag()?,
+            "flag_c" => r.flag_c = flag()?,
+            _ => return Err(format!("Unknown register {}", name)),
+        }
+        Ok(())
+    }
+
+    fn report(&self, result: Result<Stop, ExecutionError>) {
+        match result {
+            Ok(Stop::Breakpoint(address)) => println!("Breakpoint at {:04X}", address),
+            Ok(Stop::Watchpoint { id, access }) => println!("Watchpoint {}: {:?}", id, access),
+            Ok(Stop::Halted) => println!("Halted"),
+            Ok(Stop::UndefinedOpcode(address)) => println!("Undefined opcode at {:04X}", address),
+            Ok(Stop::HistoryStart) => println!("No more history"),
+            Ok(Stop::Condition) | Ok(Stop::CyclesElapsed) => {},
+            Err(error) => println!("{}", error),
+        }
+        self.show_position();
+    }
+
+    /// Returns false to quit.
+    fn command(&mut self, line: &str) -> Result<bool, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let arg = |i: usize| words.get(i).map(|word| parse_number(word)).transpose();
+        match words.first().cloned().unwrap_or("") {
+            "s" | "step" => {
+                let count = arg(1)?.unwrap_or(1);
+                let mut result = Ok(Stop::Condition);
+                for _ in 0..count {
+                    result = self.step().map(|stop| stop.unwrap_or(Stop::Condition));
+                    if result != Ok(Stop::Condition) {
+                        break;
+                    }
+                }
+                self.report(result);
+            },
+            "n" | "next" => {
+                let depth = self.frames.len();
+                let result = self.run(|session| session.frames.len() <= depth);
+                self.report(result);
+            },
+            "finish" => {
+                let depth = self.frames.len();
+                if depth == 0 {
+                    return Err("No caller on the call stack".to_string());
+                }
+                let result = self.run(|session| session.frames.len() < depth);
+                self.report(result);
+            },
+            "c" | "continue" => {
+                let result = self.run(|_| false);
+                self.report(result);
+            },
+            "rs" | "reverse-step" => {
+                let count = arg(1)?.unwrap_or(1);
+                let mut result = Ok(Stop::Condition);
+                for _ in 0..count {
+                    if !self.history.borrow_mut().step_back(&mut self.machine) {
+                        result = Ok(Stop::HistoryStart);
+                        break;
+                    }
+                }
+                self.drop_returned_frames();
+                self.report(result);
+            },
+            "rc" | "reverse-continue" => {
+                let stop = self.debugger.reverse_continue(&mut self.machine, &mut self.history.borrow_mut());
+                self.drop_returned_frames();
+                self.report(Ok(stop));
+            },
+            "b" | "break" => {
+                let address = arg(1)?.ok_or("Address expected")?;
+                self.debugger.add_breakpoint(address);
+                println!("Breakpoint at {:04X}", address);
+            },
+            "d" | "delete" => match arg(1)? {
+                Some(address) => {
+                    if !self.debugger.remove_breakpoint(address) {
+                        return Err(format!("No breakpoint at {:04X}", address));
+                    }
+                },
+                None => {
+                    let all: Vec<u16> = self.debugger.breakpoints().collect();
+                    for address in all {
+                        self.debugger.remove_breakpoint(address);
+                    }
+                },
+            },
+            kind @ "watch" | kind @ "rwatch" | kind @ "awatch" => {
+                let from = arg(1)?.ok_or("Address expected")?;
+                let to = arg(2)?.unwrap_or(from);
+                let kind = match kind {
+                    "watch" => WatchKind::Write,
+                    "rwatch" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                let id = self.debugger.add_watchpoint(Watchpoint::memory(from..=to, kind));
+                println!("Watchpoint {}", id);
+            },
+            "unwatch" => {
+                let id = arg(1)?.ok_or("Watchpoint id expected")?;
+                if self.debugger.remove_watchpoint(id as usize).is_none() {
+                    return Err(format!("No watchpoint {}", id));
+                }
+            },
+            "i" | "info" => {
+                for address in self.debugger.breakpoints() {
+                    println!("Breakpoint at {:04X}", address);
+                }
+                for (id, watchpoint) in self.debugger.watchpoints() {
+                    println!(
+                        "Watchpoint {}: {:?} {:04X}-{:04X}",
+                        id, watchpoint.kind, watchpoint.range.start(), watchpoint.range.end()
+                    );
+                }
+            },
+            "r" | "regs" => println!("{}", self.registers()),
+            "set" => {
+                let name = words.get(1).ok_or("Register expected")?.to_lowercase();
+                let value = arg(2)?.ok_or("Value expected")?;
+                self.set_register(&name, value)?;
+                println!("{}", self.registers());
+            },
+            "x" => {
+                let from = arg(1)?.ok_or("Address expected")?;
+                self.dump(from, arg(2)?.unwrap_or(0x80));
+            },
+            "e" => {
+                let from = arg(1)?.ok_or("Address expected")?;
+                let mut address = from;
+                for word in &words[2..] {
+                    let value = parse_number(word)?;
+                    let byte = u8::try_from(value).map_err(|_| format!("{:X} is not a byte", value))?;
+                    self.machine.memory.set_u8(address, byte);
+                    address = address.wrapping_add(1);
+                }
+                self.dump(from, address.wrapping_sub(from));
+            },
+            "l" | "list" => self.list(arg(1)?, arg(2)?.unwrap_or(10) as usize),
+            "bt" | "backtrace" => self.backtrace(),
+            "h" | "help" => println!("{}", HELP),
+            "q" | "quit" => return Ok(false),
+            "" => {},
+            command => return Err(format!("Unknown command {}. Type help for the list of commands.", command)),
+        }
+        Ok(true)
+    }
+}
+
+fn parse_number(text: &str) -> Result<u16, String> {
+    let digits = text.trim_start_matches("0x").trim_end_matches(['h', 'H']);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Bad number {}", text))
+}
+
+/// Parses `FILE@ADDR`.
+fn parse_image(arg: &str) -> Result<(u16, Vec<u8>), String> {
+    let (file, address) = arg.rsplit_once('@').ok_or_else(|| format!("{} should be FILE@ADDR", arg))?;
+    let address = parse_number(address)?;
+    let data = std::fs::read(file).map_err(|error| format!("{}: {}", file, error))?;
+    if address as usize + data.len() > 0x10000 {
+        return Err(format!("{} does not fit at {:04X}", file, address));
+    }
+    Ok((address, data))
+}
+
+fn build_machine(args: &[String]) -> Result<Machine<SegmentedMemory>, String> {
+    let mut roms = Vec::new();
+    let mut images = Vec::new();
+    let mut pc = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--rom" => roms.push(parse_image(args.next().ok_or("--rom needs FILE@ADDR")?)?),
+            "--pc" => pc = Some(parse_number(args.next().ok_or("--pc needs an address")?)?),
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ => images.push(parse_image(arg)?),
+        }
+    }
+    if roms.is_empty() && images.is_empty() {
+        return Err(USAGE.to_string());
+    }
+
+    roms.sort_by_key(|(address, _)| *address);
+    let mut memory = SegmentedMemory::new();
+    let mut free = 0;
+    for (address, data) in &roms {
+        let address = *address as usize;
+        if address < free {
+            return Err(format!("ROM at {:04X} overlaps another ROM", address));
+        }
+        if address > free {
+            memory = memory.add(free, address, Box::new(RAM::new(address - free)));
+        }
+        memory = memory.add(address, address + data.len(), Box::new(ROM::new(data)));
+        free = address + data.len();
+    }
+    if free < 0x10000 {
+        memory = memory.add(free, 0x10000, Box::new(RAM::new(0x10000 - free)));
+    }
+    for (address, data) in &images {
+        memory.set_range(*address, data);
+    }
+
+    let mut machine = Machine::new(memory);
+    machine.registers.pc = pc
+        .or_else(|| images.first().map(|(address, _)| *address))
+        .or_else(|| roms.first().map(|(address, _)| *address))
+        .unwrap_or(0);
+    Ok(machine)
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut machine = match build_machine(&args) {
+        Ok(machine) => machine,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(2);
+        },
+    };
+    let history = Rc::new(RefCell::new(History::new(HISTORY_STEPS)));
+    machine.add_hook(Box::new(history.clone()));
+    let mut session = Session { machine, debugger: Debugger::new(), frames: Vec::new(), history };
+    session.show_position();
+
+    let stdin = io::stdin();
+    let mut last = String::new();
+    loop {
+        print!("(rs580) ");
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        let line = if line.trim().is_empty() { last.clone() } else { line.trim().to_string() };
+        match session.command(&line) {
+            Ok(true) => {},
+            Ok(false) => break,
+            Err(message) => println!("{}", message),
+        }
+        last = line;
+    }
+}
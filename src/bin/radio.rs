@@ -7,9 +7,15 @@ use termion::input::TermRead;
 use termion::event::Key;
 use termion::screen::AlternateScreen;
 use std::io::{Write, stdout, Stdout};
-use std::sync::Arc;
+use std::rc::Rc;
 use std::cell::{RefCell, Cell};
 
+const CPU_FREQUENCY: u64 = 1_777_777; // Hz
+const SNAPSHOT_FILE: &str = "radio.snapshot";
+const REWIND_SECONDS: u64 = 2;
+/// Enough steps to go back `REWIND_SECONDS` even through 4 T-state instructions only.
+const HISTORY_STEPS: usize = (REWIND_SECONDS * CPU_FREQUENCY / 4) as usize;
+
 const ROM: [u8; 2048] = *include_bytes!("./RK86-16.rom");
 // const ZG: [u8; 2048] = *include_bytes!("./zg.rom");
 
@@ -21,6 +27,7 @@ struct RKDisplay {
     dirty: bool,
     stdout: AlternateScreen<RawTerminal<Stdout>>,
     indicators: u8,
+    status: String,
 }
 
 impl RKDisplay {
@@ -33,16 +40,17 @@ impl RKDisplay {
             cursor_x: 0,
             cursor_y: 0,
             indicators: 0,
+            status: String::new(),
             last_print: time::Instant::now(),
             dirty: true,
             stdout,
         })
     }
 
-    pub fn copy_from_machine(&mut self, machine: &rs580::Machine) {
+    pub fn copy_from_machine<M: rs580::Memory>(&mut self, machine: &rs580::Machine<M>) {
         // 0x37c2
         let video_ram = machine.memory.get_range(0x36d0, 0x3ff4);
-        if &video_ram[..] != &self.data[..] {
+        if video_ram[..] != self.data[..] {
             self.data.copy_from_slice(&video_ram);
             self.dirty = true;
         }
@@ -63,9 +71,14 @@ impl RKDisplay {
         }
     }
 
+    pub fn set_status(&mut self, status: String) {
+        self.status = status;
+        self.dirty = true;
+    }
+
     pub fn print(&mut self) -> Result<(), std::io::Error> {
         let now = time::Instant::now();
-        if (now - self.last_print).subsec_millis() % 25 != 0 {
+        if !(now - self.last_print).subsec_millis().is_multiple_of(25) {
             return Ok(());
         }
         self.last_print = now;
@@ -87,7 +100,7 @@ impl RKDisplay {
                     let b: u8 = self.data[y * 78 + x];
                     if b == 0 {
                         write!(self.stdout, " ")?;
-                    } else if b >= 32 && b < 128 {
+                    } else if (32..128).contains(&b) {
                         write!(self.stdout, "{}", b as char)?;
                     } else {
                         write!(self.stdout, "?")?;
@@ -102,7 +115,7 @@ impl RKDisplay {
                 write!(self.stdout, "-")?;
             }
             write!(self.stdout, "+\r\n")?;
-            write!(self.stdout, "{:04b}", self.indicators)?;
+            write!(self.stdout, "{:04b} {}", self.indicators, self.status)?;
 
             write!(self.stdout, "{}{}", cursor::Show, cursor::Goto(self.cursor_x as u16 + 2, self.cursor_y as u16 + 2))?;
 
@@ -212,6 +225,13 @@ impl TryFrom<Key> for RKKey {
     }
 }
 
+enum Command {
+    Quit,
+    QuickSave,
+    QuickLoad,
+    Rewind,
+}
+
 struct RKKeyboardInternal {
     key_stream: RefCell<termion::input::Keys<termion::AsyncReader>>,
     current_key: Cell<(RKKey, time::Instant)>,
@@ -220,11 +240,11 @@ struct RKKeyboardInternal {
 }
 
 #[derive(Clone)]
-struct RKKeyboard(Arc<RKKeyboardInternal>);
+struct RKKeyboard(Rc<RKKeyboardInternal>);
 
 impl RKKeyboard {
     pub fn new() -> Self {
-        RKKeyboard(Arc::new(RKKeyboardInternal {
+        RKKeyboard(Rc::new(RKKeyboardInternal {
             key_stream: RefCell::new(async_stdin().keys()),
             current_key: Cell::new((
                 RKKey {
@@ -239,17 +259,21 @@ impl RKKeyboard {
         }))
     }
 
-    pub fn process_key(&self) -> bool {
+    pub fn process_key(&self) -> Option<Command> {
         let b = self.0.key_stream.borrow_mut().next();
         if let Some(Ok(k)) = b {
-            if k == Key::Ctrl('c') || k == Key::Ctrl('q') {
-                return true;
+            match k {
+                Key::Ctrl('c') | Key::Ctrl('q') => return Some(Command::Quit),
+                Key::F(5) => return Some(Command::QuickSave),
+                Key::F(8) => return Some(Command::Rewind),
+                Key::F(9) => return Some(Command::QuickLoad),
+                _ => {},
             }
             if let Ok(key) = RKKey::try_from(k) {
                 self.0.current_key.set((key, time::Instant::now()));
             }
         }
-        false
+        None
     }
 
     fn get_current_key(&self) -> Option<RKKey> {
@@ -286,11 +310,8 @@ impl rs580::Memory for RKKeyboard {
                 }
             }
         } else if addr == 2 {
-            match self.get_current_key() {
-                Some(RKKey {a: 0, b: 0, c}) => {
-                    return c & 0xF0 | (self.0.state.get() & 0x0F);
-                },
-                _ => {},
+            if let Some(RKKey {a: 0, b: 0, c}) = self.get_current_key() {
+                return c & 0xF0 | (self.0.state.get() & 0x0F);
             }
         }
 
@@ -318,34 +339,133 @@ impl rs580::Memory for RKKeyboard {
             }
         }
     }
+
+    fn save_state(&self, writer: &mut rs580::snapshot::StateWriter) {
+        writer.write_u8(self.0.current_line.get());
+        writer.write_u8(self.0.state.get());
+    }
+
+    fn load_state(&mut self, reader: &mut rs580::snapshot::StateReader) -> Result<(), rs580::snapshot::SnapshotError> {
+        self.0.current_line.set(reader.read_u8()?);
+        self.0.state.set(reader.read_u8()?);
+        Ok(())
+    }
 }
 
 fn main() {
+    // --profile FILE writes folded stacks to FILE and a per-routine report to stderr on exit.
+    // --coverage FILE writes the annotated monitor ROM listing to FILE and a summary to stderr.
+    // --rewind keeps the last REWIND_SECONDS of execution for F8. It records every bus write,
+    // so it is off by default.
+    let args: Vec<String> = std::env::args().collect();
+    let mut profile_file = None;
+    let mut coverage_file = None;
+    let mut rewind = false;
+    let usage = || -> ! {
+        eprintln!("Usage: {} [--profile FILE] [--coverage FILE] [--rewind]", args[0]);
+        std::process::exit(2);
+    };
+    let mut options = args[1..].iter();
+    while let Some(name) = options.next() {
+        match name.as_str() {
+            "--profile" => profile_file = Some(options.next().unwrap_or_else(|| usage()).clone()),
+            "--coverage" => coverage_file = Some(options.next().unwrap_or_else(|| usage()).clone()),
+            "--rewind" => rewind = true,
+            _ => usage(),
+        }
+    }
+
     let keyboard = RKKeyboard::new();
     let mut display = RKDisplay::new().unwrap();
 
     let memory = rs580::SegmentedMemory::new()
-        .add(0x0000, 0x4000, Box::new(rs580::RAM::default()))
-        .add(0x8000, 0xA000, Box::new(keyboard.clone()))
+        .add(0x0000, 0x4000, Box::new(rs580::RAM::new(0x4000)))
+        .add_masked(0x8000, 0xA000, 0x0003, Box::new(keyboard.clone())) // 8255 decodes A0-A1 only
         .add(0xF800, 0x10000, Box::new(rs580::ROM::new(&ROM)));
 
-    let mut machine = rs580::Machine::new(Box::new(memory));
+    let mut machine = rs580::Machine::new(memory);
+    machine.variant = rs580::CpuVariant::Kr580Vm80a;
     machine.registers.pc = 0xF800;
+    let profiler = Rc::new(RefCell::new(rs580::profile::Profiler::new()));
+    if profile_file.is_some() {
+        machine.add_hook(Box::new(profiler.clone()));
+    }
+    let history = Rc::new(RefCell::new(rs580::history::History::new(HISTORY_STEPS)));
+    if rewind {
+        machine.add_hook(Box::new(history.clone()));
+    }
+    let coverage = Rc::new(RefCell::new(rs580::coverage::Coverage::new()));
+    if coverage_file.is_some() {
+        machine.add_hook(Box::new(coverage.clone()));
+    }
 
-    loop {
+    let mut started = (time::Instant::now(), machine.cycles);
+    let result = loop {
         display.copy_from_machine(&machine);
         display.copy_from_keyboard(&keyboard);
         display.print().unwrap();
-        if keyboard.process_key() {
-            break;
+        match keyboard.process_key() {
+            Some(Command::Quit) => break Ok(()),
+            Some(Command::QuickSave) => {
+                match std::fs::write(SNAPSHOT_FILE, machine.save_snapshot()) {
+                    Ok(()) => display.set_status(format!("Saved to {}", SNAPSHOT_FILE)),
+                    Err(error) => display.set_status(format!("Save failed: {}", error)),
+                }
+            },
+            Some(Command::QuickLoad) => {
+                let loaded = std::fs::read(SNAPSHOT_FILE)
+                    .map_err(|error| error.to_string())
+                    .and_then(|data| machine.load_snapshot(&data).map_err(|error| error.to_string()));
+                match loaded {
+                    Ok(()) => {
+                        display.set_status(format!("Loaded {}", SNAPSHOT_FILE));
+                        history.borrow_mut().clear();
+                        started = (time::Instant::now(), machine.cycles);
+                    },
+                    Err(error) => display.set_status(format!("Load failed: {}", error)),
+                }
+            },
+            Some(Command::Rewind) if !rewind => display.set_status("Rewind needs --rewind".to_string()),
+            Some(Command::Rewind) => {
+                let steps = history.borrow_mut().rewind_cycles(&mut machine, REWIND_SECONDS * CPU_FREQUENCY);
+                display.set_status(format!("Rewound {} instructions", steps));
+                started = (time::Instant::now(), machine.cycles);
+            },
+            None => {},
         }
 
-        machine.step();
-        if machine.halted {
-            println!("HALT");
-            break;
+        match machine.step() {
+            Ok(rs580::StepOutcome::Halted(_)) => break Err("HALT".to_string()),
+            Ok(_) => {},
+            Err(error) => break Err(error.to_string()),
         }
 
-        thread::sleep(time::Duration::from_micros(10));
+        let emulated = time::Duration::from_nanos((machine.cycles - started.1) * 1_000_000_000 / CPU_FREQUENCY);
+        let elapsed = started.0.elapsed();
+        if emulated > elapsed + time::Duration::from_millis(1) {
+            thread::sleep(emulated - elapsed);
+        }
+    };
+
+    // Leave raw mode before reporting.
+    drop(display);
+    if let Some(file) = profile_file {
+        let profiler = profiler.borrow();
+        let written = std::fs::File::create(&file).and_then(|mut out| profiler.write_folded(&mut out));
+        if let Err(error) = written {
+            eprintln!("{}: {}", file, error);
+        }
+        eprint!("{}", profiler.report());
+    }
+    if let Some(file) = coverage_file {
+        let coverage = coverage.borrow();
+        if let Err(error) = std::fs::write(&file, coverage.annotate(&machine.memory, 0xF800..=0xFFFF)) {
+            eprintln!("{}: {}", file, error);
+        }
+        eprint!("{}", coverage.report(&machine.memory, &[0xF800..=0xFFFF]));
+    }
+    if let Err(message) = result {
+        eprintln!("{}", message);
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,306 @@
+//! Code coverage: executed instructions and taken/not-taken conditional branches,
+//! reported as an annotated disassembly and per-range percentages.
+
+use std::ops::RangeInclusive;
+use crate::cpu::{CpuVariant, ExecutionError, Hook, StepContext, StepOutcome};
+use crate::disasm::{disassemble_for, Flow, Instruction};
+use crate::memory::Memory;
+
+struct Pending {
+    pc: u16,
+    conditional: bool,
+    next: u16,
+}
+
+/// Coverage of an address range.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeSummary {
+    pub range: RangeInclusive<u16>,
+    pub instructions: usize,
+    pub executed_instructions: usize,
+    /// Conditional jumps, calls and returns.
+    pub branches: usize,
+    /// Out of `2 * branches` directions.
+    pub covered_directions: usize,
+}
+
+impl RangeSummary {
+    pub fn instruction_percent(&self) -> f64 {
+        percent(self.executed_instructions, self.instructions)
+    }
+
+    pub fn branch_percent(&self) -> f64 {
+        percent(self.covered_directions, 2 * self.branches)
+    }
+}
+
+fn percent(part: usize, whole: usize) -> f64 {
+    if whole == 0 {
+        100.0
+    } else {
+        part as f64 * 100.0 / whole as f64
+    }
+}
+
+enum Line {
+    Instruction(Instruction),
+    /// Byte skipped to stay in sync with executed instructions.
+    Data(u16, u8),
+}
+
+pub struct Coverage {
+    counts: Vec<u64>,
+    taken: Vec<u64>,
+    not_taken: Vec<u64>,
+    pending: Option<Pending>,
+    /// Processor the listing is decoded for, taken from the last step.
+    variant: CpuVariant,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; 0x10000],
+            taken: vec![0; 0x10000],
+            not_taken: vec![0; 0x10000],
+            pending: None,
+            variant: CpuVariant::default(),
+        }
+    }
+
+    /// How many times the instruction starting at the address was executed.
+    pub fn count(&self, address: u16) -> u64 {
+        self.counts[address as usize]
+    }
+
+    /// Taken and not-taken counts of the conditional instruction at the address.
+    pub fn branch(&self, address: u16) -> (u64, u64) {
+        (self.taken[address as usize], self.not_taken[address as usize])
+    }
+
+    /// Disassembles the range. Executed instructions take priority, so decoding resynchronises
+    /// at them; bytes skipped for that are shown as `DB`.
+    fn lines<M: Memory + ?Sized>(&self, memory: &M, range: &RangeInclusive<u16>) -> Vec<Line> {
+        let mut lines = Vec::new();
+        let mut address = *range.start() as u32;
+        let end = *range.end() as u32;
+        while address <= end {
+            let instruction = disassemble_for(memory, address as u16, self.variant);
+            let len = instruction.len() as u32;
+            let inner = (address + 1..address + len).find(|&a| a <= 0xFFFF && self.counts[a as usize] > 0);
+            match inner {
+                Some(start) if self.counts[address as usize] == 0 => {
+                    for a in address..start {
+                        lines.push(Line::Data(a as u16, memory.get_u8(a as u16)));
+                    }
+                    address = start;
+                },
+                _ => {
+                    lines.push(Line::Instruction(instruction));
+                    address += len;
+                },
+            }
+        }
+        lines
+    }
+
+    pub fn summary<M: Memory + ?Sized>(&self, memory: &M, range: RangeInclusive<u16>) -> RangeSummary {
+        let mut summary = RangeSummary {
+            range: range.clone(),
+            instructions: 0,
+            executed_instructions: 0,
+            branches: 0,
+            covered_directions: 0,
+        };
+        for line in self.lines(memory, &range) {
+            if let Line::Instruction(instruction) = line {
+                let address = instruction.address as usize;
+                summary.instructions += 1;
+                if self.counts[address] > 0 {
+                    summary.executed_instructions += 1;
+                }
+                if is_conditional(&instruction) {
+                    summary.branches += 1;
+                    summary.covered_directions += (self.taken[address] > 0) as usize + (self.not_taken[address] > 0) as usize;
+                }
+            }
+        }
+        summary
+    }
+
+    /// Disassembly of the range with execution counts; `-----` marks code never executed.
+    pub fn annotate<M: Memory + ?Sized>(&self, memory: &M, range: RangeInclusive<u16>) -> String {
+        let mut text = String::new();
+        for line in self.lines(memory, &range) {
+            match line {
+                Line::Instruction(instruction) => {
+                    let address = instruction.address as usize;
+                    let count = match self.counts[address] {
+                        0 => "-----".to_string(),
+                        count => count.to_string(),
+                    };
+                    let mut line = format!("{:>10}  {}", count, instruction.listing());
+                    if is_conditional(&instruction) && self.counts[address] > 0 {
+                        line = format!("{:<48}; taken {}, not taken {}", line, self.taken[address], self.not_taken[address]);
+                    }
+                    text += &line;
+                },
+                Line::Data(address, value) => text += &format!("{:>10}  {:04X}  {:02X}        DB {:02X}H", "", address, value, value),
+            }
+            text += "\n";
+        }
+        text
+    }
+
+    /// Percentages for each range.
+    pub fn report<M: Memory + ?Sized>(&self, memory: &M, ranges: &[RangeInclusive<u16>]) -> String {
+        let mut text = format!("{:<11} {:>13} {:>7} {:>13} {:>7}\n", "range", "instructions", "%", "branches", "%");
+        for range in ranges {
+            let summary = self.summary(memory, range.clone());
+            text += &format!(
+                "{:04X}-{:04X} {:>6}/{:<6} {:>6.2}% {:>6}/{:<6} {:>6.2}%\n",
+                range.start(), range.end(),
+                summary.executed_instructions, summary.instructions, summary.instruction_percent(),
+                summary.covered_directions, 2 * summary.branches, summary.branch_percent(),
+            );
+        }
+        text
+    }
+}
+
+fn is_conditional(instruction: &Instruction) -> bool {
+    matches!(instruction.flow, Flow::ConditionalJump | Flow::ConditionalCall | Flow::ConditionalReturn)
+}
+
+impl Hook for Coverage {
+    fn before_step(&mut self, context: &StepContext) {
+        if context.halted {
+            return;
+        }
+        self.variant = context.variant;
+        let instruction = disassemble_for(context.memory, context.registers.pc, context.variant);
+        self.pending = Some(Pending {
+            pc: instruction.address,
+            conditional: is_conditional(&instruction),
+            next: instruction.next_address(),
+        });
+    }
+
+    fn after_step(&mut self, context: &StepContext, result: &Result<StepOutcome, ExecutionError>) {
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        // An acknowledged interrupt did not run the instruction at PC.
+        if !matches!(result, Ok(StepOutcome::Executed(_)) | Ok(StepOutcome::Halted(_))) {
+            return;
+        }
+        let pc = pending.pc as usize;
+        self.counts[pc] += 1;
+        if pending.conditional {
+            if context.registers.pc == pending.next {
+                self.not_taken[pc] += 1;
+            } else {
+                self.taken[pc] += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::cpu::{InterruptAck, Machine};
+    use crate::ram::RAM;
+    use rs580_macros::asm8080;
+
+    #[test]
+    fn test_coverage() {
+        let program = asm8080! {
+                MVI B,2;
+            again:
+                DCR B;
+                JNZ again;
+                JZ done;
+                DB 0FFh;
+                CALL again;
+            done:
+                HLT
+        };
+        let mut m = Machine::new(RAM::default());
+        m.memory.set_range(program.origin, &program.bytes);
+        let coverage = Rc::new(RefCell::new(Coverage::new()));
+        m.add_hook(Box::new(coverage.clone()));
+        while !m.halted {
+            m.step().unwrap();
+        }
+
+        let coverage = coverage.borrow();
+        let again = program.labels.again;
+        assert_eq!(coverage.count(again), 2);
+        assert_eq!(coverage.branch(again + 1), (1, 1));
+        assert_eq!(coverage.branch(again + 4), (1, 0));
+
+        let end = program.labels.done;
+        let summary = coverage.summary(&m.memory, 0..=end);
+        assert_eq!(summary, RangeSummary {
+            range: 0..=end,
+            instructions: 7,
+            executed_instructions: 5,
+            branches: 2,
+            covered_directions: 3,
+        });
+        assert_eq!(
+            coverage.annotate(&m.memory, 0..=end),
+            "         1  0000  06 02     MVI B,02H\n\
+             \x20        2  0002  05        DCR B\n\
+             \x20        2  0003  C2 02 00  JNZ 0002H           ; taken 1, not taken 1\n\
+             \x20        1  0006  CA 0D 00  JZ 000DH            ; taken 1, not taken 0\n\
+             \x20    -----  0009  FF        RST 7\n\
+             \x20    -----  000A  CD 02 00  CALL 0002H\n\
+             \x20        1  000D  76        HLT\n"
+        );
+        assert!(coverage.report(&m.memory, &[0..=end]).contains("71.43%"));
+    }
+
+    #[test]
+    fn test_interrupt_is_not_an_instruction() {
+        let program = asm8080! {
+                LXI SP,100h;
+                MVI B,1;
+                DCR B;
+            branch:
+                JNZ 0;
+                HLT;
+                ORG 38h;
+                RET
+        };
+        let mut m = Machine::new(RAM::default());
+        m.memory.set_range(program.origin, &program.bytes);
+        let coverage = Rc::new(RefCell::new(Coverage::new()));
+        m.add_hook(Box::new(coverage.clone()));
+        let branch = program.labels.branch;
+        while m.registers.pc != branch {
+            m.step().unwrap();
+        }
+        m.interrupt(InterruptAck::Rst(7));
+        assert_eq!(m.step(), Ok(StepOutcome::Interrupted(11)));
+        m.clear_interrupt();
+        while !m.halted {
+            m.step().unwrap();
+        }
+
+        let coverage = coverage.borrow();
+        assert_eq!(coverage.count(branch), 1);
+        assert_eq!(coverage.branch(branch), (0, 1));
+        assert_eq!(coverage.count(0x38), 1);
+    }
+}
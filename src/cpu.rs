@@ -1,4 +1,8 @@
+use std::cell::RefCell;
+use std::rc::Rc;
 use crate::memory::Memory;
+use crate::io::{Io, NullIo};
+use crate::snapshot::{self, SnapshotError, StateReader, StateWriter};
 
 #[inline]
 fn from_pair(h: u8, l: u8) -> u16 {
@@ -10,11 +14,6 @@ fn to_pair(hl: u16) -> (u8, u8) {
     ((hl >> 8) as u8, (hl & 0xFF) as u8)
 }
 
-#[inline]
-fn neg(v: u8) -> u8 {
-    ((0x100_i16 - (v as i16)) & 0xFF) as u8
-}
-
 #[inline]
 fn add16(x: &mut u16, value: u16) {
     *x = x.overflowing_add(value).0;
@@ -85,7 +84,295 @@ macro_rules! ops2 {
     };
 }
 
-#[derive(Default, Debug)]
+/// Maps an undocumented opcode to the documented one it behaves like.
+const fn alias(opcode: u8) -> u8 {
+    match opcode {
+        0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 => 0x00, // NOP
+        0xCB => 0xC3, // JMP
+        0xD9 => 0xC9, // RET
+        0xDD | 0xED | 0xFD => 0xCD, // CALL
+        _ => opcode,
+    }
+}
+
+/// Decoded instruction. Operands are the variable bit fields of the opcode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Op {
+    Nop,
+    Lxi(u8),
+    Stax(u8),
+    Shld,
+    Sta,
+    Inx(u8),
+    Inr(u8),
+    Dcr(u8),
+    Mvi(u8),
+    Rlc,
+    Rrc,
+    Ral,
+    Rar,
+    Dad(u8),
+    Ldax(u8),
+    Lhld,
+    Lda,
+    Dcx(u8),
+    Daa,
+    Cma,
+    Stc,
+    Cmc,
+    Hlt,
+    Mov(u8, u8),
+    Alu(u8, u8),
+    Rcond(u8),
+    Jcond(u8),
+    Ccond(u8),
+    Pop(u8),
+    Push(u8),
+    Jmp,
+    Ret,
+    Call,
+    AluImm(u8),
+    Rst(u8),
+    Out,
+    In,
+    Xthl,
+    Pchl,
+    Sphl,
+    Xchg,
+    Di,
+    Ei,
+    // 8085 only.
+    Rim,
+    Sim,
+    Dsub,
+    Arhl,
+    Rdel,
+    Ldhi,
+    Ldsi,
+    Rstv,
+    Shlx,
+    Lhlx,
+    /// JNK (false) or JK (true).
+    Jk(bool),
+    Undefined,
+}
+
+// `0b_01_110_110` groups HLT as MOV M,M.
+#[allow(clippy::unusual_byte_groupings)]
+const fn decode(opcode: u8) -> Op {
+    if opcode == 0 {
+        Op::Nop
+    } else if let Some(rp) = ops!(opcode, ('0', '0', 'X', 'X', '0', '0', '0', '1')) {
+        Op::Lxi(rp)
+    } else if let Some(r) = ops!(opcode, ('0', '0', '0', 'X', '0', '0', '1', '0')) {
+        Op::Stax(r)
+    } else if opcode == 0b_0010_0010 {
+        Op::Shld
+    } else if opcode == 0b_0011_0010 {
+        Op::Sta
+    } else if let Some(rp) = ops!(opcode, ('0', '0', 'X', 'X', '0', '0', '1', '1')) {
+        Op::Inx(rp)
+    } else if let Some(reg) = ops!(opcode, ('0', '0', 'X', 'X', 'X', '1', '0', '0')) {
+        Op::Inr(reg)
+    } else if let Some(reg) = ops!(opcode, ('0', '0', 'X', 'X', 'X', '1', '0', '1')) {
+        Op::Dcr(reg)
+    } else if let Some(reg) = ops!(opcode, ('0', '0', 'X', 'X', 'X', '1', '1', '0')) {
+        Op::Mvi(reg)
+    } else if opcode == 0b_0000_0111 {
+        Op::Rlc
+    } else if opcode == 0b_0000_1111 {
+        Op::Rrc
+    } else if opcode == 0b_0001_0111 {
+        Op::Ral
+    } else if opcode == 0b_0001_1111 {
+        Op::Rar
+    } else if let Some(rp) = ops!(opcode, ('0', '0', 'X', 'X', '1', '0', '0', '1')) {
+        Op::Dad(rp)
+    } else if let Some(r) = ops!(opcode, ('0', '0', '0', 'X', '1', '0', '1', '0')) {
+        Op::Ldax(r)
+    } else if opcode == 0b_0010_1010 {
+        Op::Lhld
+    } else if opcode == 0b_0011_1010 {
+        Op::Lda
+    } else if let Some(rp) = ops!(opcode, ('0', '0', 'X', 'X', '1', '0', '1', '1')) {
+        Op::Dcx(rp)
+    } else if opcode == 0x27 {
+        Op::Daa
+    } else if opcode == 0x2F {
+        Op::Cma
+    } else if opcode == 0x37 {
+        Op::Stc
+    } else if opcode == 0x3F {
+        Op::Cmc
+    } else if opcode == 0b_01_110_110 {
+        Op::Hlt
+    } else if let Some((dst, src)) = ops2!(opcode, ('0', '1', 'X', 'X', 'X', 'Y', 'Y', 'Y')) {
+        Op::Mov(dst, src)
+    } else if let Some((operation, operand_code)) = ops2!(opcode, ('1', '0', 'X', 'X', 'X', 'Y', 'Y', 'Y')) {
+        Op::Alu(operation, operand_code)
+    } else if let Some(cond) = ops!(opcode, ('1', '1', 'X', 'X', 'X', '0', '0', '0')) {
+        Op::Rcond(cond)
+    } else if let Some(cond) = ops!(opcode, ('1', '1', 'X', 'X', 'X', '0', '1', '0')) {
+        Op::Jcond(cond)
+    } else if let Some(cond) = ops!(opcode, ('1', '1', 'X', 'X', 'X', '1', '0', '0')) {
+        Op::Ccond(cond)
+    } else if let Some(rp) = ops!(opcode, ('1', '1', 'X', 'X', '0', '0', '0', '1')) {
+        Op::Pop(rp)
+    } else if let Some(rp) = ops!(opcode, ('1', '1', 'X', 'X', '0', '1', '0', '1')) {
+        Op::Push(rp)
+    } else if opcode == 0xC3 {
+        Op::Jmp
+    } else if opcode == 0xC9 {
+        Op::Ret
+    } else if opcode == 0xCD {
+        Op::Call
+    } else if let Some(operation) = ops!(opcode, ('1', '1', 'X', 'X', 'X', '1', '1', '0')) {
+        Op::AluImm(operation)
+    } else if let Some(exp) = ops!(opcode, ('1', '1', 'X', 'X', 'X', '1', '1', '1')) {
+        Op::Rst(exp)
+    } else if opcode == 0xD3 {
+        Op::Out
+    } else if opcode == 0xDB {
+        Op::In
+    } else if opcode == 0xE3 {
+        Op::Xthl
+    } else if opcode == 0xE9 {
+        Op::Pchl
+    } else if opcode == 0xF9 {
+        Op::Sphl
+    } else if opcode == 0xEB {
+        Op::Xchg
+    } else if opcode == 0xF3 {
+        Op::Di
+    } else if opcode == 0xFB {
+        Op::Ei
+    } else {
+        Op::Undefined
+    }
+}
+
+/// 8085 opcodes, including the undocumented ones, in place of the 8080 aliases.
+const fn decode_8085(opcode: u8) -> Op {
+    match opcode {
+        0x08 => Op::Dsub,
+        0x10 => Op::Arhl,
+        0x18 => Op::Rdel,
+        0x20 => Op::Rim,
+        0x28 => Op::Ldhi,
+        0x30 => Op::Sim,
+        0x38 => Op::Ldsi,
+        0xCB => Op::Rstv,
+        0xD9 => Op::Shlx,
+        0xDD => Op::Jk(false),
+        0xED => Op::Lhlx,
+        0xFD => Op::Jk(true),
+        _ => decode(opcode),
+    }
+}
+
+impl Op {
+    /// Instruction length in bytes.
+    pub(crate) const fn len(self) -> u16 {
+        match self {
+            Op::Lxi(_) | Op::Shld | Op::Sta | Op::Lhld | Op::Lda |
+            Op::Jcond(_) | Op::Ccond(_) | Op::Jmp | Op::Call | Op::Jk(_) => 3,
+            Op::Mvi(_) | Op::AluImm(_) | Op::Out | Op::In | Op::Ldhi | Op::Ldsi => 2,
+            _ => 1,
+        }
+    }
+
+    /// Whether the 8085 manual leaves the instruction out.
+    pub(crate) const fn undocumented_8085(self) -> bool {
+        matches!(self, Op::Dsub | Op::Arhl | Op::Rdel | Op::Ldhi | Op::Ldsi | Op::Rstv | Op::Shlx | Op::Lhlx | Op::Jk(_))
+    }
+
+    /// 8085 duration in T-states as `(taken, not_taken)`.
+    pub(crate) const fn cycles_8085(self) -> (u32, u32) {
+        match self {
+            Op::Rcond(_) => (12, 6),
+            Op::Ccond(_) => (18, 9),
+            Op::Jcond(_) | Op::Jk(_) => (10, 7),
+            Op::Rstv => (12, 6),
+            _ => {
+                let cycles = match self {
+                    Op::Inr(6) | Op::Dcr(6) | Op::Mvi(6) => 10,
+                    Op::Inx(_) | Op::Dcx(_) | Op::Pchl | Op::Sphl => 6,
+                    Op::Mov(6, _) | Op::Mov(_, 6) | Op::Alu(_, 6) => 7,
+                    Op::Stax(_) | Op::Ldax(_) | Op::Mvi(_) | Op::AluImm(_) | Op::Arhl => 7,
+                    Op::Hlt => 5,
+                    Op::Lxi(_) | Op::Dad(_) | Op::Pop(_) | Op::Jmp | Op::Ret | Op::Out | Op::In => 10,
+                    Op::Dsub | Op::Rdel | Op::Ldhi | Op::Ldsi | Op::Shlx | Op::Lhlx => 10,
+                    Op::Push(_) | Op::Rst(_) => 12,
+                    Op::Sta | Op::Lda => 13,
+                    Op::Shld | Op::Lhld | Op::Xthl => 16,
+                    Op::Call => 18,
+                    _ => 4,
+                };
+                (cycles, cycles)
+            },
+        }
+    }
+
+    /// Duration in T-states as `(taken, not_taken)`. Only conditional CALL and RET differ.
+    pub(crate) const fn cycles(self) -> (u32, u32) {
+        match self {
+            Op::Rcond(_) => (11, 5),
+            Op::Ccond(_) => (17, 11),
+            _ => {
+                let cycles = match self {
+                    Op::Inr(6) | Op::Dcr(6) | Op::Mvi(6) => 10,
+                    Op::Inx(_) | Op::Inr(_) | Op::Dcr(_) | Op::Dcx(_) | Op::Pchl | Op::Sphl => 5,
+                    Op::Mov(6, _) | Op::Mov(_, 6) | Op::Alu(_, 6) => 7,
+                    Op::Mov(_, _) => 5,
+                    Op::Stax(_) | Op::Ldax(_) | Op::Mvi(_) | Op::AluImm(_) | Op::Hlt => 7,
+                    Op::Lxi(_) | Op::Dad(_) | Op::Jcond(_) | Op::Pop(_) | Op::Jmp | Op::Ret | Op::Out | Op::In => 10,
+                    Op::Push(_) | Op::Rst(_) => 11,
+                    Op::Sta | Op::Lda => 13,
+                    Op::Shld | Op::Lhld => 16,
+                    Op::Call => 17,
+                    Op::Xthl => 18,
+                    _ => 4,
+                };
+                (cycles, cycles)
+            },
+        }
+    }
+}
+
+const fn decode_table(aliases: bool) -> [Op; 256] {
+    let mut table = [Op::Undefined; 256];
+    let mut opcode = 0;
+    while opcode < 256 {
+        table[opcode] = if aliases {
+            decode(alias(opcode as u8))
+        } else {
+            decode(opcode as u8)
+        };
+        opcode += 1;
+    }
+    table
+}
+
+const fn decode_table_8085() -> [Op; 256] {
+    let mut table = [Op::Undefined; 256];
+    let mut opcode = 0;
+    while opcode < 256 {
+        table[opcode] = decode_8085(opcode as u8);
+        opcode += 1;
+    }
+    table
+}
+
+/// Documented instructions only.
+pub(crate) static OPCODES: [Op; 256] = decode_table(false);
+
+/// Undocumented opcodes are decoded as their documented aliases.
+pub(crate) static OPCODES_WITH_ALIASES: [Op; 256] = decode_table(true);
+
+/// All 256 opcodes are defined on the 8085.
+pub(crate) static OPCODES_8085: [Op; 256] = decode_table_8085();
+
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
 pub struct Registers {
     pub a: u8,
     pub flag_s: bool,
@@ -93,6 +380,10 @@ pub struct Registers {
     pub flag_ac: bool,
     pub flag_p: bool,
     pub flag_c: bool,
+    /// 8085 two's complement overflow flag. Always false on other variants.
+    pub flag_v: bool,
+    /// 8085 flag set when INX or DCX wraps around. Always false on other variants.
+    pub flag_k: bool,
     pub b: u8,
     pub c: u8,
     pub d: u8,
@@ -108,14 +399,17 @@ impl Registers {
         from_pair(self.h, self.l)
     }
 
+    /// Flags as pushed by an 8080: `SZ0A0P1C`.
     pub fn get_flags_byte(&self) -> u8 {
         (self.flag_s as u8) << 7 |
         (self.flag_z as u8) << 6 |
         (self.flag_ac as u8) << 4 |
         (self.flag_p as u8) << 2 |
+        0b_0000_0010 |
         (self.flag_c as u8)
     }
 
+    /// Bits 1, 3 and 5 are ignored.
     pub fn set_flags_byte(&mut self, flags_byte: u8) {
         self.flag_s = flags_byte & 0b_1000_0000 != 0;
         self.flag_z = flags_byte & 0b_0100_0000 != 0;
@@ -125,355 +419,1032 @@ impl Registers {
     }
 }
 
-pub struct Machine {
+/// What happened during `Machine::step`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// An instruction was executed in given amount of T-states.
+    Executed(u32),
+    /// An interrupt was acknowledged in given amount of T-states. The instruction at PC did not run.
+    Interrupted(u32),
+    /// The processor is halted: either HLT was just executed or it waits for an interrupt.
+    Halted(u32),
+    /// An undefined opcode at given address was hit under `UndefinedOpcodes::Break`. Nothing was executed.
+    Breakpoint(u16),
+}
+
+impl StepOutcome {
+    pub fn cycles(&self) -> u32 {
+        match *self {
+            StepOutcome::Executed(cycles) | StepOutcome::Interrupted(cycles) | StepOutcome::Halted(cycles) => cycles,
+            StepOutcome::Breakpoint(_) => 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExecutionError {
+    IllegalOpcode { opcode: u8, address: u16 },
+}
+
+impl std::fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            ExecutionError::IllegalOpcode { opcode, address } =>
+                write!(f, "Bad opcode 0x{:02X} at 0x{:04X}.", opcode, address),
+        }
+    }
+}
+
+impl std::error::Error for ExecutionError {}
+
+/// How `Machine::step` treats undefined opcodes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum UndefinedOpcodes {
+    /// Execute them the way 8080 and KR580VM80A silicon does:
+    /// 0x08, 0x10, ..., 0x38 as NOP, 0xCB as JMP, 0xD9 as RET, 0xDD, 0xED and 0xFD as CALL.
+    #[default]
+    Alias,
+    /// Return `ExecutionError::IllegalOpcode`, leaving PC at the opcode.
+    Fail,
+    /// Skip the opcode as a one-byte NOP.
+    Nop,
+    /// Stop with `StepOutcome::Breakpoint`, leaving PC at the opcode. Handy for software breakpoints.
+    Break,
+}
+
+/// Processor whose flag semantics `Machine` follows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum CpuVariant {
+    /// ANA and ANI set AC to the OR of bit 3 of both operands. Bit 1 of the pushed flags is 1.
+    #[default]
+    Intel8080,
+    /// Soviet clone of the 8080 with identical flag behaviour.
+    Kr580Vm80a,
+    /// ANA and ANI always set AC. The pushed flags are `SZKA0PVC`. Enables the 8085 instructions,
+    /// interrupts and timings; every opcode is defined, so `Machine::undefined_opcodes` is ignored.
+    Intel8085,
+}
+
+impl CpuVariant {
+    /// Flags byte pushed by PUSH PSW.
+    pub fn flags_byte(self, registers: &Registers) -> u8 {
+        match self {
+            CpuVariant::Intel8080 | CpuVariant::Kr580Vm80a => registers.get_flags_byte(),
+            CpuVariant::Intel8085 => {
+                registers.get_flags_byte() & !0b_0000_0010 |
+                (registers.flag_k as u8) << 5 |
+                (registers.flag_v as u8) << 1
+            },
+        }
+    }
+
+    /// Sets flags from a byte popped by POP PSW.
+    pub fn set_flags_byte(self, registers: &mut Registers, flags_byte: u8) {
+        registers.set_flags_byte(flags_byte);
+        if self == CpuVariant::Intel8085 {
+            registers.flag_k = flags_byte & 0b_0010_0000 != 0;
+            registers.flag_v = flags_byte & 0b_0000_0010 != 0;
+        }
+    }
+}
+
+/// Instruction which the interrupting device (or an 8228/8259 controller) puts on
+/// the data bus during interrupt acknowledge.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InterruptAck {
+    /// `RST n`, n is 0..=7.
+    Rst(u8),
+    /// `CALL addr`.
+    Call(u16),
+}
+
+/// Bus transaction made by the processor, see `Machine::bus_log`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BusAccess {
+    /// Opcode or operand read.
+    Fetch { address: u16, value: u8 },
+    Read { address: u16, value: u8 },
+    /// `old` is the value read back from the address just before the write.
+    Write { address: u16, value: u8, old: u8 },
+    In { port: u8, value: u8 },
+    Out { port: u8, value: u8 },
+}
+
+/// 8085 interrupt latches and SIM outputs, which live outside `Registers`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct State8085 {
+    sod: bool,
+    rst75_pending: bool,
+    trap_pending: bool,
+    interrupt_masks: u8,
+}
+
+/// Machine state visible to a `Hook`.
+pub struct StepContext<'a> {
+    pub registers: &'a Registers,
+    pub memory: &'a dyn Memory,
+    /// Value of `Machine::cycles`.
+    pub cycles: u64,
+    pub halted: bool,
+    pub interruption_enabled: bool,
+    pub interrupt_request: Option<InterruptAck>,
+    pub(crate) interrupt_delay: bool,
+    pub(crate) state_8085: State8085,
+    pub variant: CpuVariant,
+    /// Bus transactions of the step when the bus log is enabled. Empty in `before_step`.
+    pub bus: &'a [BusAccess],
+}
+
+/// Observer of `Machine::step`, e.g. a tracer or a profiler.
+///
+/// Wrap a hook in `Rc<RefCell<_>>` to keep access to it after `Machine::add_hook`.
+pub trait Hook {
+    fn before_step(&mut self, _context: &StepContext) {
+    }
+
+    fn after_step(&mut self, _context: &StepContext, _result: &Result<StepOutcome, ExecutionError>) {
+    }
+
+    /// Whether the hook needs `StepContext::bus`.
+    fn wants_bus_log(&self) -> bool {
+        false
+    }
+}
+
+impl<T: Hook + ?Sized> Hook for Rc<RefCell<T>> {
+    fn before_step(&mut self, context: &StepContext) {
+        self.borrow_mut().before_step(context)
+    }
+
+    fn after_step(&mut self, context: &StepContext, result: &Result<StepOutcome, ExecutionError>) {
+        self.borrow_mut().after_step(context, result)
+    }
+
+    fn wants_bus_log(&self) -> bool {
+        self.borrow().wants_bus_log()
+    }
+}
+
+/// 8080 processor attached to the memory `M`.
+///
+/// With a concrete memory type all memory accesses are monomorphised and can be inlined.
+/// `BoxedMachine` is the dynamically dispatched variant for memory maps chosen at run time.
+pub struct Machine<M = Box<dyn Memory>> {
     pub registers: Registers,
     pub halted: bool,
     pub interruption_enabled: bool,
-    pub memory: Box<dyn Memory>,
+    /// State of the INT line together with the instruction supplied on acknowledge.
+    pub interrupt_request: Option<InterruptAck>,
+    /// Set by EI: interrupts are not accepted until one more instruction is executed.
+    interrupt_delay: bool,
+    pub undefined_opcodes: UndefinedOpcodes,
+    pub variant: CpuVariant,
+    /// 8085 serial input pin, read by RIM.
+    pub sid: bool,
+    /// 8085 serial output pin, written by SIM.
+    pub sod: bool,
+    /// Level of the 8085 RST 5.5 input.
+    pub rst55: bool,
+    /// Level of the 8085 RST 6.5 input.
+    pub rst65: bool,
+    /// RST 7.5 is edge triggered and latched until acknowledged or reset by SIM.
+    rst75_pending: bool,
+    trap_pending: bool,
+    /// RST 5.5, 6.5 and 7.5 masks in bits 0 to 2, as set by SIM.
+    interrupt_masks: u8,
+    /// Total number of T-states executed since creation of the machine.
+    pub cycles: u64,
+    /// When set, collects bus transactions of the last `step`. Disabled by default as it slows execution down.
+    pub bus_log: Option<Vec<BusAccess>>,
+    pub memory: M,
+    pub io: Box<dyn Io>,
+    hooks: Vec<Box<dyn Hook>>,
 }
 
-impl Machine {
-    pub fn new(memory: Box<dyn Memory>) -> Self {
+pub type BoxedMachine = Machine<Box<dyn Memory>>;
+
+impl<M: Memory> Machine<M> {
+    pub fn new(memory: M) -> Self {
         Self {
             registers: Registers::default(),
             halted: false,
             interruption_enabled: true,
+            interrupt_request: None,
+            interrupt_delay: false,
+            undefined_opcodes: UndefinedOpcodes::default(),
+            variant: CpuVariant::default(),
+            sid: false,
+            sod: false,
+            rst55: false,
+            rst65: false,
+            rst75_pending: false,
+            trap_pending: false,
+            interrupt_masks: 0b111,
+            cycles: 0,
+            bus_log: None,
             memory,
+            io: Box::new(NullIo),
+            hooks: Vec::new(),
         }
     }
 
+    pub fn with_io(mut self, io: Box<dyn Io>) -> Self {
+        self.io = io;
+        self
+    }
+
     pub fn reset(&mut self) {
         self.registers.pc = 0;
     }
 
-    pub fn step(&mut self) {
-        let opcode = self.memory.get_u8(self.registers.pc);
-        if opcode == 0 {
-            // NOP
-            add16(&mut self.registers.pc, 1);
-        } else if let Some(rp) = ops!(opcode, ('0', '0', 'X', 'X', '0', '0', '0', '1')) {
-            // LXI
-            let data16 = self.memory.get_u16(self.registers.pc.overflowing_add(1).0);
-            self.set_pair(rp, data16);
-            add16(&mut self.registers.pc, 3);
-        } else if let Some(r) = ops!(opcode, ('0', '0', '0', 'X', '0', '0', '1', '0')) {
-            // STAX
-            let addr = self.get_pair(r);
-            self.memory.set_u8(addr, self.registers.a);
-            add16(&mut self.registers.pc, 1);
-        } else if opcode == 0b_0010_0010 {
-            // SHLD
-            let addr = self.memory.get_u16(self.registers.pc.overflowing_add(1).0);
-            self.memory.set_u8(addr, self.registers.l);
-            self.memory.set_u8(addr + 1, self.registers.h);
-            add16(&mut self.registers.pc, 3);
-        } else if opcode == 0b_0011_0010 {
-            // STA
-            let addr = self.memory.get_u16(self.registers.pc.overflowing_add(1).0);
-            self.memory.set_u8(addr, self.registers.a);
-            add16(&mut self.registers.pc, 3);
-        } else if let Some(rp) = ops!(opcode, ('0', '0', 'X', 'X', '0', '0', '1', '1')) {
-            // INX
-            self.set_pair(rp, self.get_pair(rp) + 1);
-            add16(&mut self.registers.pc, 1);
-        } else if let Some(reg) = ops!(opcode, ('0', '0', 'X', 'X', 'X', '1', '0', '0')) {
-            // INR
-            let value = self.get_location(reg);
-            self.set_location(reg, value.overflowing_add(1).0);
-            self.registers.flag_ac = (value & 0x0F) + 1 > 0x0F;
-            let value = self.get_location(reg);
-            self.set_flags(value);
-            add16(&mut self.registers.pc, 1);
-        } else if let Some(reg) = ops!(opcode, ('0', '0', 'X', 'X', 'X', '1', '0', '1')) {
-            // DCR
-            let value = self.get_location(reg);
-            self.set_location(reg, value.overflowing_sub(1).0);
-            self.registers.flag_ac = (value & 0x0F) + 0x0F <= 0x0F;
-            let value = self.get_location(reg);
-            self.set_flags(value);
-            add16(&mut self.registers.pc, 1);
-        } else if let Some(reg) = ops!(opcode, ('0', '0', 'X', 'X', 'X', '1', '1', '0')) {
-            // MVI
-            let data = self.memory.get_u8(self.registers.pc.overflowing_add(1).0);
-            self.set_location(reg, data);
-            self.registers.pc = self.registers.pc.overflowing_add(2).0;
-        } else if opcode == 0b_0000_0111 {
-            // RLC
-            self.registers.flag_c = (self.registers.a & 0x80) != 0;
-            self.registers.a = self.registers.a.rotate_left(1);
-            add16(&mut self.registers.pc, 1);
-        } else if opcode == 0b_0000_1111 {
-            // RRC
-            self.registers.flag_c = (self.registers.a & 1) != 0;
-            self.registers.a = self.registers.a.rotate_right(1);
-            add16(&mut self.registers.pc, 1);
-        } else if opcode == 0b_0001_0111 {
-            // RAL
-            let c = self.registers.flag_c;
-            self.registers.flag_c = (self.registers.a & 0x80) != 0;
-            self.registers.a = self.registers.a << 1;
-            if c {
-                self.registers.a |= 1;
-            }
-            add16(&mut self.registers.pc, 1);
-        } else if opcode == 0b_0001_1111 {
-            // RAR
-            let c = self.registers.flag_c;
-            self.registers.flag_c = (self.registers.a & 1) != 0;
-            self.registers.a = self.registers.a >> 1;
-            if c {
-                self.registers.a |= 0x80;
+    /// Serialises processor state and the state of all memory and I/O devices.
+    pub fn save_snapshot(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        for b in snapshot::MAGIC {
+            writer.write_u8(*b);
+        }
+        writer.write_u16(snapshot::VERSION);
+
+        let r = &self.registers;
+        for value in &[r.a, r.b, r.c, r.d, r.e, r.h, r.l] {
+            writer.write_u8(*value);
+        }
+        for flag in &[r.flag_s, r.flag_z, r.flag_ac, r.flag_p, r.flag_c] {
+            writer.write_bool(*flag);
+        }
+        writer.write_u16(r.pc);
+        writer.write_u16(r.sp);
+        writer.write_bool(self.halted);
+        writer.write_bool(self.interruption_enabled);
+        writer.write_bool(self.interrupt_delay);
+        match self.interrupt_request {
+            None => writer.write_u8(0),
+            Some(InterruptAck::Rst(n)) => {
+                writer.write_u8(1);
+                writer.write_u8(n);
+            },
+            Some(InterruptAck::Call(addr)) => {
+                writer.write_u8(2);
+                writer.write_u16(addr);
+            },
+        }
+        writer.write_u64(self.cycles);
+        for flag in &[
+            r.flag_v, r.flag_k, self.sid, self.sod, self.rst55, self.rst65, self.rst75_pending, self.trap_pending,
+        ] {
+            writer.write_bool(*flag);
+        }
+        writer.write_u8(self.interrupt_masks);
+
+        self.memory.save_state(&mut writer);
+        self.io.save_state(&mut writer);
+        writer.into_inner()
+    }
+
+    /// Restores a snapshot made by `save_snapshot` on a machine with the same memory and I/O layout.
+    /// On error the machine is left unchanged.
+    pub fn load_snapshot(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        if !data.starts_with(snapshot::MAGIC) {
+            return Err(SnapshotError::BadMagic);
+        }
+        let data = &data[snapshot::MAGIC.len()..];
+        if data.len() < 2 {
+            return Err(SnapshotError::UnexpectedEnd);
+        }
+        let version = u16::from_le_bytes([data[0], data[1]]);
+        if version == 0 || version > snapshot::VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let mut reader = StateReader::new(&data[2..], version);
+
+        let mut registers = Registers::default();
+        for value in &mut [
+            &mut registers.a, &mut registers.b, &mut registers.c, &mut registers.d,
+            &mut registers.e, &mut registers.h, &mut registers.l,
+        ] {
+            **value = reader.read_u8()?;
+        }
+        for flag in &mut [
+            &mut registers.flag_s, &mut registers.flag_z, &mut registers.flag_ac,
+            &mut registers.flag_p, &mut registers.flag_c,
+        ] {
+            **flag = reader.read_bool()?;
+        }
+        registers.pc = reader.read_u16()?;
+        registers.sp = reader.read_u16()?;
+        let halted = reader.read_bool()?;
+        let interruption_enabled = reader.read_bool()?;
+        let interrupt_delay = reader.read_bool()?;
+        let interrupt_request = match reader.read_u8()? {
+            0 => None,
+            1 => Some(InterruptAck::Rst(reader.read_u8()?)),
+            2 => Some(InterruptAck::Call(reader.read_u16()?)),
+            tag => return Err(SnapshotError::Mismatch(format!("unknown interrupt request {}", tag))),
+        };
+        let cycles = reader.read_u64()?;
+        let mut pins = [false; 6];
+        let mut interrupt_masks = 0b111;
+        if reader.version() >= 2 {
+            registers.flag_v = reader.read_bool()?;
+            registers.flag_k = reader.read_bool()?;
+            for pin in &mut pins {
+                *pin = reader.read_bool()?;
             }
-            add16(&mut self.registers.pc, 1);
-        } else if let Some(rp) = ops!(opcode, ('0', '0', 'X', 'X', '1', '0', '0', '1')) {
-            // DAD
-            let operand = self.get_pair(rp);
-            let hl = from_pair(self.registers.h, self.registers.l);
-            let (value, overflow) = hl.overflowing_add(operand);
-            let (h, l) = to_pair(value);
-            self.registers.flag_c = overflow;
-            self.registers.h = h;
-            self.registers.l = l;
-            add16(&mut self.registers.pc, 1);
-        } else if let Some(r) = ops!(opcode, ('0', '0', '0', 'X', '1', '0', '1', '0')) {
-            // LDAX
-            self.registers.a = self.memory.get_u8(self.get_pair(r));
-            add16(&mut self.registers.pc, 1);
-        } else if opcode == 0b_0010_1010 {
-            // LHLD
-            let addr = self.memory.get_u16(self.registers.pc.overflowing_add(1).0);
-            let (l, h) = self.memory.get_u8_u8(addr);
-            self.registers.l = l;
-            self.registers.h = h;
-            add16(&mut self.registers.pc, 3);
-        } else if opcode == 0b_0011_1010 {
-            // LDA
-            let addr = self.memory.get_u16(self.registers.pc.overflowing_add(1).0);
-            self.registers.a = self.memory.get_u8(addr);
-            add16(&mut self.registers.pc, 3);
-        } else if let Some(rp) = ops!(opcode, ('0', '0', 'X', 'X', '1', '0', '1', '1')) {
-            // DCX
-            self.set_pair(rp, self.get_pair(rp).overflowing_sub(1).0);
-            add16(&mut self.registers.pc, 1);
-        } else if opcode == 0x27 {
-            // DAA
-            if self.registers.a & 0x0F > 9 || self.registers.flag_ac {
-                let (value, overflow) = self.registers.a.overflowing_add(0x06);
-                self.registers.flag_ac = (self.registers.a & 0x0F) + 0x06 > 0x0F;
-                self.registers.a = value;
+            interrupt_masks = reader.read_u8()? & 0b111;
+        }
+
+        // Devices can only be checked by loading them, so keep their state to roll back to.
+        let mut backup = StateWriter::new();
+        self.memory.save_state(&mut backup);
+        self.io.save_state(&mut backup);
+        if let Err(error) = self.load_devices(&mut reader) {
+            let backup = backup.into_inner();
+            self.load_devices(&mut StateReader::new(&backup, snapshot::VERSION))
+                .expect("devices accept the state they saved");
+            return Err(error);
+        }
+
+        self.registers = registers;
+        self.halted = halted;
+        self.interruption_enabled = interruption_enabled;
+        self.interrupt_delay = interrupt_delay;
+        self.interrupt_request = interrupt_request;
+        self.cycles = cycles;
+        let [sid, sod, rst55, rst65, rst75_pending, trap_pending] = pins;
+        self.sid = sid;
+        self.sod = sod;
+        self.rst55 = rst55;
+        self.rst65 = rst65;
+        self.rst75_pending = rst75_pending;
+        self.trap_pending = trap_pending;
+        self.interrupt_masks = interrupt_masks;
+        Ok(())
+    }
+
+    /// Loads the memory and I/O devices from the rest of a snapshot.
+    fn load_devices(&mut self, reader: &mut StateReader) -> Result<(), SnapshotError> {
+        self.memory.load_state(reader)?;
+        self.io.load_state(reader)?;
+        if !reader.is_empty() {
+            return Err(SnapshotError::TrailingData);
+        }
+        Ok(())
+    }
+
+    /// Asserts INT. The request stays pending until it is acknowledged or cleared.
+    pub fn interrupt(&mut self, ack: InterruptAck) {
+        self.interrupt_request = Some(ack);
+    }
+
+    pub fn clear_interrupt(&mut self) {
+        self.interrupt_request = None;
+    }
+
+    /// Rising edge on the 8085 RST 7.5 input.
+    pub fn rst75(&mut self) {
+        self.rst75_pending = true;
+    }
+
+    /// Rising edge on the 8085 TRAP input, a non-maskable interrupt to 0024h.
+    pub fn trap(&mut self) {
+        self.trap_pending = true;
+    }
+
+    /// Attaches a hook called around every `step`. Enables the bus log if the hook asks for it.
+    pub fn add_hook(&mut self, hook: Box<dyn Hook>) {
+        if hook.wants_bus_log() && self.bus_log.is_none() {
+            self.bus_log = Some(Vec::new());
+        }
+        self.hooks.push(hook);
+    }
+
+    /// Whether any attached hook relies on the bus log.
+    pub fn hooks_want_bus_log(&self) -> bool {
+        self.hooks.iter().any(|hook| hook.wants_bus_log())
+    }
+
+    pub fn clear_hooks(&mut self) {
+        self.hooks.clear();
+    }
+
+    /// Restores processor state captured from a `StepContext`, see `history`.
+    pub(crate) fn restore_state(&mut self, registers: Registers, halted: bool, interruption_enabled: bool,
+                                interrupt_request: Option<InterruptAck>, interrupt_delay: bool, cycles: u64) {
+        self.registers = registers;
+        self.halted = halted;
+        self.interruption_enabled = interruption_enabled;
+        self.interrupt_request = interrupt_request;
+        self.interrupt_delay = interrupt_delay;
+        self.cycles = cycles;
+    }
+
+    pub(crate) fn restore_state_8085(&mut self, state: State8085) {
+        self.sod = state.sod;
+        self.rst75_pending = state.rst75_pending;
+        self.trap_pending = state.trap_pending;
+        self.interrupt_masks = state.interrupt_masks;
+    }
+
+    /// Executes one instruction (or acknowledges a pending interrupt).
+    /// A halted processor does nothing and spends 4 T-states per step.
+    pub fn step(&mut self) -> Result<StepOutcome, ExecutionError> {
+        if self.hooks.is_empty() {
+            return self.step_unhooked();
+        }
+        let mut hooks = std::mem::take(&mut self.hooks);
+        for hook in &mut hooks {
+            hook.before_step(&self.context());
+        }
+        let result = self.step_unhooked();
+        for hook in &mut hooks {
+            hook.after_step(&self.context(), &result);
+        }
+        self.hooks = hooks;
+        result
+    }
+
+    fn context(&self) -> StepContext<'_> {
+        StepContext {
+            registers: &self.registers,
+            memory: &self.memory,
+            cycles: self.cycles,
+            halted: self.halted,
+            interruption_enabled: self.interruption_enabled,
+            interrupt_request: self.interrupt_request,
+            interrupt_delay: self.interrupt_delay,
+            state_8085: State8085 {
+                sod: self.sod,
+                rst75_pending: self.rst75_pending,
+                trap_pending: self.trap_pending,
+                interrupt_masks: self.interrupt_masks,
+            },
+            variant: self.variant,
+            bus: self.bus_log.as_deref().unwrap_or_default(),
+        }
+    }
+
+    fn step_unhooked(&mut self) -> Result<StepOutcome, ExecutionError> {
+        // The common case has nothing to log, acknowledge or wait for: go straight to the instruction.
+        if self.bus_log.is_none() && !self.halted && !self.interrupt_delay && !self.interrupt_pending() {
+            return self.step_instruction(false);
+        }
+        if let Some(log) = &mut self.bus_log {
+            log.clear();
+        }
+        let delayed = std::mem::replace(&mut self.interrupt_delay, false);
+        let restart = if self.variant == CpuVariant::Intel8085 {
+            self.pending_restart(delayed)
+        } else {
+            None
+        };
+        let acknowledged = match (restart, self.interrupt_request) {
+            (Some(address), _) => {
+                self.restart(address);
+                Some(12)
+            },
+            (None, Some(ack)) if self.interruption_enabled && !delayed => Some(self.acknowledge(ack)),
+            _ => None,
+        };
+        if let Some(cycles) = acknowledged {
+            self.cycles += cycles as u64;
+            return Ok(StepOutcome::Interrupted(cycles));
+        }
+        if self.halted {
+            self.cycles += 4;
+            return Ok(StepOutcome::Halted(4));
+        }
+        self.step_instruction(delayed)
+    }
+
+    /// Whether an interrupt input is active, acknowledged or not.
+    #[inline]
+    fn interrupt_pending(&self) -> bool {
+        self.interrupt_request.is_some()
+            || (self.variant == CpuVariant::Intel8085
+                && (self.trap_pending || self.rst75_pending || self.rst65 || self.rst55))
+    }
+
+    /// Executes the instruction at PC. `delayed` is restored if it turns out to be undefined.
+    #[inline]
+    fn step_instruction(&mut self, delayed: bool) -> Result<StepOutcome, ExecutionError> {
+        let cycles = match self.execute() {
+            Ok(cycles) => cycles,
+            Err(opcode) => {
+                let address = self.registers.pc;
+                match self.undefined_opcodes {
+                    UndefinedOpcodes::Fail => {
+                        self.interrupt_delay = delayed;
+                        return Err(ExecutionError::IllegalOpcode { opcode, address });
+                    },
+                    UndefinedOpcodes::Break => {
+                        self.interrupt_delay = delayed;
+                        return Ok(StepOutcome::Breakpoint(address));
+                    },
+                    UndefinedOpcodes::Nop => {
+                        add16(&mut self.registers.pc, 1);
+                        4
+                    },
+                    UndefinedOpcodes::Alias => unreachable!("every undefined opcode has an alias"),
+                }
+            },
+        };
+        self.cycles += cycles as u64;
+        if self.halted {
+            Ok(StepOutcome::Halted(cycles))
+        } else {
+            Ok(StepOutcome::Executed(cycles))
+        }
+    }
+
+    fn acknowledge(&mut self, ack: InterruptAck) -> u32 {
+        self.interrupt_request = None;
+        let (address, cycles) = match ack {
+            InterruptAck::Rst(n) => (((n & 7) as u16) << 3, 11),
+            InterruptAck::Call(addr) => (addr, 17),
+        };
+        self.restart(address);
+        match self.variant {
+            CpuVariant::Intel8085 => cycles + 1,
+            CpuVariant::Intel8080 | CpuVariant::Kr580Vm80a => cycles,
+        }
+    }
+
+    /// Pushes PC and jumps to the interrupt handler at `address`.
+    fn restart(&mut self, address: u16) {
+        self.interruption_enabled = false;
+        self.halted = false;
+        self.push_u16(self.registers.pc);
+        self.registers.pc = address;
+    }
+
+    /// Returns the fetched opcode as an error if it is undefined. Nothing is executed in that case.
+    fn execute(&mut self) -> Result<u32, u8> {
+        let opcode = self.fetch_u8(self.registers.pc);
+        let op = if self.variant == CpuVariant::Intel8085 {
+            OPCODES_8085[opcode as usize]
+        } else if self.undefined_opcodes == UndefinedOpcodes::Alias {
+            OPCODES_WITH_ALIASES[opcode as usize]
+        } else {
+            OPCODES[opcode as usize]
+        };
+        // Conditional instructions clear it when the condition does not hold.
+        let mut taken = true;
+        match op {
+            Op::Nop => {
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Lxi(rp) => {
+                let data16 = self.fetch_u16(self.registers.pc.overflowing_add(1).0);
+                self.set_pair(rp, data16);
+                add16(&mut self.registers.pc, 3);
+            },
+            Op::Stax(r) => {
+                let addr = self.get_pair(r);
+                self.write_u8(addr, self.registers.a);
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Shld => {
+                let addr = self.fetch_u16(self.registers.pc.overflowing_add(1).0);
+                self.write_u8(addr, self.registers.l);
+                self.write_u8(addr.overflowing_add(1).0, self.registers.h);
+                add16(&mut self.registers.pc, 3);
+            },
+            Op::Sta => {
+                let addr = self.fetch_u16(self.registers.pc.overflowing_add(1).0);
+                self.write_u8(addr, self.registers.a);
+                add16(&mut self.registers.pc, 3);
+            },
+            Op::Inx(rp) => {
+                let value = self.get_pair(rp).overflowing_add(1).0;
+                self.set_pair(rp, value);
+                if self.variant == CpuVariant::Intel8085 {
+                    self.registers.flag_k = value == 0;
+                }
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Inr(reg) => {
+                let value = self.get_location(reg);
+                self.set_location(reg, value.overflowing_add(1).0);
+                self.registers.flag_ac = (value & 0x0F) + 1 > 0x0F;
+                let value = self.get_location(reg);
+                self.set_flags(value);
+                self.set_overflow(value == 0x80);
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Dcr(reg) => {
+                let value = self.get_location(reg);
+                self.set_location(reg, value.overflowing_sub(1).0);
+                self.registers.flag_ac = (value & 0x0F) != 0; // carry out of bit 3 of value + 0FFh
+                let value = self.get_location(reg);
+                self.set_flags(value);
+                self.set_overflow(value == 0x7F);
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Mvi(reg) => {
+                let data = self.fetch_u8(self.registers.pc.overflowing_add(1).0);
+                self.set_location(reg, data);
+                self.registers.pc = self.registers.pc.overflowing_add(2).0;
+            },
+            Op::Rlc => {
+                self.registers.flag_c = (self.registers.a & 0x80) != 0;
+                self.registers.a = self.registers.a.rotate_left(1);
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Rrc => {
+                self.registers.flag_c = (self.registers.a & 1) != 0;
+                self.registers.a = self.registers.a.rotate_right(1);
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Ral => {
+                let c = self.registers.flag_c;
+                self.registers.flag_c = (self.registers.a & 0x80) != 0;
+                self.registers.a <<= 1;
+                if c {
+                    self.registers.a |= 1;
+                }
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Rar => {
+                let c = self.registers.flag_c;
+                self.registers.flag_c = (self.registers.a & 1) != 0;
+                self.registers.a >>= 1;
+                if c {
+                    self.registers.a |= 0x80;
+                }
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Dad(rp) => {
+                let operand = self.get_pair(rp);
+                let hl = from_pair(self.registers.h, self.registers.l);
+                let (value, overflow) = hl.overflowing_add(operand);
+                let (h, l) = to_pair(value);
                 self.registers.flag_c = overflow;
-                self.set_a_flags();
-            }
-            if self.registers.a & 0xF0 > 0x90 || self.registers.flag_c {
-                let (value, overflow) = self.registers.a.overflowing_add(0x60);
-                self.registers.a = value;
-                if overflow {
+                self.registers.h = h;
+                self.registers.l = l;
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Ldax(r) => {
+                self.registers.a = self.read_u8(self.get_pair(r));
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Lhld => {
+                let addr = self.fetch_u16(self.registers.pc.overflowing_add(1).0);
+                self.registers.l = self.read_u8(addr);
+                self.registers.h = self.read_u8(addr.overflowing_add(1).0);
+                add16(&mut self.registers.pc, 3);
+            },
+            Op::Lda => {
+                let addr = self.fetch_u16(self.registers.pc.overflowing_add(1).0);
+                self.registers.a = self.read_u8(addr);
+                add16(&mut self.registers.pc, 3);
+            },
+            Op::Dcx(rp) => {
+                let value = self.get_pair(rp).overflowing_sub(1).0;
+                self.set_pair(rp, value);
+                if self.variant == CpuVariant::Intel8085 {
+                    self.registers.flag_k = value == 0xFFFF;
+                }
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Daa => {
+                let a = self.registers.a;
+                let mut correction = 0;
+                if a & 0x0F > 9 || self.registers.flag_ac {
+                    correction |= 0x06;
+                }
+                if a > 0x99 || self.registers.flag_c {
+                    correction |= 0x60;
                     self.registers.flag_c = true;
                 }
+                self.registers.flag_ac = (a & 0x0F) + (correction & 0x0F) > 0x0F;
+                self.registers.a = a.overflowing_add(correction).0;
                 self.set_a_flags();
-            }
-            add16(&mut self.registers.pc, 1);
-        } else if opcode == 0x2F {
-            // CMA
-            self.registers.a = !self.registers.a;
-            add16(&mut self.registers.pc, 1);
-        } else if opcode == 0x37 {
-            // STC
-            self.registers.flag_c = true;
-            add16(&mut self.registers.pc, 1);
-        } else if opcode == 0x3F {
-            // CMC
-            self.registers.flag_c = !self.registers.flag_c;
-            add16(&mut self.registers.pc, 1);
-        } else if opcode == 0b_01_110_110 {
-            self.halted = true;
-        } else if let Some((dst, src)) = ops2!(opcode, ('0', '1', 'X', 'X', 'X', 'Y', 'Y', 'Y')) {
-            // mov
-            let value = self.get_location(src);
-            self.set_location(dst, value);
-            add16(&mut self.registers.pc, 1);
-        } else if let Some((operation, operand_code)) = ops2!(opcode, ('1', '0', 'X', 'X', 'X', 'Y', 'Y', 'Y')) {
-            // arithmetic
-            let operand = self.get_location(operand_code);
-            match operation {
-                0 => self.add(operand, false),
-                1 => self.add(operand, self.registers.flag_c),
-                2 => self.sub(operand, false),
-                3 => self.sub(operand, self.registers.flag_c),
-                4 => {
-                    self.registers.a &= operand;
-                    self.registers.flag_c = false;
-                    self.set_a_flags();
-                },
-                5 => {
-                    self.registers.a ^= operand;
-                    self.registers.flag_c = false;
-                    self.registers.flag_ac = false;
-                    self.set_a_flags();
-                },
-                6 => {
-                    self.registers.a |= operand;
-                    self.registers.flag_c = false;
-                    self.set_a_flags();
-                },
-                7 => {
-                    let a = self.registers.a;
-                    self.sub(operand, false);
-                    self.registers.a = a; // restore A
-                },
-                _ => unreachable!()
-            }
-            add16(&mut self.registers.pc, 1);
-        } else if let Some(cond) = ops!(opcode, ('1', '1', 'X', 'X', 'X', '0', '0', '0')) {
-            // conditional return
-            if self.check_cond(cond) {
-                self.registers.pc = self.memory.get_u16(self.registers.sp);
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Cma => {
+                self.registers.a = !self.registers.a;
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Stc => {
+                self.registers.flag_c = true;
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Cmc => {
+                self.registers.flag_c = !self.registers.flag_c;
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Hlt => {
+                self.halted = true;
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Mov(dst, src) => {
+                let value = self.get_location(src);
+                self.set_location(dst, value);
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Alu(operation, operand_code) => {
+                let operand = self.get_location(operand_code);
+                self.alu(operation, operand);
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Rcond(cond) => {
+                if self.check_cond(cond) {
+                    self.registers.pc = self.read_u16(self.registers.sp);
+                    self.registers.sp = self.registers.sp.overflowing_add(2).0;
+                } else {
+                    add16(&mut self.registers.pc, 1);
+                    taken = false;
+                }
+            },
+            Op::Jcond(cond) => {
+                let addr = self.fetch_u16(self.registers.pc.overflowing_add(1).0);
+                if self.check_cond(cond) {
+                    self.registers.pc = addr;
+                } else {
+                    add16(&mut self.registers.pc, 3);
+                    taken = false;
+                }
+            },
+            Op::Ccond(cond) => {
+                // The address is read even if the call is not taken.
+                let addr = self.fetch_u16(self.registers.pc.overflowing_add(1).0);
+                if self.check_cond(cond) {
+                    self.push_u16(self.registers.pc.overflowing_add(3).0);
+                    self.registers.pc = addr;
+                } else {
+                    add16(&mut self.registers.pc, 3);
+                    taken = false;
+                }
+            },
+            Op::Pop(rp) => {
+                let data16 = self.read_u16(self.registers.sp);
+                self.set_pair_flags(rp, data16);
                 self.registers.sp = self.registers.sp.overflowing_add(2).0;
-            } else {
                 add16(&mut self.registers.pc, 1);
-            }
-        } else if let Some(cond) = ops!(opcode, ('1', '1', 'X', 'X', 'X', '0', '1', '0')) {
-            // conditional jump
-            if self.check_cond(cond) {
-                self.registers.pc = self.memory.get_u16(self.registers.pc.overflowing_add(1).0);
-            } else {
-                add16(&mut self.registers.pc, 3);
-            }
-        } else if let Some(cond) = ops!(opcode, ('1', '1', 'X', 'X', 'X', '1', '0', '0')) {
-            // conditional call
-            if self.check_cond(cond) {
-                sub16(&mut self.registers.sp, 2);
-                self.memory.set_u16(self.registers.sp, self.registers.pc.overflowing_add(3).0);
-                self.registers.pc = self.memory.get_u16(self.registers.pc.overflowing_add(1).0);
-            } else {
-                add16(&mut self.registers.pc, 3);
-            }
-        } else if let Some(rp) = ops!(opcode, ('1', '1', 'X', 'X', '0', '0', '0', '1')) {
-            // POP
-            let data16 = self.memory.get_u16(self.registers.sp);
-            self.set_pair_flags(rp, data16);
-            self.registers.sp = self.registers.sp.overflowing_add(2).0;
-            add16(&mut self.registers.pc, 1);
-        } else if let Some(rp) = ops!(opcode, ('1', '1', 'X', 'X', '0', '1', '0', '1')) {
-            // PUSH
-            let data16 = self.get_pair_flags(rp);
-            sub16(&mut self.registers.sp, 2);
-            self.memory.set_u16(self.registers.sp, data16);
-            add16(&mut self.registers.pc, 1);
-        } else if opcode == 0xC3 {
-            // JMP
-            self.registers.pc = self.memory.get_u16(self.registers.pc.overflowing_add(1).0);
-        } else if opcode == 0xC9 {
-            // RET
-            self.registers.pc = self.memory.get_u16(self.registers.sp);
-            self.registers.sp = self.registers.sp.overflowing_add(2).0;
-        } else if opcode == 0xCD {
-            // CALL
-            sub16(&mut self.registers.sp, 2);
-            self.memory.set_u16(self.registers.sp, self.registers.pc.overflowing_add(3).0);
-            self.registers.pc = self.memory.get_u16(self.registers.pc.overflowing_add(1).0);
-        } else if let Some(operation) = ops!(opcode, ('1', '1', 'X', 'X', 'X', '1', '1', '0')) {
-            // arithmetic
-            let operand = self.memory.get_u8(self.registers.pc.overflowing_add(1).0);
-            match operation {
-                0 => self.add(operand, false),
-                1 => self.add(operand, self.registers.flag_c),
-                2 => self.sub(operand, false),
-                3 => self.sub(operand, self.registers.flag_c),
-                4 => {
-                    self.registers.a &= operand;
-                    self.registers.flag_c = false;
-                    self.set_a_flags();
-                },
-                5 => {
-                    self.registers.a ^= operand;
-                    self.registers.flag_c = false;
-                    self.registers.flag_ac = false;
-                    self.set_a_flags();
-                },
-                6 => {
-                    self.registers.a |= operand;
-                    self.registers.flag_c = false;
-                    self.set_a_flags();
-                },
-                7 => {
-                    let a = self.registers.a;
-                    self.sub(operand, false);
-                    self.registers.a = a; // restore A
-                },
-                _ => unreachable!()
-            }
-            self.registers.pc = self.registers.pc.overflowing_add(2).0;
-        } else if let Some(exp) = ops!(opcode, ('1', '1', 'X', 'X', 'X', '1', '1', '1')) {
-            // RST
-            sub16(&mut self.registers.sp, 2);
-            self.memory.set_u16(self.registers.sp, self.registers.pc.overflowing_add(1).0);
-            self.registers.pc = (exp as u16) << 3;
-        } else if opcode == 0xD3 {
-            // OUT
-            let port = self.memory.get_u8(self.registers.pc.overflowing_add(1).0);
-            self.out(port, self.registers.a);
-            add16(&mut self.registers.pc, 2);
-        } else if opcode == 0xDB {
-            // IN
-            let port = self.memory.get_u8(self.registers.pc.overflowing_add(1).0);
-            self.registers.a = self.inp(port);
-            add16(&mut self.registers.pc, 2);
-        } else if opcode == 0xE3 {
-            // XTHL
-            self.memory.swap(self.registers.sp, &mut self.registers.l);
-            self.memory.swap(self.registers.sp + 1, &mut self.registers.h);
-            add16(&mut self.registers.pc, 1);
-        } else if opcode == 0xE9 {
-            // PCHL
-            self.registers.pc = self.registers.hl();
-        } else if opcode == 0xF9 {
-            // SPHL
-            self.registers.sp = self.registers.hl();
-            add16(&mut self.registers.pc, 1);
-        } else if opcode == 0xEB {
-            // XCHG
-            std::mem::swap(&mut self.registers.l, &mut self.registers.e);
-            std::mem::swap(&mut self.registers.h, &mut self.registers.d);
-            add16(&mut self.registers.pc, 1);
-        } else if opcode == 0xF3 {
-            // DI
-            add16(&mut self.registers.pc, 1);
-            self.interruption_enabled = false;
-        } else if opcode == 0xFB {
-            // EI
-            add16(&mut self.registers.pc, 1);
-            self.interruption_enabled = true;
+            },
+            Op::Push(rp) => {
+                let data16 = self.get_pair_flags(rp);
+                self.push_u16(data16);
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Jmp => {
+                self.registers.pc = self.fetch_u16(self.registers.pc.overflowing_add(1).0);
+            },
+            Op::Ret => {
+                self.registers.pc = self.read_u16(self.registers.sp);
+                self.registers.sp = self.registers.sp.overflowing_add(2).0;
+            },
+            Op::Call => {
+                let addr = self.fetch_u16(self.registers.pc.overflowing_add(1).0);
+                self.push_u16(self.registers.pc.overflowing_add(3).0);
+                self.registers.pc = addr;
+            },
+            Op::AluImm(operation) => {
+                let operand = self.fetch_u8(self.registers.pc.overflowing_add(1).0);
+                self.alu(operation, operand);
+                self.registers.pc = self.registers.pc.overflowing_add(2).0;
+            },
+            Op::Rst(exp) => {
+                self.push_u16(self.registers.pc.overflowing_add(1).0);
+                self.registers.pc = (exp as u16) << 3;
+            },
+            Op::Out => {
+                let port = self.fetch_u8(self.registers.pc.overflowing_add(1).0);
+                self.out(port, self.registers.a);
+                add16(&mut self.registers.pc, 2);
+            },
+            Op::In => {
+                let port = self.fetch_u8(self.registers.pc.overflowing_add(1).0);
+                self.registers.a = self.inp(port);
+                add16(&mut self.registers.pc, 2);
+            },
+            Op::Xthl => {
+                let l = self.read_u8(self.registers.sp);
+                let h = self.read_u8(self.registers.sp.overflowing_add(1).0);
+                self.write_u8(self.registers.sp.overflowing_add(1).0, self.registers.h);
+                self.write_u8(self.registers.sp, self.registers.l);
+                self.registers.l = l;
+                self.registers.h = h;
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Pchl => {
+                self.registers.pc = self.registers.hl();
+            },
+            Op::Sphl => {
+                self.registers.sp = self.registers.hl();
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Xchg => {
+                std::mem::swap(&mut self.registers.l, &mut self.registers.e);
+                std::mem::swap(&mut self.registers.h, &mut self.registers.d);
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Di => {
+                add16(&mut self.registers.pc, 1);
+                self.interruption_enabled = false;
+            },
+            Op::Ei => {
+                add16(&mut self.registers.pc, 1);
+                self.interruption_enabled = true;
+                self.interrupt_delay = true;
+            },
+            Op::Rim => {
+                self.registers.a = self.read_interrupt_mask();
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Sim => {
+                self.set_interrupt_mask(self.registers.a);
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Dsub => {
+                let l = self.sub8(self.registers.l, self.registers.c, false);
+                let h = self.sub8(self.registers.h, self.registers.b, self.registers.flag_c);
+                self.registers.h = h;
+                self.registers.l = l;
+                self.registers.flag_z = h | l == 0;
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Arhl => {
+                let hl = self.registers.hl();
+                self.registers.flag_c = hl & 1 != 0;
+                self.set_pair(2, ((hl as i16) >> 1) as u16);
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Rdel => {
+                let de = self.get_pair(1);
+                let value = de << 1 | self.registers.flag_c as u16;
+                self.set_pair(1, value);
+                self.registers.flag_c = de & 0x8000 != 0;
+                self.registers.flag_v = (de ^ value) & 0x8000 != 0;
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Ldhi | Op::Ldsi => {
+                let offset = self.fetch_u8(self.registers.pc.overflowing_add(1).0);
+                let base = if op == Op::Ldhi { self.registers.hl() } else { self.registers.sp };
+                self.set_pair(1, base.overflowing_add(offset as u16).0);
+                add16(&mut self.registers.pc, 2);
+            },
+            Op::Rstv => {
+                if self.registers.flag_v {
+                    self.push_u16(self.registers.pc.overflowing_add(1).0);
+                    self.registers.pc = 0x40;
+                } else {
+                    add16(&mut self.registers.pc, 1);
+                    taken = false;
+                }
+            },
+            Op::Shlx => {
+                let addr = self.get_pair(1);
+                self.write_u8(addr, self.registers.l);
+                self.write_u8(addr.overflowing_add(1).0, self.registers.h);
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Lhlx => {
+                let addr = self.get_pair(1);
+                self.registers.l = self.read_u8(addr);
+                self.registers.h = self.read_u8(addr.overflowing_add(1).0);
+                add16(&mut self.registers.pc, 1);
+            },
+            Op::Jk(k) => {
+                let addr = self.fetch_u16(self.registers.pc.overflowing_add(1).0);
+                if self.registers.flag_k == k {
+                    self.registers.pc = addr;
+                } else {
+                    add16(&mut self.registers.pc, 3);
+                    taken = false;
+                }
+            },
+            Op::Undefined => return Err(opcode),
+        }
+        let (taken_cycles, not_taken_cycles) = match self.variant {
+            CpuVariant::Intel8085 => op.cycles_8085(),
+            CpuVariant::Intel8080 | CpuVariant::Kr580Vm80a => op.cycles(),
+        };
+        Ok(if taken { taken_cycles } else { not_taken_cycles })
+    }
+
+    /// RIM: `SID I7.5 I6.5 I5.5 IE M7.5 M6.5 M5.5`.
+    fn read_interrupt_mask(&self) -> u8 {
+        (self.sid as u8) << 7 |
+        (self.rst75_pending as u8) << 6 |
+        (self.rst65 as u8) << 5 |
+        (self.rst55 as u8) << 4 |
+        (self.interruption_enabled as u8) << 3 |
+        self.interrupt_masks
+    }
+
+    /// SIM: `SOD SOE - R7.5 MSE M7.5 M6.5 M5.5`.
+    fn set_interrupt_mask(&mut self, value: u8) {
+        if value & 0x08 != 0 {
+            self.interrupt_masks = value & 0x07;
+        }
+        if value & 0x10 != 0 {
+            self.rst75_pending = false;
+        }
+        if value & 0x40 != 0 {
+            self.sod = value & 0x80 != 0;
+        }
+    }
+
+    /// Restart address of the 8085 interrupt input to acknowledge, by priority.
+    fn pending_restart(&mut self, delayed: bool) -> Option<u16> {
+        if self.trap_pending {
+            self.trap_pending = false;
+            return Some(0x24);
+        }
+        if !self.interruption_enabled || delayed {
+            return None;
+        }
+        if self.rst75_pending && self.interrupt_masks & 0b100 == 0 {
+            self.rst75_pending = false;
+            Some(0x3C)
+        } else if self.rst65 && self.interrupt_masks & 0b010 == 0 {
+            Some(0x34)
+        } else if self.rst55 && self.interrupt_masks & 0b001 == 0 {
+            Some(0x2C)
         } else {
-            panic!("Bad opcode 0x{:02X} at 0x{:04X}.", opcode, self.registers.pc);
+            None
+        }
+    }
+
+    fn alu(&mut self, operation: u8, operand: u8) {
+        match operation {
+            0 => self.add(operand, false),
+            1 => self.add(operand, self.registers.flag_c),
+            2 => self.sub(operand, false),
+            3 => self.sub(operand, self.registers.flag_c),
+            4 => {
+                self.registers.flag_ac = match self.variant {
+                    CpuVariant::Intel8080 | CpuVariant::Kr580Vm80a => (self.registers.a | operand) & 0x08 != 0,
+                    CpuVariant::Intel8085 => true,
+                };
+                self.registers.a &= operand;
+                self.registers.flag_c = false;
+                self.set_a_flags();
+            },
+            5 => {
+                self.registers.a ^= operand;
+                self.registers.flag_c = false;
+                self.registers.flag_ac = false;
+                self.set_a_flags();
+            },
+            6 => {
+                self.registers.a |= operand;
+                self.registers.flag_c = false;
+                self.registers.flag_ac = false;
+                self.set_a_flags();
+            },
+            7 => {
+                let a = self.registers.a;
+                self.sub(operand, false);
+                self.registers.a = a; // restore A
+            },
+            _ => unreachable!()
         }
     }
 
     fn set_flags(&mut self, register: u8) {
         self.registers.flag_s = register >= 0b_1000_0000;
         self.registers.flag_z = register == 0;
-        self.registers.flag_p = register.count_ones() % 2 == 0;
+        self.registers.flag_p = register.count_ones().is_multiple_of(2);
     }
 
     fn set_a_flags(&mut self) {
         self.set_flags(self.registers.a);
     }
 
-    fn add(&mut self, operand: u8, carry: bool) {
-        let (o, a) = to_pair((self.registers.a as u16) + (operand as u16) + if carry { 1 } else { 0 });
-        self.registers.a = a;
+    /// Sets V and K on the 8085, K being `S xor V` as after a signed comparison.
+    fn set_overflow(&mut self, overflow: bool) {
+        if self.variant == CpuVariant::Intel8085 {
+            self.registers.flag_v = overflow;
+            self.registers.flag_k = self.registers.flag_s != overflow;
+        }
+    }
+
+    fn add8(&mut self, x: u8, y: u8, carry: bool) -> u8 {
+        let carry = carry as u8;
+        let (o, result) = to_pair((x as u16) + (y as u16) + (carry as u16));
         self.registers.flag_c = o > 0;
-        self.registers.flag_ac = (self.registers.a & 0x0F) + (operand & 0x0F) + if carry { 1 } else { 0 } > 0x0F;
-        self.set_a_flags();
+        self.registers.flag_ac = (x & 0x0F) + (y & 0x0F) + carry > 0x0F;
+        self.set_flags(result);
+        self.set_overflow((x ^ result) & (y ^ result) & 0x80 != 0);
+        result
     }
 
-    fn sub(&mut self, operand: u8, carry: bool) {
-        let operand = operand.overflowing_add(if carry { 1 } else { 0 }).0;
-        let (o, a) = to_pair((self.registers.a as u16) + (neg(operand) as u16));
-        self.registers.a = a;
-        self.registers.flag_c = o <= 0;
-        self.registers.flag_ac = (self.registers.a & 0x0F) + (neg(operand) & 0x0F) <= 0x0F;
-        self.set_a_flags();
+    /// Subtraction is addition of the complemented operand with inverted borrow;
+    /// C is the inverted carry out of bit 7 and AC the (not inverted) carry out of bit 3.
+    fn sub8(&mut self, x: u8, y: u8, borrow: bool) -> u8 {
+        let result = self.add8(x, !y, !borrow);
+        self.registers.flag_c = !self.registers.flag_c;
+        result
+    }
+
+    fn add(&mut self, operand: u8, carry: bool) {
+        self.registers.a = self.add8(self.registers.a, operand, carry);
+    }
+
+    fn sub(&mut self, operand: u8, borrow: bool) {
+        self.registers.a = self.sub8(self.registers.a, operand, borrow);
     }
 
     fn get_location(&mut self, reg: u8) -> u8 {
@@ -484,7 +1455,7 @@ impl Machine {
             3 => self.registers.e,
             4 => self.registers.h,
             5 => self.registers.l,
-            6 => self.memory.get_u8(self.registers.hl()),
+            6 => self.read_u8(self.registers.hl()),
             7 => self.registers.a,
             _ => unreachable!(),
         }
@@ -498,7 +1469,7 @@ impl Machine {
             3 => self.registers.e = value,
             4 => self.registers.h = value,
             5 => self.registers.l = value,
-            6 => self.memory.set_u8(self.registers.hl(), value),
+            6 => self.write_u8(self.registers.hl(), value),
             7 => self.registers.a = value,
             _ => unreachable!(),
         }
@@ -530,7 +1501,7 @@ impl Machine {
             0 => from_pair(self.registers.b, self.registers.c),
             1 => from_pair(self.registers.d, self.registers.e),
             2 => from_pair(self.registers.h, self.registers.l),
-            3 => from_pair(self.registers.get_flags_byte(), self.registers.a),
+            3 => from_pair(self.registers.a, self.variant.flags_byte(&self.registers)),
             _ => unreachable!(),
         }
     }
@@ -541,7 +1512,7 @@ impl Machine {
             0 => { self.registers.b = h; self.registers.c = l; },
             1 => { self.registers.d = h; self.registers.e = l; },
             2 => { self.registers.h = h; self.registers.l = l; },
-            3 => { self.registers.set_flags_byte(h); self.registers.a = l; },
+            3 => { self.registers.a = h; self.variant.set_flags_byte(&mut self.registers, l); },
             _ => unreachable!(),
         }
     }
@@ -560,29 +1531,258 @@ impl Machine {
         }
     }
 
-    pub fn out(&self, port: u8, data: u8) {
-        println!("OUT: port 0x{:02X} data 0x{:02X}", port, data);
+    pub fn out(&mut self, port: u8, data: u8) {
+        self.log(BusAccess::Out { port, value: data });
+        self.io.out(port, data);
     }
 
-    pub fn inp(&self, port: u8) -> u8 {
-        println!("IN: port 0x{:02X}", port);
-        0
+    pub fn inp(&mut self, port: u8) -> u8 {
+        let value = self.io.inp(port);
+        self.log(BusAccess::In { port, value });
+        value
+    }
+
+    #[inline]
+    fn log(&mut self, access: BusAccess) {
+        if let Some(log) = &mut self.bus_log {
+            log.push(access);
+        }
+    }
+
+    #[inline]
+    fn fetch_u8(&mut self, address: u16) -> u8 {
+        let value = self.memory.get_u8(address);
+        self.log(BusAccess::Fetch { address, value });
+        value
+    }
+
+    #[inline]
+    fn fetch_u16(&mut self, address: u16) -> u16 {
+        let l = self.fetch_u8(address);
+        let h = self.fetch_u8(address.overflowing_add(1).0);
+        from_pair(h, l)
+    }
+
+    #[inline]
+    fn read_u8(&mut self, address: u16) -> u8 {
+        let value = self.memory.get_u8(address);
+        self.log(BusAccess::Read { address, value });
+        value
+    }
+
+    #[inline]
+    fn read_u16(&mut self, address: u16) -> u16 {
+        let l = self.read_u8(address);
+        let h = self.read_u8(address.overflowing_add(1).0);
+        from_pair(h, l)
+    }
+
+    #[inline]
+    fn write_u8(&mut self, address: u16, value: u8) {
+        if self.bus_log.is_some() {
+            let old = self.memory.get_u8(address);
+            self.log(BusAccess::Write { address, value, old });
+        }
+        self.memory.set_u8(address, value);
+    }
+
+    /// Pushes a word in the bus order of the 8080: the high byte goes first.
+    #[inline]
+    fn push_u16(&mut self, value: u16) {
+        let (h, l) = to_pair(value);
+        sub16(&mut self.registers.sp, 1);
+        self.write_u8(self.registers.sp, h);
+        sub16(&mut self.registers.sp, 1);
+        self.write_u8(self.registers.sp, l);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rs580_macros::asm8080;
+
+    fn alu_flags(a: u8, operation: u8, operand: u8, carry: bool) -> (u8, bool, bool) {
+        use crate::ram::RAM;
+
+        let mut m = Machine::new(RAM::default());
+        m.registers.a = a;
+        m.registers.flag_c = carry;
+        m.alu(operation, operand);
+        (m.registers.a, m.registers.flag_c, m.registers.flag_ac)
+    }
+
+    #[test]
+    fn test_arithmetic_flags() {
+        // (A, C, AC)
+        assert_eq!(alu_flags(0x3A, 0, 0x06, false), (0x40, false, true));
+        assert_eq!(alu_flags(0xF0, 1, 0x0F, true), (0x00, true, true));
+        assert_eq!(alu_flags(0x03, 2, 0x05, false), (0xFE, true, false));
+        assert_eq!(alu_flags(0x15, 2, 0x03, false), (0x12, false, true));
+        assert_eq!(alu_flags(0x00, 2, 0x00, false), (0x00, false, true));
+        assert_eq!(alu_flags(0x10, 3, 0xFF, true), (0x10, true, false));
+        assert_eq!(alu_flags(0x10, 3, 0x0F, true), (0x00, false, false));
+        assert_eq!(alu_flags(0x05, 7, 0x05, false), (0x05, false, true));
+        assert_eq!(alu_flags(0x05, 6, 0x00, true), (0x05, false, false));
+    }
+
+    #[test]
+    fn test_variants() {
+        use crate::ram::RAM;
+
+        // (ANI 08h with A=01h) -> AC, (ANI 01h with A=01h) -> AC, pushed flags after STC
+        let expected = [
+            (CpuVariant::Intel8080, true, false, 0x03),
+            (CpuVariant::Kr580Vm80a, true, false, 0x03),
+            (CpuVariant::Intel8085, true, true, 0x11),
+        ];
+        for &(variant, ac_bit3, ac_no_bit3, flags) in &expected {
+            let program = asm8080! {
+                    LXI SP,100h;
+                    MVI A,1;
+                    ANI 8;
+                    MVI A,1;
+                    ANI 1;
+                    STC;
+                    PUSH PSW
+            };
+            let mut m = Machine::new(RAM::default());
+            m.variant = variant;
+            m.memory.set_range(program.origin, &program.bytes);
+            for _ in 0..3 {
+                m.step().unwrap();
+            }
+            assert_eq!(m.registers.flag_ac, ac_bit3, "{:?}", variant);
+            for _ in 0..2 {
+                m.step().unwrap();
+            }
+            assert_eq!(m.registers.flag_ac, ac_no_bit3, "{:?}", variant);
+            for _ in 0..2 {
+                m.step().unwrap();
+            }
+            assert_eq!(m.memory.get_u16(0xFE), 0x0100 | flags as u16, "{:?}", variant);
+            m.registers.set_flags_byte(0xFF);
+            assert_eq!(variant.flags_byte(&m.registers), 0xD5 | flags & 0x02, "{:?}", variant);
+        }
+    }
 
     #[test]
-    fn test_neg() {
-        assert_eq!(neg(0), 0);
-        assert_eq!(neg(1), 0xFF);
-        assert_eq!(neg(127), 0x81);
-        assert_eq!(neg(128), 128);
+    fn test_8085_instructions() {
+        use crate::ram::RAM;
+
+        let program = asm8080! {
+                LXI H,8001h;
+                LXI B,0002h;
+                DSUB;
+                ARHL;
+                LXI D,8000h;
+                RDEL;
+                LDHI 10h;
+                SHLX;
+                INX D;
+                LHLX;
+                RSTV;
+                LXI D,0FFFFh;
+                INX D;
+                JK done;
+                HLT;
+            done:
+                LDSI 2;
+                HLT
+        };
+        let mut m = Machine::new(RAM::default());
+        m.variant = CpuVariant::Intel8085;
+        m.memory.set_range(program.origin, &program.bytes);
+        m.registers.sp = 0x2000;
+        for _ in 0..3 {
+            m.step().unwrap();
+        }
+        // 8001h - 0002h overflows
+        assert_eq!(m.registers.hl(), 0x7FFF);
+        assert_eq!((m.registers.flag_v, m.registers.flag_k, m.registers.flag_c), (true, true, false));
+        assert_eq!(m.step(), Ok(StepOutcome::Executed(7)));
+        assert_eq!((m.registers.hl(), m.registers.flag_c), (0x3FFF, true));
+        m.step().unwrap();
+        m.step().unwrap();
+        assert_eq!((m.registers.d, m.registers.e, m.registers.flag_c, m.registers.flag_v), (0x00, 0x01, true, true));
+        m.step().unwrap();
+        assert_eq!((m.registers.d, m.registers.e), (0x40, 0x0F));
+        m.step().unwrap();
+        assert_eq!(m.memory.get_u16(0x400F), 0x3FFF);
+        m.memory.set_u8(0x4011, 0x12);
+        m.step().unwrap();
+        m.step().unwrap();
+        assert_eq!(m.registers.hl(), 0x123F);
+        // V is still set by RDEL
+        assert_eq!(m.step(), Ok(StepOutcome::Executed(12)));
+        assert_eq!(m.registers.pc, 0x40);
+        assert_eq!(m.memory.get_u16(0x1FFE), program.labels.done - 8);
+
+        m.registers.pc = program.labels.done - 8;
+        m.step().unwrap();
+        m.step().unwrap();
+        assert!(m.registers.flag_k);
+        assert_eq!(m.step(), Ok(StepOutcome::Executed(10)));
+        assert_eq!(m.registers.pc, program.labels.done);
+        m.step().unwrap();
+        assert_eq!((m.registers.d, m.registers.e), (0x20, 0x00));
     }
 
     #[test]
+    fn test_8085_interrupts() {
+        use crate::ram::RAM;
+
+        let program = asm8080! {
+                LXI SP,100h;
+                EI;
+                NOP;
+                MVI A,0x0E; // unmask RST 5.5
+                SIM;
+                NOP;
+                ORG 2Ch;
+                RIM;
+                MVI A,0C0h; // SOD = 1
+                SIM;
+                EI;
+                HLT
+        };
+        let mut m = Machine::new(RAM::default());
+        m.variant = CpuVariant::Intel8085;
+        m.memory.set_range(program.origin, &program.bytes);
+        m.rst55 = true;
+        m.sid = true;
+        for _ in 0..5 {
+            m.step().unwrap();
+        }
+        assert_eq!(m.registers.pc, 8);
+        assert_eq!(m.step(), Ok(StepOutcome::Interrupted(12)));
+        assert_eq!(m.registers.pc, 0x2C);
+        assert_eq!(m.memory.get_u16(0xFE), 8);
+        m.rst55 = false;
+
+        m.rst75();
+        m.step().unwrap();
+        // SID, I7.5 pending, IE cleared, 7.5 and 6.5 masked
+        assert_eq!(m.registers.a, 0b1100_0110);
+        m.step().unwrap();
+        m.step().unwrap();
+        assert!(m.sod);
+
+        // TRAP is accepted with interrupts disabled, the masked RST 7.5 stays pending
+        m.trap();
+        assert_eq!(m.step(), Ok(StepOutcome::Interrupted(12)));
+        assert_eq!(m.registers.pc, 0x24);
+        assert_eq!(m.memory.get_u16(0xFC), 0x30);
+
+        let snapshot = m.save_snapshot();
+        let mut restored = Machine::new(RAM::default());
+        restored.load_snapshot(&snapshot).unwrap();
+        assert_eq!(restored.save_snapshot(), snapshot);
+        assert!(restored.sod && restored.rst75_pending);
+    }
+
+    #[test]
+    #[allow(clippy::unusual_byte_groupings)]
     fn test_mask() {
         const E: OpMask = ('0', '1', 'X', 'X', 'X', '1', '0', '0');
         const MASK: u8 = mask(E);
@@ -602,4 +1802,295 @@ mod tests {
             None
         );
     }
+
+    #[test]
+    fn test_decode_tables() {
+        let undefined: Vec<usize> = (0..256).filter(|&i| OPCODES[i] == Op::Undefined).collect();
+        assert_eq!(undefined, vec![0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38, 0xCB, 0xD9, 0xDD, 0xED, 0xFD]);
+        assert!(OPCODES_WITH_ALIASES.iter().all(|op| *op != Op::Undefined));
+
+        assert_eq!(OPCODES[0x76], Op::Hlt);
+        assert_eq!(OPCODES[0x7E], Op::Mov(7, 6));
+        assert_eq!(OPCODES[0xBB], Op::Alu(7, 3));
+        assert_eq!(OPCODES[0xFF], Op::Rst(7));
+        assert_eq!(OPCODES_WITH_ALIASES[0xDD], Op::Call);
+    }
+
+    #[test]
+    fn test_cycle_table_matches_execution() {
+        use crate::ram::RAM;
+
+        for &variant in &[CpuVariant::Intel8080, CpuVariant::Intel8085] {
+            for opcode in 0..=255 {
+                let (op, (taken, not_taken)) = if variant == CpuVariant::Intel8085 {
+                    let op = OPCODES_8085[opcode as usize];
+                    (op, op.cycles_8085())
+                } else {
+                    let op = OPCODES_WITH_ALIASES[opcode as usize];
+                    (op, op.cycles())
+                };
+                for &flags in &[0x00, 0xFF] {
+                    let mut m = Machine::new(RAM::default());
+                    m.variant = variant;
+                    m.memory.set_range(0x100, &[opcode, 0x00, 0x02]);
+                    m.registers.pc = 0x100;
+                    m.registers.sp = 0x1000;
+                    variant.set_flags_byte(&mut m.registers, flags);
+                    let cycles = m.step().unwrap().cycles();
+                    let sequential = m.registers.pc == 0x100 + op.len();
+                    match op {
+                        Op::Rcond(_) | Op::Ccond(_) | Op::Jcond(_) | Op::Jk(_) | Op::Rstv if sequential => {
+                            assert_eq!(cycles, not_taken, "{:?} opcode {:02X}", variant, opcode)
+                        },
+                        _ => assert_eq!(cycles, taken, "{:?} opcode {:02X}", variant, opcode),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_conditional_call_cycles() {
+        use crate::ram::RAM;
+
+        let mut m = Machine::new(RAM::default());
+        let program = asm8080! {
+                CNZ routine;
+                CZ routine;
+                ORG 10h;
+            routine:
+                RNZ;
+                RZ
+        };
+        m.memory.set_range(program.origin, &program.bytes);
+        m.registers.sp = 0x100;
+        m.registers.flag_z = true;
+
+        assert_eq!(m.step(), Ok(StepOutcome::Executed(11))); // CNZ not taken
+        assert_eq!(m.registers.pc, 3);
+        assert_eq!(m.step(), Ok(StepOutcome::Executed(17))); // CZ taken
+        assert_eq!(m.registers.pc, 0x10);
+        assert_eq!(m.step(), Ok(StepOutcome::Executed(5))); // RNZ not taken
+        assert_eq!(m.step(), Ok(StepOutcome::Executed(11))); // RZ taken
+        assert_eq!(m.registers.pc, 6);
+        assert_eq!(m.cycles, 11 + 17 + 5 + 11);
+    }
+
+    #[test]
+    fn test_in_out() {
+        use crate::io::MirroredIo;
+        use crate::ram::RAM;
+
+        let mut m = Machine::new(RAM::default())
+            .with_io(Box::new(MirroredIo(RAM::default())));
+        let program = asm8080! { OUT 12h; XRA A; IN 12h };
+        m.memory.set_range(program.origin, &program.bytes);
+        m.registers.a = 0x5A;
+        assert_eq!(m.step(), Ok(StepOutcome::Executed(10)));
+        m.step().unwrap();
+        assert_eq!(m.registers.a, 0);
+        assert_eq!(m.step(), Ok(StepOutcome::Executed(10)));
+        assert_eq!(m.registers.a, 0x5A);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        use crate::ram::RAM;
+
+        let mut m = Machine::new(RAM::default());
+        let program = asm8080! { DI; NOP; EI; NOP; HLT };
+        m.memory.set_range(program.origin, &program.bytes);
+        m.registers.sp = 0x100;
+
+        m.step().unwrap();
+        m.interrupt(InterruptAck::Rst(7));
+        m.step().unwrap();
+        assert_eq!(m.registers.pc, 2); // disabled
+        m.step().unwrap();
+        assert_eq!(m.registers.pc, 3); // EI
+        m.step().unwrap();
+        assert_eq!(m.registers.pc, 4); // one more instruction after EI
+        assert_eq!(m.step(), Ok(StepOutcome::Interrupted(11))); // RST 7
+        assert_eq!(m.registers.pc, 0x38);
+        assert_eq!(m.memory.get_u16(m.registers.sp), 4);
+        assert!(!m.interruption_enabled);
+        assert_eq!(m.interrupt_request, None);
+    }
+
+    #[test]
+    fn test_interrupt_wakes_halted() {
+        use crate::ram::RAM;
+
+        let mut m = Machine::new(RAM::default());
+        let program = asm8080! { HLT };
+        m.memory.set_range(program.origin, &program.bytes);
+        m.registers.sp = 0x100;
+
+        assert_eq!(m.step(), Ok(StepOutcome::Halted(7)));
+        assert!(m.halted);
+        assert_eq!(m.step(), Ok(StepOutcome::Halted(4)));
+        assert_eq!(m.registers.pc, 1);
+
+        m.interrupt(InterruptAck::Call(0x1234));
+        assert_eq!(m.step(), Ok(StepOutcome::Interrupted(17)));
+        assert!(!m.halted);
+        assert_eq!(m.registers.pc, 0x1234);
+        assert_eq!(m.memory.get_u16(m.registers.sp), 1);
+    }
+
+    #[test]
+    fn test_undefined_opcodes() {
+        use crate::ram::RAM;
+
+        let mut m = Machine::new(RAM::default());
+        m.memory.set_range(0, &[0x00, 0x08]);
+        m.undefined_opcodes = UndefinedOpcodes::Fail;
+
+        m.step().unwrap();
+        assert_eq!(m.step(), Err(ExecutionError::IllegalOpcode { opcode: 0x08, address: 1 }));
+        assert_eq!(m.registers.pc, 1);
+
+        m.undefined_opcodes = UndefinedOpcodes::Break;
+        assert_eq!(m.step(), Ok(StepOutcome::Breakpoint(1)));
+        assert_eq!(m.registers.pc, 1);
+
+        m.undefined_opcodes = UndefinedOpcodes::Nop;
+        assert_eq!(m.step(), Ok(StepOutcome::Executed(4)));
+        assert_eq!(m.registers.pc, 2);
+        assert_eq!(m.cycles, 8);
+
+        /// Every read returns the next undefined opcode: 08h, 18h, 28h...
+        struct ReadCounter(std::cell::Cell<u8>);
+
+        impl Memory for ReadCounter {
+            fn get_u8(&self, _addr: u16) -> u8 {
+                let value = self.0.get();
+                self.0.set(value + 0x10);
+                value
+            }
+
+            fn set_u8(&mut self, _addr: u16, _value: u8) {
+            }
+        }
+
+        let mut m = Machine::new(ReadCounter(std::cell::Cell::new(0x08)));
+        m.undefined_opcodes = UndefinedOpcodes::Fail;
+        assert_eq!(m.step(), Err(ExecutionError::IllegalOpcode { opcode: 0x08, address: 0 }));
+        assert_eq!(m.memory.0.get(), 0x18);
+    }
+
+    #[test]
+    fn test_undocumented_aliases() {
+        use crate::ram::RAM;
+
+        let mut m = Machine::new(RAM::default());
+        m.registers.sp = 0x100;
+        m.memory.set_range(0, &[0x08, 0x38, 0xCB, 0x10, 0x00]); // NOP; NOP; JMP 0010h
+        m.memory.set_range(0x10, &[0xDD, 0x20, 0x00, 0xED, 0x20, 0x00, 0xFD, 0x20, 0x00]); // CALL 0020h x3
+        m.memory.set_u8(0x20, 0xD9); // RET
+
+        assert_eq!(m.step(), Ok(StepOutcome::Executed(4)));
+        assert_eq!(m.step(), Ok(StepOutcome::Executed(4)));
+        assert_eq!(m.registers.pc, 2);
+        assert_eq!(m.step(), Ok(StepOutcome::Executed(10)));
+        assert_eq!(m.registers.pc, 0x10);
+        for ret in &[0x13, 0x16, 0x19] {
+            assert_eq!(m.step(), Ok(StepOutcome::Executed(17)));
+            assert_eq!(m.registers.pc, 0x20);
+            assert_eq!(m.step(), Ok(StepOutcome::Executed(10)));
+            assert_eq!(m.registers.pc, *ret);
+        }
+        assert_eq!(m.registers.sp, 0x100);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        use crate::ram::RAM;
+        use crate::rom::ROM;
+        use crate::segmented_memory::SegmentedMemory;
+
+        fn machine() -> BoxedMachine {
+            let memory = SegmentedMemory::new()
+                .add(0x0000, 0x1000, Box::new(RAM::new(0x1000)))
+                .add(0xF000, 0x10000, Box::new(ROM::new(&[0xC3, 0x00, 0x00])));
+            Machine::new(Box::new(memory))
+        }
+
+        let mut m = machine();
+        let program = asm8080! {
+                MVI A,12h;
+            again:
+                STA 0800h;
+                INR A;
+                JMP again
+        };
+        m.memory.set_range(program.origin, &program.bytes);
+        m.registers.sp = 0x1000;
+        for _ in 0..10 {
+            m.step().unwrap();
+        }
+        m.interrupt(InterruptAck::Rst(1));
+        let snapshot = m.save_snapshot();
+
+        let mut restored = machine();
+        restored.load_snapshot(&snapshot).unwrap();
+        assert_eq!(restored.save_snapshot(), snapshot);
+        assert_eq!(restored.registers.a, m.registers.a);
+        assert_eq!(restored.registers.pc, m.registers.pc);
+        assert_eq!(restored.cycles, m.cycles);
+        assert_eq!(restored.interrupt_request, Some(InterruptAck::Rst(1)));
+        assert_eq!(restored.memory.get_u8(0x800), m.memory.get_u8(0x800));
+
+        for _ in 0..10 {
+            m.step().unwrap();
+            restored.step().unwrap();
+        }
+        assert_eq!(restored.save_snapshot(), m.save_snapshot());
+    }
+
+    #[test]
+    fn test_snapshot_errors() {
+        use crate::ram::RAM;
+
+        let m = Machine::new(RAM::new(0x100));
+        let snapshot = m.save_snapshot();
+
+        let mut other = Machine::new(RAM::new(0x200));
+        assert!(matches!(other.load_snapshot(&snapshot), Err(SnapshotError::Mismatch(_))));
+        assert_eq!(other.load_snapshot(b"garbage"), Err(SnapshotError::BadMagic));
+        assert_eq!(other.load_snapshot(&snapshot[..20]), Err(SnapshotError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn test_failed_snapshot_leaves_machine_unchanged() {
+        use crate::ram::RAM;
+        use crate::segmented_memory::SegmentedMemory;
+
+        fn machine(second: usize) -> Machine<SegmentedMemory> {
+            Machine::new(SegmentedMemory::new()
+                .add(0x0000, 0x0100, Box::new(RAM::new(0x100)))
+                .add(0x0100, 0x0100 + second, Box::new(RAM::new(second))))
+        }
+
+        let mut source = machine(0x100);
+        source.memory.set_u8(0x0010, 0x55);
+        source.registers.a = 0x12;
+        let snapshot = source.save_snapshot();
+
+        let mut other = machine(0x200);
+        other.memory.set_u8(0x0010, 0xAA);
+        let before = other.save_snapshot();
+        assert!(matches!(other.load_snapshot(&snapshot), Err(SnapshotError::Mismatch(_))));
+        assert_eq!(other.save_snapshot(), before);
+
+        let mut same = machine(0x100);
+        same.memory.set_u8(0x0010, 0xAA);
+        let before = same.save_snapshot();
+        let mut trailing = snapshot.clone();
+        trailing.push(0);
+        assert_eq!(same.load_snapshot(&trailing), Err(SnapshotError::TrailingData));
+        assert_eq!(same.save_snapshot(), before);
+        assert_eq!(same.load_snapshot(&snapshot), Ok(()));
+        assert_eq!((same.memory.get_u8(0x0010), same.registers.a), (0x55, 0x12));
+    }
 }
@@ -0,0 +1,345 @@
+//! Breakpoints, watchpoints and run-until helpers on top of `Machine::step`.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::RangeInclusive;
+use crate::cpu::{BusAccess, ExecutionError, Machine, StepOutcome};
+use crate::history::History;
+use crate::memory::Memory;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressSpace {
+    Memory,
+    Io,
+}
+
+/// Which accesses trigger a watchpoint. For I/O ports reads are IN and writes are OUT.
+/// Instruction fetches never trigger memory watchpoints.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub space: AddressSpace,
+    pub range: RangeInclusive<u16>,
+    pub kind: WatchKind,
+    /// Only trigger when this value is read or written.
+    pub value: Option<u8>,
+}
+
+impl Watchpoint {
+    pub fn memory(range: RangeInclusive<u16>, kind: WatchKind) -> Self {
+        Self { space: AddressSpace::Memory, range, kind, value: None }
+    }
+
+    pub fn port(range: RangeInclusive<u8>, kind: WatchKind) -> Self {
+        let range = *range.start() as u16..=*range.end() as u16;
+        Self { space: AddressSpace::Io, range, kind, value: None }
+    }
+
+    pub fn with_value(mut self, value: u8) -> Self {
+        self.value = Some(value);
+        self
+    }
+
+    pub fn matches(&self, access: &BusAccess) -> bool {
+        let (space, address, value, write) = match *access {
+            BusAccess::Fetch { .. } => return false,
+            BusAccess::Read { address, value } => (AddressSpace::Memory, address, value, false),
+            BusAccess::Write { address, value, .. } => (AddressSpace::Memory, address, value, true),
+            BusAccess::In { port, value } => (AddressSpace::Io, port as u16, value, false),
+            BusAccess::Out { port, value } => (AddressSpace::Io, port as u16, value, true),
+        };
+        let kind = match self.kind {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::Access => true,
+        };
+        space == self.space
+            && kind
+            && self.range.contains(&address)
+            && self.value.is_none_or(|v| v == value)
+    }
+}
+
+/// Why a run stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stop {
+    /// PC reached a breakpoint. The instruction at it is not executed yet.
+    Breakpoint(u16),
+    /// The last executed instruction made an access matching the watchpoint with given id.
+    Watchpoint { id: usize, access: BusAccess },
+    /// The processor halted.
+    Halted,
+    /// Undefined opcode at given address under `UndefinedOpcodes::Break`.
+    UndefinedOpcode(u16),
+    /// The predicate of `run_until` returned true.
+    Condition,
+    /// The cycle budget of `run_for_cycles` is spent.
+    CyclesElapsed,
+    /// `reverse_continue` undid all recorded steps.
+    HistoryStart,
+}
+
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    watchpoints: BTreeMap<usize, Watchpoint>,
+    next_watchpoint: usize,
+    /// Set when `step` turned `Machine::bus_log` on for watchpoints. It is turned off again
+    /// once no watchpoints are left.
+    enabled_bus_log: bool,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address);
+    }
+
+    /// Returns false if there was no breakpoint at the address.
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn has_breakpoint(&self, address: u16) -> bool {
+        self.breakpoints.contains(&address)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().cloned()
+    }
+
+    /// Returns the id of the new watchpoint.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        let id = self.next_watchpoint;
+        self.next_watchpoint += 1;
+        self.watchpoints.insert(id, watchpoint);
+        id
+    }
+
+    pub fn remove_watchpoint(&mut self, id: usize) -> Option<Watchpoint> {
+        self.watchpoints.remove(&id)
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = (usize, &Watchpoint)> {
+        self.watchpoints.iter().map(|(id, watchpoint)| (*id, watchpoint))
+    }
+
+    /// Executes one instruction and reports a watchpoint hit or halt. Breakpoints are not
+    /// checked, so this also steps off a breakpoint.
+    pub fn step<M: Memory>(&mut self, machine: &mut Machine<M>) -> Result<Option<Stop>, ExecutionError> {
+        let watching = !self.watchpoints.is_empty();
+        if watching && machine.bus_log.is_none() {
+            machine.bus_log = Some(Vec::new());
+            self.enabled_bus_log = true;
+        } else if !watching && self.enabled_bus_log {
+            // A hook added since, such as a `History`, may need the log now.
+            if !machine.hooks_want_bus_log() {
+                machine.bus_log = None;
+            }
+            self.enabled_bus_log = false;
+        }
+        let outcome = machine.step()?;
+        if watching {
+            if let Some(stop) = self.check_watchpoints(machine.bus_log.as_deref().unwrap_or_default()) {
+                return Ok(Some(stop));
+            }
+        }
+        Ok(match outcome {
+            StepOutcome::Executed(_) | StepOutcome::Interrupted(_) => None,
+            StepOutcome::Halted(_) => Some(Stop::Halted),
+            StepOutcome::Breakpoint(address) => Some(Stop::UndefinedOpcode(address)),
+        })
+    }
+
+    /// Runs until a breakpoint, watchpoint or halt, or until `predicate` returns true after an
+    /// instruction. The instruction at the current PC is always executed, even if it has a breakpoint.
+    pub fn run_until<M, F>(&mut self, machine: &mut Machine<M>, mut predicate: F) -> Result<Stop, ExecutionError>
+    where
+        M: Memory,
+        F: FnMut(&Machine<M>) -> bool,
+    {
+        loop {
+            if let Some(stop) = self.step(machine)? {
+                return Ok(stop);
+            }
+            if predicate(machine) {
+                return Ok(Stop::Condition);
+            }
+            if self.breakpoints.contains(&machine.registers.pc) {
+                return Ok(Stop::Breakpoint(machine.registers.pc));
+            }
+        }
+    }
+
+    /// Runs until a breakpoint, watchpoint or halt.
+    pub fn run<M: Memory>(&mut self, machine: &mut Machine<M>) -> Result<Stop, ExecutionError> {
+        self.run_until(machine, |_| false)
+    }
+
+    /// Runs for at least `cycles` T-states unless another condition stops the run earlier.
+    pub fn run_for_cycles<M: Memory>(&mut self, machine: &mut Machine<M>, cycles: u64) -> Result<Stop, ExecutionError> {
+        let until = machine.cycles + cycles;
+        match self.run_until(machine, |m| m.cycles >= until)? {
+            Stop::Condition => Ok(Stop::CyclesElapsed),
+            stop => Ok(stop),
+        }
+    }
+
+    /// Steps back until PC reaches a breakpoint or the history runs out. The step that
+    /// brought the machine to the current PC is always undone, even if it has a breakpoint.
+    pub fn reverse_continue<M: Memory>(&mut self, machine: &mut Machine<M>, history: &mut History) -> Stop {
+        while history.step_back(machine) {
+            if self.breakpoints.contains(&machine.registers.pc) {
+                return Stop::Breakpoint(machine.registers.pc);
+            }
+        }
+        Stop::HistoryStart
+    }
+
+    fn check_watchpoints(&self, log: &[BusAccess]) -> Option<Stop> {
+        log.iter().find_map(|access| {
+            self.watchpoints.iter()
+                .find(|(_, watchpoint)| watchpoint.matches(access))
+                .map(|(id, _)| Stop::Watchpoint { id: *id, access: *access })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::io::MirroredIo;
+    use crate::ram::RAM;
+    use rs580_macros::asm8080;
+
+    fn machine() -> Machine<RAM> {
+        let program = asm8080! {
+                LXI SP,100h;
+                MVI B,3;
+            again:
+                MOV A,B;
+                STA 80h;
+                OUT 10h;
+                DCR B;
+                JNZ again;
+            done:
+                HLT
+        };
+        let mut m = Machine::new(RAM::default()).with_io(Box::new(MirroredIo(RAM::default())));
+        m.memory.set_range(program.origin, &program.bytes);
+        m
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut m = machine();
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(5);
+        assert_eq!(debugger.run(&mut m), Ok(Stop::Breakpoint(5)));
+        assert_eq!(m.registers.b, 3);
+        assert_eq!(debugger.run(&mut m), Ok(Stop::Breakpoint(5)));
+        assert_eq!(m.registers.b, 2);
+        assert!(debugger.remove_breakpoint(5));
+        assert_eq!(debugger.run(&mut m), Ok(Stop::Halted));
+        assert_eq!(m.registers.pc, 0x10);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut m = machine();
+        let mut debugger = Debugger::new();
+        let store = debugger.add_watchpoint(Watchpoint::memory(0x80..=0x8F, WatchKind::Write).with_value(2));
+        let port = debugger.add_watchpoint(Watchpoint::port(0x10..=0x10, WatchKind::Write).with_value(1));
+        debugger.add_watchpoint(Watchpoint::memory(0x80..=0x80, WatchKind::Read));
+
+        assert_eq!(
+            debugger.run(&mut m),
+            Ok(Stop::Watchpoint { id: store, access: BusAccess::Write { address: 0x80, value: 2, old: 3 } })
+        );
+        assert_eq!(m.registers.pc, 0x09);
+        assert_eq!(
+            debugger.run(&mut m),
+            Ok(Stop::Watchpoint { id: port, access: BusAccess::Out { port: 0x10, value: 1 } })
+        );
+        assert_eq!(debugger.run(&mut m), Ok(Stop::Halted));
+    }
+
+    #[test]
+    fn test_watchpoints_restore_bus_log() {
+        let mut m = machine();
+        let mut debugger = Debugger::new();
+        let id = debugger.add_watchpoint(Watchpoint::memory(0x80..=0x80, WatchKind::Write));
+        debugger.step(&mut m).unwrap();
+        assert!(m.bus_log.is_some());
+        debugger.remove_watchpoint(id);
+        debugger.step(&mut m).unwrap();
+        assert!(m.bus_log.is_none());
+
+        m.bus_log = Some(Vec::new());
+        let id = debugger.add_watchpoint(Watchpoint::memory(0x80..=0x80, WatchKind::Write));
+        debugger.step(&mut m).unwrap();
+        debugger.remove_watchpoint(id);
+        debugger.step(&mut m).unwrap();
+        assert!(m.bus_log.is_some());
+    }
+
+    #[test]
+    fn test_unwatch_keeps_bus_log_for_history() {
+        let mut m = machine();
+        let mut debugger = Debugger::new();
+        let id = debugger.add_watchpoint(Watchpoint::memory(0x81..=0x81, WatchKind::Write));
+        debugger.step(&mut m).unwrap();
+        let history = Rc::new(RefCell::new(History::new(1000)));
+        m.add_hook(Box::new(history.clone()));
+        debugger.remove_watchpoint(id);
+        for _ in 0..3 {
+            debugger.step(&mut m).unwrap();
+        }
+        assert!(m.bus_log.is_some());
+        assert_eq!(m.memory.get_u8(0x80), 3);
+
+        assert!(history.borrow_mut().step_back(&mut m));
+        assert_eq!((m.registers.pc, m.memory.get_u8(0x80)), (6, 0));
+    }
+
+    #[test]
+    fn test_run_for_cycles() {
+        let mut m = machine();
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.run_for_cycles(&mut m, 20), Ok(Stop::CyclesElapsed));
+        assert_eq!(m.cycles, 22); // LXI, MVI, MOV
+        assert_eq!(debugger.run_until(&mut m, |m| m.registers.b == 1), Ok(Stop::Condition));
+        assert_eq!(m.registers.pc, 0x0C);
+    }
+
+    #[test]
+    fn test_reverse_continue() {
+        let mut m = machine();
+        let history = Rc::new(RefCell::new(History::new(1000)));
+        m.add_hook(Box::new(history.clone()));
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.run(&mut m), Ok(Stop::Halted));
+        assert_eq!(m.memory.get_u8(0x80), 1);
+
+        debugger.add_breakpoint(5);
+        let mut history = history.borrow_mut();
+        assert_eq!(debugger.reverse_continue(&mut m, &mut history), Stop::Breakpoint(5));
+        assert_eq!((m.registers.b, m.memory.get_u8(0x80)), (1, 2));
+        assert_eq!(debugger.reverse_continue(&mut m, &mut history), Stop::Breakpoint(5));
+        assert_eq!((m.registers.b, m.memory.get_u8(0x80)), (2, 3));
+        assert_eq!(debugger.reverse_continue(&mut m, &mut history), Stop::Breakpoint(5));
+        assert_eq!((m.registers.b, m.memory.get_u8(0x80)), (3, 0));
+        assert_eq!(debugger.reverse_continue(&mut m, &mut history), Stop::HistoryStart);
+        assert_eq!((m.registers.pc, m.cycles), (0, 0));
+    }
+}
@@ -0,0 +1,339 @@
+//! Intel 8080 and 8085 disassembler.
+//!
+//! Decoding uses the same opcode tables as `cpu::Machine`, so the disassembly always
+//! describes what the processor executes.
+
+use std::fmt;
+use crate::cpu::{CpuVariant, Op, OPCODES, OPCODES_8085, OPCODES_WITH_ALIASES};
+use crate::memory::Memory;
+
+const REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "M", "A"];
+const PAIRS: [&str; 4] = ["B", "D", "H", "SP"];
+const PAIRS_PSW: [&str; 4] = ["B", "D", "H", "PSW"];
+const ALU: [&str; 8] = ["ADD", "ADC", "SUB", "SBB", "ANA", "XRA", "ORA", "CMP"];
+const ALU_IMMEDIATE: [&str; 8] = ["ADI", "ACI", "SUI", "SBI", "ANI", "XRI", "ORI", "CPI"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+    /// B, C, D, E, H, L, M or A by its 3-bit code.
+    Register(u8),
+    /// B, D, H or SP by its 2-bit code.
+    Pair(u8),
+    /// B, D, H or PSW by its 2-bit code.
+    PairPsw(u8),
+    Data8(u8),
+    Data16(u16),
+    Address(u16),
+    Port(u8),
+    /// RST number.
+    Vector(u8),
+}
+
+/// Effect of the instruction on the control flow.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flow {
+    Sequential,
+    Jump,
+    ConditionalJump,
+    Call,
+    ConditionalCall,
+    Return,
+    ConditionalReturn,
+    /// RST, a one-byte call.
+    Restart,
+    /// PCHL, jump to an address known only at run time.
+    IndirectJump,
+    Halt,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    pub address: u16,
+    /// Opcode followed by the immediate bytes.
+    pub bytes: Vec<u8>,
+    pub mnemonic: &'static str,
+    pub operands: Vec<Operand>,
+    /// Undocumented opcode executed as an alias of `mnemonic`.
+    pub undocumented: bool,
+    pub flow: Flow,
+    /// Duration in T-states; for conditional instructions when the condition holds. That is
+    /// conditional CALL and RET and, on the 8085, also conditional jumps, JK, JNK and RSTV.
+    pub cycles: u32,
+    /// Duration in T-states when the condition does not hold.
+    pub cycles_not_taken: u32,
+    /// Trailing operands implied by the mnemonic and left out of its text, such as the 0040h of RSTV.
+    pub implicit_operands: usize,
+}
+
+impl Instruction {
+    pub fn len(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Address of the following instruction.
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.len())
+    }
+
+    /// Destination of a jump, call or restart.
+    pub fn target(&self) -> Option<u16> {
+        match self.flow {
+            Flow::Jump | Flow::ConditionalJump | Flow::Call | Flow::ConditionalCall => match self.operands.last() {
+                Some(Operand::Address(addr)) => Some(*addr),
+                _ => None,
+            },
+            Flow::Restart => match self.operands.last() {
+                Some(Operand::Vector(n)) => Some((*n as u16) << 3),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Line of a listing: address, bytes and the instruction.
+    pub fn listing(&self) -> String {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        format!("{:04X}  {:<8}  {}", self.address, bytes.join(" "), self)
+    }
+}
+
+/// Formats a number the Intel way: hexadecimal with H suffix and a leading zero before a letter.
+pub fn hex(value: u16, digits: usize) -> String {
+    let text = format!("{:01$X}H", value, digits);
+    if text.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        format!("0{}", text)
+    } else {
+        text
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Operand::Register(r) => write!(f, "{}", REGISTERS[r as usize]),
+            Operand::Pair(rp) => write!(f, "{}", PAIRS[rp as usize]),
+            Operand::PairPsw(rp) => write!(f, "{}", PAIRS_PSW[rp as usize]),
+            Operand::Data8(value) | Operand::Port(value) => write!(f, "{}", hex(value as u16, 2)),
+            Operand::Data16(value) | Operand::Address(value) => write!(f, "{}", hex(value, 4)),
+            Operand::Vector(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.undocumented {
+            write!(f, "*")?;
+        }
+        write!(f, "{}", self.mnemonic)?;
+        let shown = &self.operands[..self.operands.len() - self.implicit_operands];
+        for (i, operand) in shown.iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { " " } else { "," }, operand)?;
+        }
+        Ok(())
+    }
+}
+
+/// Decodes the 8080 instruction at `address`.
+///
+/// Undocumented opcodes are always decoded as the instructions they alias, whatever
+/// `Machine::undefined_opcodes` is set to; `Instruction::undocumented` marks them.
+pub fn disassemble<M: Memory + ?Sized>(memory: &M, address: u16) -> Instruction {
+    disassemble_for(memory, address, CpuVariant::Intel8080)
+}
+
+/// Decodes the instruction at `address` as executed by `variant`. Undocumented opcodes are
+/// treated as in `disassemble`.
+pub fn disassemble_for<M: Memory + ?Sized>(memory: &M, address: u16, variant: CpuVariant) -> Instruction {
+    let opcode = memory.get_u8(address);
+    let (op, undocumented) = match variant {
+        CpuVariant::Intel8085 => {
+            let op = OPCODES_8085[opcode as usize];
+            (op, op.undocumented_8085())
+        },
+        CpuVariant::Intel8080 | CpuVariant::Kr580Vm80a => {
+            let op = OPCODES_WITH_ALIASES[opcode as usize];
+            (op, OPCODES[opcode as usize] != op)
+        },
+    };
+    let bytes: Vec<u8> = (0..op.len()).map(|i| memory.get_u8(address.wrapping_add(i))).collect();
+    let data8 = || bytes[1];
+    let data16 = || (bytes[2] as u16) << 8 | bytes[1] as u16;
+
+    use Operand::*;
+    let (mnemonic, operands, flow) = match op {
+        Op::Nop => ("NOP", vec![], Flow::Sequential),
+        Op::Lxi(rp) => ("LXI", vec![Pair(rp), Data16(data16())], Flow::Sequential),
+        Op::Stax(rp) => ("STAX", vec![Pair(rp)], Flow::Sequential),
+        Op::Shld => ("SHLD", vec![Address(data16())], Flow::Sequential),
+        Op::Sta => ("STA", vec![Address(data16())], Flow::Sequential),
+        Op::Inx(rp) => ("INX", vec![Pair(rp)], Flow::Sequential),
+        Op::Inr(r) => ("INR", vec![Register(r)], Flow::Sequential),
+        Op::Dcr(r) => ("DCR", vec![Register(r)], Flow::Sequential),
+        Op::Mvi(r) => ("MVI", vec![Register(r), Data8(data8())], Flow::Sequential),
+        Op::Rlc => ("RLC", vec![], Flow::Sequential),
+        Op::Rrc => ("RRC", vec![], Flow::Sequential),
+        Op::Ral => ("RAL", vec![], Flow::Sequential),
+        Op::Rar => ("RAR", vec![], Flow::Sequential),
+        Op::Dad(rp) => ("DAD", vec![Pair(rp)], Flow::Sequential),
+        Op::Ldax(rp) => ("LDAX", vec![Pair(rp)], Flow::Sequential),
+        Op::Lhld => ("LHLD", vec![Address(data16())], Flow::Sequential),
+        Op::Lda => ("LDA", vec![Address(data16())], Flow::Sequential),
+        Op::Dcx(rp) => ("DCX", vec![Pair(rp)], Flow::Sequential),
+        Op::Daa => ("DAA", vec![], Flow::Sequential),
+        Op::Cma => ("CMA", vec![], Flow::Sequential),
+        Op::Stc => ("STC", vec![], Flow::Sequential),
+        Op::Cmc => ("CMC", vec![], Flow::Sequential),
+        Op::Hlt => ("HLT", vec![], Flow::Halt),
+        Op::Mov(dst, src) => ("MOV", vec![Register(dst), Register(src)], Flow::Sequential),
+        Op::Alu(operation, r) => (ALU[operation as usize], vec![Register(r)], Flow::Sequential),
+        Op::Rcond(cond) => (["RNZ", "RZ", "RNC", "RC", "RPO", "RPE", "RP", "RM"][cond as usize], vec![], Flow::ConditionalReturn),
+        Op::Jcond(cond) => (["JNZ", "JZ", "JNC", "JC", "JPO", "JPE", "JP", "JM"][cond as usize], vec![Address(data16())], Flow::ConditionalJump),
+        Op::Ccond(cond) => (["CNZ", "CZ", "CNC", "CC", "CPO", "CPE", "CP", "CM"][cond as usize], vec![Address(data16())], Flow::ConditionalCall),
+        Op::Pop(rp) => ("POP", vec![PairPsw(rp)], Flow::Sequential),
+        Op::Push(rp) => ("PUSH", vec![PairPsw(rp)], Flow::Sequential),
+        Op::Jmp => ("JMP", vec![Address(data16())], Flow::Jump),
+        Op::Ret => ("RET", vec![], Flow::Return),
+        Op::Call => ("CALL", vec![Address(data16())], Flow::Call),
+        Op::AluImm(operation) => (ALU_IMMEDIATE[operation as usize], vec![Data8(data8())], Flow::Sequential),
+        Op::Rst(n) => ("RST", vec![Vector(n)], Flow::Restart),
+        Op::Out => ("OUT", vec![Port(data8())], Flow::Sequential),
+        Op::In => ("IN", vec![Port(data8())], Flow::Sequential),
+        Op::Xthl => ("XTHL", vec![], Flow::Sequential),
+        Op::Pchl => ("PCHL", vec![], Flow::IndirectJump),
+        Op::Sphl => ("SPHL", vec![], Flow::Sequential),
+        Op::Xchg => ("XCHG", vec![], Flow::Sequential),
+        Op::Di => ("DI", vec![], Flow::Sequential),
+        Op::Ei => ("EI", vec![], Flow::Sequential),
+        Op::Rim => ("RIM", vec![], Flow::Sequential),
+        Op::Sim => ("SIM", vec![], Flow::Sequential),
+        Op::Dsub => ("DSUB", vec![], Flow::Sequential),
+        Op::Arhl => ("ARHL", vec![], Flow::Sequential),
+        Op::Rdel => ("RDEL", vec![], Flow::Sequential),
+        Op::Ldhi => ("LDHI", vec![Data8(data8())], Flow::Sequential),
+        Op::Ldsi => ("LDSI", vec![Data8(data8())], Flow::Sequential),
+        // Calls 0040h when V is set.
+        Op::Rstv => ("RSTV", vec![Address(0x0040)], Flow::ConditionalCall),
+        Op::Shlx => ("SHLX", vec![], Flow::Sequential),
+        Op::Lhlx => ("LHLX", vec![], Flow::Sequential),
+        Op::Jk(k) => (if k { "JK" } else { "JNK" }, vec![Address(data16())], Flow::ConditionalJump),
+        Op::Undefined => unreachable!("every undefined opcode has an alias"),
+    };
+    let (cycles, cycles_not_taken) = match variant {
+        CpuVariant::Intel8085 => op.cycles_8085(),
+        CpuVariant::Intel8080 | CpuVariant::Kr580Vm80a => op.cycles(),
+    };
+
+    Instruction {
+        address,
+        bytes,
+        mnemonic,
+        operands,
+        undocumented,
+        flow,
+        cycles,
+        cycles_not_taken,
+        implicit_operands: (op == Op::Rstv) as usize,
+    }
+}
+
+/// Decodes instructions for `variant` starting at `from` while they start before `to`.
+pub fn disassemble_range<M: Memory + ?Sized>(memory: &M, from: u16, to: u16, variant: CpuVariant) -> Vec<Instruction> {
+    let mut result = Vec::new();
+    let mut address = from as u32;
+    while address < to as u32 {
+        let instruction = disassemble_for(memory, address as u16, variant);
+        address += instruction.len() as u32;
+        result.push(instruction);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ram::RAM;
+
+    fn text(bytes: &[u8]) -> String {
+        let mut memory = RAM::default();
+        memory.set_range(0x100, bytes);
+        disassemble(&memory, 0x100).to_string()
+    }
+
+    #[test]
+    fn test_mnemonics() {
+        assert_eq!(text(&[0x00]), "NOP");
+        assert_eq!(text(&[0x3E, 0x9B]), "MVI A,9BH");
+        assert_eq!(text(&[0x21, 0x34, 0x12]), "LXI H,1234H");
+        assert_eq!(text(&[0x31, 0x00, 0xF8]), "LXI SP,0F800H");
+        assert_eq!(text(&[0x7E]), "MOV A,M");
+        assert_eq!(text(&[0xF5]), "PUSH PSW");
+        assert_eq!(text(&[0xBB]), "CMP E");
+        assert_eq!(text(&[0xFE, 0x0D]), "CPI 0DH");
+        assert_eq!(text(&[0xC2, 0x00, 0x10]), "JNZ 1000H");
+        assert_eq!(text(&[0xEC, 0x00, 0x10]), "CPE 1000H");
+        assert_eq!(text(&[0xF8]), "RM");
+        assert_eq!(text(&[0xFF]), "RST 7");
+        assert_eq!(text(&[0xD3, 0xF9]), "OUT 0F9H");
+        assert_eq!(text(&[0xDD, 0x00, 0x10]), "*CALL 1000H");
+        assert_eq!(text(&[0x08]), "*NOP");
+    }
+
+    #[test]
+    fn test_instruction() {
+        let mut memory = RAM::default();
+        memory.set_range(0xF800, &[0xCD, 0x36, 0xF8, 0xC0, 0x3E, 0x01]);
+
+        let call = disassemble(&memory, 0xF800);
+        assert_eq!(call.len(), 3);
+        assert_eq!(call.flow, Flow::Call);
+        assert_eq!(call.target(), Some(0xF836));
+        assert_eq!(call.cycles, 17);
+        assert_eq!(call.next_address(), 0xF803);
+        assert_eq!(call.listing(), "F800  CD 36 F8  CALL 0F836H");
+
+        let ret = disassemble(&memory, 0xF803);
+        assert_eq!((ret.cycles, ret.cycles_not_taken), (11, 5));
+
+        let listing = disassemble_range(&memory, 0xF800, 0xF806, CpuVariant::Intel8080);
+        assert_eq!(listing.len(), 3);
+        assert_eq!(listing[2].to_string(), "MVI A,01H");
+    }
+
+    #[test]
+    fn test_8085() {
+        let mut memory = RAM::default();
+        memory.set_range(0x100, &[0x20, 0x28, 0x10, 0xFD, 0x00, 0x10, 0xCB, 0xE3]);
+        let listing: Vec<String> = disassemble_range(&memory, 0x100, 0x108, CpuVariant::Intel8085)
+            .iter()
+            .map(|instruction| instruction.to_string())
+            .collect();
+        assert_eq!(listing, ["RIM", "*LDHI 10H", "*JK 1000H", "*RSTV", "XTHL"]);
+
+        let xthl = disassemble_for(&memory, 0x107, CpuVariant::Intel8085);
+        assert_eq!(xthl.cycles, 16);
+        let jk = disassemble_for(&memory, 0x103, CpuVariant::Intel8085);
+        assert_eq!((jk.flow, jk.cycles, jk.cycles_not_taken), (Flow::ConditionalJump, 10, 7));
+        assert_eq!(jk.target(), Some(0x1000));
+        let rstv = disassemble_for(&memory, 0x106, CpuVariant::Intel8085);
+        assert_eq!((rstv.flow, rstv.target()), (Flow::ConditionalCall, Some(0x0040)));
+        assert_eq!(rstv.operands, [Operand::Address(0x0040)]);
+        assert_eq!((rstv.cycles, rstv.cycles_not_taken), (12, 6));
+        assert_eq!(disassemble(&memory, 0x100).to_string(), "*NOP");
+    }
+
+    #[test]
+    fn test_all_opcodes() {
+        let mut memory = RAM::default();
+        for opcode in 0..=255 {
+            memory.set_u8(0, opcode);
+            let instruction = disassemble(&memory, 0);
+            assert!(!instruction.mnemonic.is_empty());
+            assert_eq!(instruction.bytes[0], opcode);
+        }
+    }
+}
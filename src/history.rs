@@ -0,0 +1,291 @@
+//! Bounded execution history for stepping backwards.
+//!
+//! `History` is a `Hook` recording the processor state before every step together with the
+//! bytes the step overwrote. Undoing a step restores both, including the 8085 interrupt latches,
+//! masks and SOD. State of I/O devices is not recorded, so ports and memory-mapped devices only
+//! see the restored memory writes.
+
+use std::collections::VecDeque;
+use crate::cpu::{BusAccess, ExecutionError, Hook, InterruptAck, Machine, Registers, State8085, StepContext, StepOutcome};
+use crate::memory::Memory;
+
+/// An 8080 instruction or interrupt acknowledge writes at most two bytes. Any further
+/// writes go to `Entry::overflow`, which does not allocate in the usual case.
+const MAX_WRITES: usize = 2;
+
+#[derive(Clone, Debug)]
+struct Entry {
+    registers: Registers,
+    halted: bool,
+    interruption_enabled: bool,
+    interrupt_request: Option<InterruptAck>,
+    interrupt_delay: bool,
+    state_8085: State8085,
+    cycles: u64,
+    /// Address and previous value of every byte written by the step.
+    writes: [(u16, u8); MAX_WRITES],
+    write_count: u8,
+    /// Writes beyond `MAX_WRITES`.
+    overflow: Vec<(u16, u8)>,
+}
+
+pub struct History {
+    entries: VecDeque<Entry>,
+    capacity: usize,
+    pending: Option<Entry>,
+}
+
+impl History {
+    /// Keeps the last `capacity` steps.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity.min(0x10000)),
+            capacity,
+            pending: None,
+        }
+    }
+
+    /// Number of steps that can be undone.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Forgets all steps, e.g. after the machine state was replaced by a snapshot.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Value of `Machine::cycles` before the oldest recorded step.
+    pub fn oldest_cycles(&self) -> Option<u64> {
+        self.entries.front().map(|entry| entry.cycles)
+    }
+
+    /// Undoes the last recorded step. Returns false if there is nothing to undo.
+    ///
+    /// The machine must be the one the history was recorded on.
+    pub fn step_back<M: Memory>(&mut self, machine: &mut Machine<M>) -> bool {
+        let entry = match self.entries.pop_back() {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let writes = entry.writes[..entry.write_count as usize].iter().chain(&entry.overflow);
+        for &(address, old) in writes.rev() {
+            machine.memory.set_u8(address, old);
+        }
+        machine.restore_state(
+            entry.registers, entry.halted, entry.interruption_enabled,
+            entry.interrupt_request, entry.interrupt_delay, entry.cycles,
+        );
+        machine.restore_state_8085(entry.state_8085);
+        true
+    }
+
+    /// Undoes steps until `Machine::cycles` went back by at least `cycles` or the history is
+    /// exhausted. Returns the number of undone steps.
+    pub fn rewind_cycles<M: Memory>(&mut self, machine: &mut Machine<M>, cycles: u64) -> usize {
+        let until = machine.cycles.saturating_sub(cycles);
+        let mut steps = 0;
+        while machine.cycles > until && self.step_back(machine) {
+            steps += 1;
+        }
+        steps
+    }
+}
+
+impl Hook for History {
+    fn before_step(&mut self, context: &StepContext) {
+        self.pending = Some(Entry {
+            registers: context.registers.clone(),
+            halted: context.halted,
+            interruption_enabled: context.interruption_enabled,
+            interrupt_request: context.interrupt_request,
+            interrupt_delay: context.interrupt_delay,
+            state_8085: context.state_8085,
+            cycles: context.cycles,
+            writes: [(0, 0); MAX_WRITES],
+            write_count: 0,
+            overflow: Vec::new(),
+        });
+    }
+
+    fn after_step(&mut self, context: &StepContext, result: &Result<StepOutcome, ExecutionError>) {
+        let mut entry = match self.pending.take() {
+            Some(entry) => entry,
+            None => return,
+        };
+        // Failed steps and undefined opcode breaks leave the machine untouched.
+        if !matches!(result, Ok(StepOutcome::Executed(_)) | Ok(StepOutcome::Interrupted(_)) | Ok(StepOutcome::Halted(_)))
+            || self.capacity == 0
+        {
+            return;
+        }
+        for access in context.bus {
+            if let BusAccess::Write { address, old, .. } = *access {
+                if (entry.write_count as usize) < MAX_WRITES {
+                    entry.writes[entry.write_count as usize] = (address, old);
+                    entry.write_count += 1;
+                } else {
+                    entry.overflow.push((address, old));
+                }
+            }
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    fn wants_bus_log(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::ram::RAM;
+    use rs580_macros::asm8080;
+
+    #[test]
+    fn test_step_back() {
+        let program = asm8080! {
+                LXI SP,100h;
+                MVI A,1;
+            again:
+                STA 80h;
+                PUSH PSW;
+                INR A;
+                CALL again
+        };
+        let mut m = Machine::new(RAM::default());
+        m.memory.set_range(program.origin, &program.bytes);
+        let history = Rc::new(RefCell::new(History::new(8)));
+        m.add_hook(Box::new(history.clone()));
+
+        let mut states = Vec::new();
+        for _ in 0..12 {
+            states.push((m.registers.clone(), m.cycles, m.memory.get_u8(0x80), m.memory.get_u16(0xFE)));
+            m.step().unwrap();
+        }
+        assert_eq!(history.borrow().len(), 8);
+
+        let mut history = history.borrow_mut();
+        for _ in 0..8 {
+            assert!(history.step_back(&mut m));
+            let state = states.pop().unwrap();
+            assert_eq!((m.registers.clone(), m.cycles, m.memory.get_u8(0x80), m.memory.get_u16(0xFE)), state);
+        }
+        assert!(history.is_empty());
+        assert!(!history.step_back(&mut m));
+    }
+
+    #[test]
+    fn test_step_back_8085() {
+        let program = asm8080! {
+                LXI SP,100h;
+                MVI A,0C8h; // SOD = 1, unmask all
+                SIM;
+                ORG 3Ch;
+                MVI A,10h;  // reset RST 7.5
+                SIM
+        };
+        let mut m = Machine::new(RAM::default());
+        m.variant = crate::cpu::CpuVariant::Intel8085;
+        m.memory.set_range(program.origin, &program.bytes);
+        let history = Rc::new(RefCell::new(History::new(16)));
+        m.add_hook(Box::new(history.clone()));
+
+        let mut states = Vec::new();
+        for step in 0..8 {
+            if step == 2 {
+                m.rst75();
+            }
+            if step == 6 {
+                m.rst75();
+                m.trap();
+            }
+            states.push(m.save_snapshot());
+            m.step().unwrap();
+        }
+        assert!(m.sod);
+        assert_eq!(m.registers.pc, 0x25);
+
+        let mut history = history.borrow_mut();
+        while let Some(state) = states.pop() {
+            assert!(history.step_back(&mut m));
+            assert!(m.save_snapshot() == state, "{} steps left", states.len());
+        }
+    }
+
+    #[test]
+    fn test_many_writes_in_one_step() {
+        /// Reports writes of a memory-mapped device on top of the CPU ones.
+        struct DeviceWrites(History);
+
+        impl Hook for DeviceWrites {
+            fn before_step(&mut self, context: &StepContext) {
+                self.0.before_step(context);
+            }
+
+            fn after_step(&mut self, context: &StepContext, result: &Result<StepOutcome, ExecutionError>) {
+                let mut bus = context.bus.to_vec();
+                bus.push(BusAccess::Write { address: 0x90, value: 1, old: 0x11 });
+                bus.push(BusAccess::Write { address: 0x91, value: 2, old: 0x22 });
+                self.0.after_step(&StepContext { bus: &bus, ..*context }, result);
+            }
+
+            fn wants_bus_log(&self) -> bool {
+                true
+            }
+        }
+
+        let program = asm8080! {
+                LXI SP,100h;
+                MVI A,55h;
+                PUSH PSW
+        };
+        let mut m = Machine::new(RAM::default());
+        m.memory.set_range(program.origin, &program.bytes);
+        let history = Rc::new(RefCell::new(DeviceWrites(History::new(8))));
+        m.add_hook(Box::new(history.clone()));
+        m.step().unwrap();
+        m.step().unwrap();
+        m.memory.set_range(0xFE, &[0x77, 0x88]);
+        m.step().unwrap();
+        m.memory.set_range(0x90, &[1, 2]);
+
+        assert!(history.borrow_mut().0.step_back(&mut m));
+        assert_eq!(m.memory.get_range(0x90, 0x92), [0x11, 0x22]);
+        assert_eq!(m.memory.get_range(0xFE, 0x100), [0x77, 0x88]);
+        assert_eq!(m.registers.sp, 0x100);
+    }
+
+    #[test]
+    fn test_rewind_cycles() {
+        let program = asm8080! {
+            again:
+                INR B;
+                JMP again
+        };
+        let mut m = Machine::new(RAM::default());
+        m.memory.set_range(program.origin, &program.bytes);
+        let history = Rc::new(RefCell::new(History::new(100)));
+        m.add_hook(Box::new(history.clone()));
+        for _ in 0..20 {
+            m.step().unwrap();
+        }
+        assert_eq!(m.registers.b, 10);
+        // INR + JMP = 15 T-states
+        assert_eq!(history.borrow_mut().rewind_cycles(&mut m, 31), 5);
+        assert_eq!(m.registers.b, 8);
+        assert_eq!(m.cycles, 110);
+        assert_eq!(history.borrow_mut().rewind_cycles(&mut m, 1000), 15);
+        assert_eq!(m.cycles, 0);
+    }
+}
@@ -0,0 +1,175 @@
+use crate::memory::Memory;
+use crate::snapshot::{SnapshotError, StateReader, StateWriter};
+
+pub trait Io {
+    fn inp(&mut self, port: u8) -> u8;
+
+    fn out(&mut self, port: u8, value: u8);
+
+    /// Writes the device state for a snapshot. Stateless devices write nothing.
+    fn save_state(&self, _writer: &mut StateWriter) {
+    }
+
+    /// Restores the device state written by `save_state`.
+    fn load_state(&mut self, _reader: &mut StateReader) -> Result<(), SnapshotError> {
+        Ok(())
+    }
+}
+
+/// Nothing is connected to the I/O bus. Reads return 0xFF and writes are ignored.
+#[derive(Default)]
+pub struct NullIo;
+
+impl Io for NullIo {
+    #[inline]
+    fn inp(&mut self, _port: u8) -> u8 {
+        0xFF
+    }
+
+    #[inline]
+    fn out(&mut self, _port: u8, _value: u8) {
+        // DO NOTHING
+    }
+}
+
+/// Port ranges mapped to devices. Where ranges overlap, the device added first handles
+/// both IN and OUT; the later ones never see those ports.
+pub struct SegmentedIo {
+    segments: Vec<(u8, u8, Box<dyn Io>)>,
+}
+
+impl SegmentedIo {
+    pub fn new() -> Self {
+        Self {
+            segments: Vec::new()
+        }
+    }
+
+    /// Maps ports `from..=to` to the device. The device sees port numbers relative to `from`.
+    pub fn add(mut self, from: u8, to: u8, io: Box<dyn Io>) -> Self {
+        self.segments.push((from, to, io));
+        self
+    }
+
+    /// The first device mapped to the port together with the start of its range.
+    fn segment(&mut self, port: u8) -> Option<(u8, &mut Box<dyn Io>)> {
+        self.segments.iter_mut()
+            .find(|(from, to, _)| *from <= port && port <= *to)
+            .map(|(from, _, io)| (*from, io))
+    }
+}
+
+impl std::default::Default for SegmentedIo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Io for SegmentedIo {
+    fn inp(&mut self, port: u8) -> u8 {
+        match self.segment(port) {
+            Some((from, io)) => io.inp(port - from),
+            None => 0xFF,
+        }
+    }
+
+    fn out(&mut self, port: u8, value: u8) {
+        if let Some((from, io)) = self.segment(port) {
+            io.out(port - from, value);
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        for (_, _, io) in &self.segments {
+            io.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SnapshotError> {
+        for (_, _, io) in self.segments.iter_mut() {
+            io.load_state(reader)?;
+        }
+        Ok(())
+    }
+}
+
+/// During IN and OUT the 8080 puts the port number on both halves of the address bus.
+/// This adapter reproduces that, so the memory-like device sees address `port << 8 | port`.
+/// It is useful for machines which decode I/O devices by the high address byte
+/// or share one device between memory and port spaces.
+pub struct MirroredIo<M: Memory>(pub M);
+
+impl<M: Memory> Io for MirroredIo<M> {
+    #[inline]
+    fn inp(&mut self, port: u8) -> u8 {
+        self.0.get_u8((port as u16) << 8 | (port as u16))
+    }
+
+    #[inline]
+    fn out(&mut self, port: u8, value: u8) {
+        self.0.set_u8((port as u16) << 8 | (port as u16), value);
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.0.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SnapshotError> {
+        self.0.load_state(reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ram::RAM;
+
+    struct Latch(u8);
+
+    impl Io for Latch {
+        fn inp(&mut self, port: u8) -> u8 {
+            self.0.wrapping_add(port)
+        }
+
+        fn out(&mut self, _port: u8, value: u8) {
+            self.0 = value;
+        }
+    }
+
+    #[test]
+    fn test_segmented_io() {
+        let mut io = SegmentedIo::new()
+            .add(0x10, 0x13, Box::new(Latch(0)))
+            .add(0x20, 0x20, Box::new(Latch(0x50)));
+
+        io.out(0x12, 0x30);
+        assert_eq!(io.inp(0x10), 0x30);
+        assert_eq!(io.inp(0x13), 0x33);
+        assert_eq!(io.inp(0x20), 0x50);
+        assert_eq!(io.inp(0x14), 0xFF);
+        assert_eq!(io.inp(0x21), 0xFF);
+    }
+
+    #[test]
+    fn test_segmented_io_overlap() {
+        let mut io = SegmentedIo::new()
+            .add(0x10, 0x11, Box::new(Latch(0)))
+            .add(0x00, 0xFF, Box::new(Latch(0x50)));
+
+        io.out(0x11, 0x30);
+        assert_eq!(io.inp(0x10), 0x30);
+        assert_eq!(io.inp(0x12), 0x62);
+        io.out(0x12, 0x40);
+        assert_eq!(io.inp(0x11), 0x31);
+        assert_eq!(io.inp(0x00), 0x40);
+    }
+
+    #[test]
+    fn test_mirrored_io() {
+        let mut io = MirroredIo(RAM::default());
+        io.out(0x42, 0x99);
+        assert_eq!(io.0.get_u8(0x4242), 0x99);
+        io.0.set_u8(0x0505, 0x11);
+        assert_eq!(io.inp(0x05), 0x11);
+    }
+}
@@ -1,33 +1,45 @@
 pub mod memory;
+pub mod io;
 pub mod ram;
 pub mod rom;
 pub mod segmented_memory;
+pub mod banked_memory;
 pub mod cpu;
+pub mod snapshot;
+pub mod disasm;
+pub mod debug;
+pub mod trace;
+pub mod profile;
+pub mod coverage;
+pub mod history;
+pub mod asm;
+pub mod z80;
 
-pub use cpu::Machine;
+pub use cpu::{BoxedMachine, BusAccess, CpuVariant, ExecutionError, Hook, InterruptAck, Machine, StepContext, StepOutcome, UndefinedOpcodes};
 pub use memory::Memory;
+pub use io::Io;
 pub use ram::RAM;
 pub use rom::ROM;
 pub use segmented_memory::SegmentedMemory;
+pub use banked_memory::{BankRegister, BankedMemory};
+pub use z80::Z80;
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::ram::RAM;
+    use rs580_macros::asm8080;
 
     #[test]
     fn test_opcodes_implemented() {
         let mut m: Machine = Machine::new(Box::new(RAM::default()));
         for i in 0..=255 {
-            match i {
-                0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 | 0xCB | 0xD9 | 0xDD | 0xED | 0xFD => continue,
-                _ => {}
-            }
             for a in &[0xFFFC, 0xFFFD, 0xFFFE, 0xFFFF, 0x0000, 0x0001, 0x0002, 0x0003] {
                 println!("Try instruction {:02x} at {:04x}.", i, a);
                 m.memory.set_u8(*a, i);
                 m.registers.pc = *a;
-                m.step();
+                m.halted = false;
+                m.step().unwrap();
             }
         }
     }
@@ -35,12 +47,13 @@ mod tests {
     #[test]
     fn test_daa() {
         let mut m: Machine = Machine::new(Box::new(RAM::default()));
-        m.memory.set_u8(0, 0x27);
+        let program = asm8080! { MVI A,9Bh; DAA };
+        m.memory.set_range(program.origin, &program.bytes);
         m.reset();
-        m.registers.a = 0x9B;
         m.registers.flag_c = false;
         m.registers.flag_ac = false;
-        m.step();
+        m.step().unwrap();
+        m.step().unwrap();
         assert_eq!(m.registers.a, 1);
         assert!(m.registers.flag_c);
         assert!(m.registers.flag_ac);
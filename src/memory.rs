@@ -1,3 +1,5 @@
+use crate::snapshot::{SnapshotError, StateReader, StateWriter};
+
 pub trait Memory {
     fn get_u8(&self, addr: u16) -> u8;
 
@@ -52,4 +54,33 @@ pub trait Memory {
         self.set_u8(addr, *value);
         *value = m;
     }
+
+    /// Writes the device state for a snapshot. Stateless devices write nothing.
+    fn save_state(&self, _writer: &mut StateWriter) {
+    }
+
+    /// Restores the device state written by `save_state`.
+    fn load_state(&mut self, _reader: &mut StateReader) -> Result<(), SnapshotError> {
+        Ok(())
+    }
+}
+
+impl<M: Memory + ?Sized> Memory for Box<M> {
+    #[inline]
+    fn get_u8(&self, addr: u16) -> u8 {
+        (**self).get_u8(addr)
+    }
+
+    #[inline]
+    fn set_u8(&mut self, addr: u16, value: u8) {
+        (**self).set_u8(addr, value)
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        (**self).save_state(writer)
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SnapshotError> {
+        (**self).load_state(reader)
+    }
 }
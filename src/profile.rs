@@ -0,0 +1,333 @@
+//! Execution profiler: per-address counts and cycles, a call graph built from CALL/RST/RET
+//! and interrupts, a per-routine report and folded stacks for flame-graph tools.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use crate::cpu::{ExecutionError, Hook, StepContext, StepOutcome};
+use crate::disasm::{disassemble_for, Flow};
+
+/// Node of the call tree. Node 0 is the root: code executed outside of any observed call.
+struct Node {
+    routine: Option<u16>,
+    children: HashMap<u16, usize>,
+    self_cycles: u64,
+}
+
+struct Pending {
+    pc: u16,
+    sp: u16,
+    cycles: u64,
+    call: bool,
+    target: Option<u16>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoutineStats {
+    /// Entry address, `None` for code outside of any call.
+    pub address: Option<u16>,
+    pub calls: u64,
+    /// Cycles spent in the routine itself.
+    pub self_cycles: u64,
+    /// Cycles spent in the routine and everything it called.
+    pub total_cycles: u64,
+}
+
+pub struct Profiler {
+    counts: Vec<u64>,
+    cycles: Vec<u64>,
+    nodes: Vec<Node>,
+    /// Call tree nodes of active calls with SP pointing at their return address.
+    stack: Vec<(usize, u16)>,
+    calls: BTreeMap<u16, u64>,
+    edges: BTreeMap<(Option<u16>, u16), u64>,
+    names: HashMap<u16, String>,
+    pending: Option<Pending>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; 0x10000],
+            cycles: vec![0; 0x10000],
+            nodes: vec![Node { routine: None, children: HashMap::new(), self_cycles: 0 }],
+            stack: Vec::new(),
+            calls: BTreeMap::new(),
+            edges: BTreeMap::new(),
+            names: HashMap::new(),
+            pending: None,
+        }
+    }
+
+    /// Names routines in reports, e.g. with `asm::Program::symbols`.
+    pub fn with_symbols<'a, I: IntoIterator<Item = (&'a String, &'a u16)>>(mut self, symbols: I) -> Self {
+        for (name, address) in symbols {
+            self.names.entry(*address).or_insert_with(|| name.clone());
+        }
+        self
+    }
+
+    /// How many times the instruction at the address was executed.
+    pub fn count(&self, address: u16) -> u64 {
+        self.counts[address as usize]
+    }
+
+    /// T-states spent executing the instruction at the address.
+    pub fn cycles(&self, address: u16) -> u64 {
+        self.cycles[address as usize]
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.nodes.iter().map(|node| node.self_cycles).sum()
+    }
+
+    /// Addresses ordered by spent cycles, most expensive first.
+    pub fn hot_addresses(&self, limit: usize) -> Vec<(u16, u64)> {
+        let mut hot: Vec<(u16, u64)> = (0..=0xFFFF_u16)
+            .filter(|&address| self.cycles[address as usize] > 0)
+            .map(|address| (address, self.cycles[address as usize]))
+            .collect();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hot.truncate(limit);
+        hot
+    }
+
+    /// Call graph edges: (caller routine, callee routine) and the number of calls.
+    pub fn call_graph(&self) -> impl Iterator<Item = (Option<u16>, u16, u64)> + '_ {
+        self.edges.iter().map(|(&(caller, callee), &count)| (caller, callee, count))
+    }
+
+    /// Routines ordered by total cycles, most expensive first.
+    pub fn routines(&self) -> Vec<RoutineStats> {
+        let mut stats: BTreeMap<Option<u16>, RoutineStats> = BTreeMap::new();
+        let mut path = Vec::new();
+        self.collect(0, &mut path, &mut stats);
+        let mut stats: Vec<RoutineStats> = stats.into_values().collect();
+        stats.sort_by(|a, b| b.total_cycles.cmp(&a.total_cycles).then(a.address.cmp(&b.address)));
+        stats
+    }
+
+    /// Returns the inclusive cycles of the subtree and adds them to routines not already on `path`,
+    /// so recursion is not counted twice.
+    fn collect(&self, index: usize, path: &mut Vec<Option<u16>>, stats: &mut BTreeMap<Option<u16>, RoutineStats>) -> u64 {
+        let node = &self.nodes[index];
+        path.push(node.routine);
+        let mut total = node.self_cycles;
+        for &child in node.children.values() {
+            total += self.collect(child, path, stats);
+        }
+        path.pop();
+
+        let calls = node.routine.map_or(0, |address| self.calls.get(&address).cloned().unwrap_or(0));
+        let entry = stats.entry(node.routine).or_insert(RoutineStats {
+            address: node.routine,
+            calls,
+            self_cycles: 0,
+            total_cycles: 0,
+        });
+        entry.self_cycles += node.self_cycles;
+        if !path.contains(&node.routine) {
+            entry.total_cycles += total;
+        }
+        total
+    }
+
+    pub fn name(&self, routine: Option<u16>) -> String {
+        match routine {
+            None => "[root]".to_string(),
+            Some(address) => self.names.get(&address).cloned().unwrap_or_else(|| format!("{:04X}", address)),
+        }
+    }
+
+    /// Per-routine table.
+    pub fn report(&self) -> String {
+        let total = self.total_cycles().max(1);
+        let mut text = format!("{:<16} {:>8} {:>12} {:>7} {:>12} {:>7}\n", "routine", "calls", "self", "%", "total", "%");
+        for stats in self.routines() {
+            text += &format!(
+                "{:<16} {:>8} {:>12} {:>6.2}% {:>12} {:>6.2}%\n",
+                self.name(stats.address), stats.calls,
+                stats.self_cycles, stats.self_cycles as f64 * 100.0 / total as f64,
+                stats.total_cycles, stats.total_cycles as f64 * 100.0 / total as f64,
+            );
+        }
+        text
+    }
+
+    /// Writes `root;caller;callee cycles` lines as consumed by flamegraph.pl and compatible tools.
+    pub fn write_folded(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut path = Vec::new();
+        self.write_folded_node(0, &mut path, out)
+    }
+
+    fn write_folded_node(&self, index: usize, path: &mut Vec<String>, out: &mut dyn Write) -> io::Result<()> {
+        let node = &self.nodes[index];
+        path.push(self.name(node.routine));
+        if node.self_cycles > 0 {
+            writeln!(out, "{} {}", path.join(";"), node.self_cycles)?;
+        }
+        let mut children: Vec<(&u16, &usize)> = node.children.iter().collect();
+        children.sort();
+        for (_, &child) in children {
+            self.write_folded_node(child, path, out)?;
+        }
+        path.pop();
+        Ok(())
+    }
+
+    fn current(&self) -> usize {
+        self.stack.last().map_or(0, |&(node, _)| node)
+    }
+
+    fn enter(&mut self, routine: u16, sp: u16) {
+        let parent = self.current();
+        let caller = self.nodes[parent].routine;
+        let child = match self.nodes[parent].children.get(&routine) {
+            Some(&child) => child,
+            None => {
+                self.nodes.push(Node { routine: Some(routine), children: HashMap::new(), self_cycles: 0 });
+                let child = self.nodes.len() - 1;
+                self.nodes[parent].children.insert(routine, child);
+                child
+            },
+        };
+        self.stack.push((child, sp));
+        *self.calls.entry(routine).or_insert(0) += 1;
+        *self.edges.entry((caller, routine)).or_insert(0) += 1;
+    }
+}
+
+impl Hook for Profiler {
+    fn before_step(&mut self, context: &StepContext) {
+        let r = context.registers;
+        let (call, target) = if context.halted {
+            (false, None)
+        } else {
+            let instruction = disassemble_for(context.memory, r.pc, context.variant);
+            let call = matches!(instruction.flow, Flow::Call | Flow::ConditionalCall | Flow::Restart);
+            (call, instruction.target())
+        };
+        self.pending = Some(Pending { pc: r.pc, sp: r.sp, cycles: context.cycles, call, target });
+    }
+
+    fn after_step(&mut self, context: &StepContext, result: &Result<StepOutcome, ExecutionError>) {
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        let spent = context.cycles - pending.cycles;
+        let r = context.registers;
+        let pushed = r.sp == pending.sp.wrapping_sub(2);
+        if let Ok(StepOutcome::Interrupted(_)) = result {
+            // The instruction at PC did not run; the acknowledge belongs to the handler.
+            if pushed {
+                self.enter(r.pc, r.sp);
+            }
+            let current = self.current();
+            self.nodes[current].self_cycles += spent;
+            return;
+        }
+        self.counts[pending.pc as usize] += 1;
+        self.cycles[pending.pc as usize] += spent;
+        let current = self.current();
+        self.nodes[current].self_cycles += spent;
+
+        if pushed && pending.call && Some(r.pc) == pending.target {
+            self.enter(r.pc, r.sp);
+        }
+        while self.stack.last().is_some_and(|&(_, sp)| sp < r.sp) {
+            self.stack.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::asm::assemble;
+    use crate::cpu::{InterruptAck, Machine};
+    use crate::ram::RAM;
+
+    #[test]
+    fn test_profile() {
+        let program = assemble("
+        LXI SP,100h
+        CALL outer
+        CALL inner
+        HLT
+outer:  CALL inner
+        CALL inner
+        RET
+inner:  NOP
+        RET
+").unwrap();
+        let mut m = Machine::new(RAM::default());
+        program.load_into(&mut m.memory);
+        let profiler = Rc::new(RefCell::new(Profiler::new().with_symbols(&program.symbols)));
+        m.add_hook(Box::new(profiler.clone()));
+        while !m.halted {
+            m.step().unwrap();
+        }
+
+        let profiler = profiler.borrow();
+        let inner = program.symbol("inner").unwrap();
+        let outer = program.symbol("outer").unwrap();
+        assert_eq!(profiler.count(inner), 3);
+        assert_eq!(profiler.cycles(inner), 12);
+        assert_eq!(profiler.total_cycles(), m.cycles);
+
+        // inner: NOP + RET = 14 per call, outer: 2 CALLs + RET = 44 plus two inner calls
+        let routines = profiler.routines();
+        assert_eq!(routines[0], RoutineStats { address: None, calls: 0, self_cycles: 10 + 17 + 17 + 7, total_cycles: m.cycles });
+        assert_eq!(routines[1], RoutineStats { address: Some(outer), calls: 1, self_cycles: 44, total_cycles: 72 });
+        assert_eq!(routines[2], RoutineStats { address: Some(inner), calls: 3, self_cycles: 42, total_cycles: 42 });
+        let edges: Vec<_> = profiler.call_graph().collect();
+        assert_eq!(edges, vec![(None, outer, 1), (None, inner, 1), (Some(outer), inner, 2)]);
+
+        let mut folded = Vec::new();
+        profiler.write_folded(&mut folded).unwrap();
+        assert_eq!(
+            String::from_utf8(folded).unwrap(),
+            "[root] 51\n[root];outer 44\n[root];outer;inner 28\n[root];inner 14\n"
+        );
+        assert!(profiler.report().contains("outer"));
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let program = assemble("
+        LXI SP,100h
+        MVI A,1
+        HLT
+        ORG 38h
+isr:    RET
+").unwrap();
+        let mut m = Machine::new(RAM::default());
+        program.load_into(&mut m.memory);
+        let profiler = Rc::new(RefCell::new(Profiler::new()));
+        m.add_hook(Box::new(profiler.clone()));
+        m.step().unwrap();
+        m.interrupt(InterruptAck::Rst(7));
+        assert_eq!(m.step(), Ok(StepOutcome::Interrupted(11)));
+        m.clear_interrupt();
+        while !m.halted {
+            m.step().unwrap();
+        }
+
+        let profiler = profiler.borrow();
+        let isr = program.symbol("isr").unwrap();
+        assert_eq!(profiler.count(3), 1);
+        assert_eq!(profiler.cycles(3), 7);
+        assert_eq!(profiler.total_cycles(), m.cycles);
+        let routines = profiler.routines();
+        assert_eq!(routines[0], RoutineStats { address: None, calls: 0, self_cycles: 10 + 7 + 7, total_cycles: m.cycles });
+        assert_eq!(routines[1], RoutineStats { address: Some(isr), calls: 1, self_cycles: 11 + 10, total_cycles: 21 });
+    }
+}
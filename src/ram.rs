@@ -1,4 +1,5 @@
 pub use crate::memory::Memory;
+use crate::snapshot::{SnapshotError, StateReader, StateWriter};
 
 pub struct RAM {
     data: Vec<u8>,
@@ -19,11 +20,22 @@ impl std::default::Default for RAM {
 impl Memory for RAM {
     #[inline]
     fn get_u8(&self, addr: u16) -> u8 {
-        self.data[addr as usize]
+        // Past the end of the device the bus floats.
+        self.data.get(addr as usize).copied().unwrap_or(0xFF)
     }
 
     #[inline]
     fn set_u8(&mut self, addr: u16, value: u8) {
-        self.data[addr as usize] = value;
+        if let Some(byte) = self.data.get_mut(addr as usize) {
+            *byte = value;
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.data);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SnapshotError> {
+        reader.read_bytes_into(&mut self.data)
     }
 }
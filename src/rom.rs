@@ -15,7 +15,8 @@ impl ROM {
 impl Memory for ROM {
     #[inline]
     fn get_u8(&self, addr: u16) -> u8 {
-        self.data[addr as usize]
+        // Past the end of the device the bus floats.
+        self.data.get(addr as usize).copied().unwrap_or(0xFF)
     }
 
     #[inline]
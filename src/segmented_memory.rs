@@ -1,39 +1,255 @@
 use crate::memory::Memory;
+use crate::snapshot::{SnapshotError, StateReader, StateWriter};
+
+const PAGE_BITS: u32 = 8;
+const PAGE_SIZE: usize = 1 << PAGE_BITS;
+const PAGES: usize = 0x10000 / PAGE_SIZE;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Page {
+    Unmapped,
+    /// The whole page belongs to a single segment.
+    Segment(usize),
+    /// The page is split between segments or they overlap. The index selects a table in
+    /// `shared` which gives the set of segments for every byte of the page.
+    Shared(usize),
+}
 
 pub struct SegmentedMemory {
-    segments: Vec<(usize, usize, Box<dyn Memory>)>,
+    /// `(from, mask, memory)`: the device sees `(addr - from) & mask`.
+    segments: Vec<(usize, u16, Box<dyn Memory>)>,
+    pages: [Page; PAGES],
+    /// Per-byte indices into `sets` for shared pages.
+    shared: Vec<[u16; PAGE_SIZE]>,
+    /// Distinct sets of segments mapped to a byte, in the order they were added.
+    /// Reads use the first segment, writes go to all of them.
+    sets: Vec<Vec<usize>>,
 }
 
 impl SegmentedMemory {
     pub fn new() -> Self {
         Self {
-            segments: Vec::new()
+            segments: Vec::new(),
+            pages: [Page::Unmapped; PAGES],
+            shared: Vec::new(),
+            sets: vec![Vec::new()],
         }
     }
 
-    pub fn add(mut self, from: usize, to: usize, memory: Box<dyn Memory>) -> Self {
-        self.segments.push((from, to, memory));
+    pub fn add(self, from: usize, to: usize, memory: Box<dyn Memory>) -> Self {
+        self.add_masked(from, to, 0xFFFF, memory)
+    }
+
+    /// Maps the device to `from..to` decoding only address lines present in `mask`,
+    /// i.e. the device sees `(addr - from) & mask`.
+    pub fn add_masked(mut self, from: usize, to: usize, mask: u16, memory: Box<dyn Memory>) -> Self {
+        let index = self.segments.len();
+        self.segments.push((from, mask, memory));
+
+        let to = to.min(0x10000);
+        if from < to {
+            for page in (from / PAGE_SIZE)..=((to - 1) / PAGE_SIZE) {
+                let start = page * PAGE_SIZE;
+                let covered = from <= start && start + PAGE_SIZE <= to;
+                if covered && self.pages[page] == Page::Unmapped {
+                    self.pages[page] = Page::Segment(index);
+                    continue;
+                }
+                let table = self.share(page);
+                for offset in from.max(start) - start..to.min(start + PAGE_SIZE) - start {
+                    let mut set = self.sets[self.shared[table][offset] as usize].clone();
+                    set.push(index);
+                    self.shared[table][offset] = self.set_index(set);
+                }
+            }
+        }
         self
     }
+
+    /// Turns the page into a shared one keeping its current mapping. Returns its table.
+    fn share(&mut self, page: usize) -> usize {
+        let set = match self.pages[page] {
+            Page::Shared(table) => return table,
+            Page::Unmapped => 0,
+            Page::Segment(index) => self.set_index(vec![index]),
+        };
+        self.shared.push([set; PAGE_SIZE]);
+        self.pages[page] = Page::Shared(self.shared.len() - 1);
+        self.shared.len() - 1
+    }
+
+    fn set_index(&mut self, set: Vec<usize>) -> u16 {
+        let index = match self.sets.iter().position(|s| *s == set) {
+            Some(index) => index,
+            None => {
+                self.sets.push(set);
+                self.sets.len() - 1
+            },
+        };
+        index as u16
+    }
+
+    /// Maps the device of `size` bytes to `from..to` repeating it through the whole window.
+    /// `size` must be a power of two.
+    pub fn add_mirrored(self, from: usize, to: usize, size: usize, memory: Box<dyn Memory>) -> Self {
+        assert!(size.is_power_of_two() && size <= 0x10000, "mirror size must be a power of two");
+        self.add_masked(from, to, (size - 1) as u16, memory)
+    }
+}
+
+impl std::default::Default for SegmentedMemory {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Memory for SegmentedMemory {
     #[inline]
     fn get_u8(&self, addr: u16) -> u8 {
-        for (from, to, memory) in &self.segments {
-            if *from <= (addr as usize) && (addr as usize) < *to {
-                return memory.get_u8(addr - (*from as u16));
-            }
+        match self.pages[(addr >> PAGE_BITS) as usize] {
+            Page::Unmapped => 0xFF,
+            Page::Segment(index) => {
+                let (from, mask, ref memory) = self.segments[index];
+                memory.get_u8((addr - (from as u16)) & mask)
+            },
+            Page::Shared(table) => {
+                let set = self.shared[table][addr as usize % PAGE_SIZE];
+                match self.sets[set as usize].first() {
+                    Some(&index) => {
+                        let (from, mask, ref memory) = self.segments[index];
+                        memory.get_u8((addr - (from as u16)) & mask)
+                    },
+                    None => 0xFF,
+                }
+            },
         }
-        0xFF
     }
 
     #[inline]
     fn set_u8(&mut self, addr: u16, value: u8) {
-        for (from, to, ref mut memory) in self.segments.iter_mut() {
-            if *from <= (addr as usize) && (addr as usize) < *to {
-                memory.set_u8(addr - (*from as u16), value);
-            }
+        match self.pages[(addr >> PAGE_BITS) as usize] {
+            Page::Unmapped => {},
+            Page::Segment(index) => {
+                let (from, mask, ref mut memory) = self.segments[index];
+                memory.set_u8((addr - (from as u16)) & mask, value);
+            },
+            Page::Shared(table) => {
+                let set = self.shared[table][addr as usize % PAGE_SIZE];
+                for &index in &self.sets[set as usize] {
+                    let (from, mask, ref mut memory) = self.segments[index];
+                    memory.set_u8((addr - (from as u16)) & mask, value);
+                }
+            },
         }
     }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        for (_, _, memory) in &self.segments {
+            memory.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SnapshotError> {
+        for (_, _, memory) in self.segments.iter_mut() {
+            memory.load_state(reader)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ram::RAM;
+    use crate::rom::ROM;
+
+    #[test]
+    fn test_pages() {
+        let memory = SegmentedMemory::new()
+            .add(0x0000, 0x4000, Box::new(RAM::new(0x4000)))
+            .add(0x8000, 0x8004, Box::new(RAM::new(4)))
+            .add(0x8010, 0x8100, Box::new(RAM::new(0xF0)))
+            .add(0xF800, 0x10000, Box::new(ROM::new(&[0x55; 0x800])));
+
+        assert_eq!(memory.pages[0x00], Page::Segment(0));
+        assert_eq!(memory.pages[0x3F], Page::Segment(0));
+        assert_eq!(memory.pages[0x40], Page::Unmapped);
+        assert_eq!(memory.pages[0x80], Page::Shared(0));
+        assert_eq!(memory.sets[memory.shared[0][0x03] as usize], [1]);
+        assert_eq!(memory.sets[memory.shared[0][0x04] as usize], []);
+        assert_eq!(memory.sets[memory.shared[0][0x10] as usize], [2]);
+        assert_eq!(memory.pages[0x81], Page::Unmapped);
+        assert_eq!(memory.pages[0xFF], Page::Segment(3));
+    }
+
+    #[test]
+    fn test_access() {
+        let mut memory = SegmentedMemory::new()
+            .add(0x0000, 0x4000, Box::new(RAM::new(0x4000)))
+            .add(0x8000, 0x8004, Box::new(RAM::new(4)))
+            .add(0x8010, 0x8100, Box::new(RAM::new(0xF0)))
+            .add(0xF800, 0x10000, Box::new(ROM::new(&[0x55; 0x800])));
+
+        memory.set_u8(0x3FFF, 1);
+        memory.set_u8(0x8003, 2);
+        memory.set_u8(0x8010, 3);
+        memory.set_u8(0xF800, 4);
+        memory.set_u8(0x4000, 5);
+        memory.set_u8(0x8004, 6);
+
+        assert_eq!(memory.get_u8(0x3FFF), 1);
+        assert_eq!(memory.get_u8(0x8003), 2);
+        assert_eq!(memory.get_u8(0x8010), 3);
+        assert_eq!(memory.get_u8(0xF800), 0x55);
+        assert_eq!(memory.get_u8(0x4000), 0xFF);
+        assert_eq!(memory.get_u8(0x8004), 0xFF);
+        assert_eq!(memory.get_u8(0x800F), 0xFF);
+    }
+
+    #[test]
+    fn test_overlapping_writes_reach_all_segments() {
+        let mut memory = SegmentedMemory::new()
+            .add(0x0000, 0x1000, Box::new(RAM::new(0x1000)))
+            .add(0x0000, 0x0100, Box::new(RAM::new(0x100)));
+
+        assert_eq!(memory.pages[0], Page::Shared(0));
+        assert_eq!(memory.pages[1], Page::Segment(0));
+        assert_eq!(memory.sets[memory.shared[0][0x10] as usize], [0, 1]);
+        memory.set_u8(0x0010, 7);
+        assert_eq!(memory.get_u8(0x0010), 7);
+        assert_eq!(memory.segments[1].2.get_u8(0x0010), 7);
+    }
+
+    #[test]
+    fn test_mirroring() {
+        let mut memory = SegmentedMemory::new()
+            .add_mirrored(0x0000, 0x8000, 0x1000, Box::new(RAM::new(0x1000)))
+            .add_masked(0x8000, 0xA000, 0x0003, Box::new(RAM::new(4)))
+            .add_mirrored(0xF000, 0x10000, 0x800, Box::new(ROM::new(&[0x55; 0x800])));
+
+        memory.set_u8(0x0123, 1);
+        assert_eq!(memory.get_u8(0x1123), 1);
+        assert_eq!(memory.get_u8(0x7123), 1);
+
+        memory.set_u8(0x8001, 2);
+        assert_eq!(memory.get_u8(0x8005), 2);
+        assert_eq!(memory.get_u8(0x9FFD), 2);
+
+        assert_eq!(memory.get_u8(0xF7FF), 0x55);
+        assert_eq!(memory.get_u8(0xFFFF), 0x55);
+    }
+
+    #[test]
+    fn test_device_smaller_than_window() {
+        let mut memory = SegmentedMemory::new()
+            .add(0x0000, 0x1000, Box::new(RAM::new(0x400)))
+            .add(0xF000, 0x10000, Box::new(ROM::new(&[0x55; 0xC00])));
+
+        memory.set_u8(0x0FFF, 1);
+        assert_eq!(memory.get_u8(0x0FFF), 0xFF);
+        assert_eq!(memory.get_u8(0x03FF), 0);
+        assert_eq!(memory.get_u8(0xFBFF), 0x55);
+        assert_eq!(memory.get_u8(0xFC00), 0xFF);
+        assert_eq!(memory.get_u8(0xFFFF), 0xFF);
+    }
 }
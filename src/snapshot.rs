@@ -0,0 +1,149 @@
+//! Binary snapshot format.
+//!
+//! A snapshot starts with `MAGIC` and a little-endian `u16` version followed by the
+//! processor state, the state of the memory devices and the state of the I/O devices.
+//! Devices write their state in the order they are mapped, so a snapshot can only be
+//! loaded into a machine with the same memory and I/O layout.
+
+use std::fmt;
+
+pub const MAGIC: &[u8; 8] = b"RS580SNP";
+
+/// Version of the format written by `StateWriter`. Older versions are accepted by the loader.
+pub const VERSION: u16 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnexpectedEnd,
+    /// The snapshot does not match the machine, e.g. memory of a different size.
+    Mismatch(String),
+    TrailingData,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::BadMagic => write!(f, "Not a snapshot."),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "Unsupported snapshot version {}.", version),
+            SnapshotError::UnexpectedEnd => write!(f, "Snapshot is truncated."),
+            SnapshotError::Mismatch(what) => write!(f, "Snapshot does not match the machine: {}.", what),
+            SnapshotError::TrailingData => write!(f, "Unexpected data at the end of snapshot."),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Writes length-prefixed bytes.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    version: u16,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8], version: u16) -> Self {
+        Self { data, version }
+    }
+
+    /// Version of the snapshot being read.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.data.len() < len {
+            return Err(SnapshotError::UnexpectedEnd);
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, SnapshotError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, SnapshotError> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, SnapshotError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, SnapshotError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Reads length-prefixed bytes.
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], SnapshotError> {
+        let len = self.read_u32()? as usize;
+        self.take(len)
+    }
+
+    /// Reads length-prefixed bytes into `buffer`, which must be of the same length.
+    pub fn read_bytes_into(&mut self, buffer: &mut [u8]) -> Result<(), SnapshotError> {
+        let bytes = self.read_bytes()?;
+        if bytes.len() != buffer.len() {
+            return Err(SnapshotError::Mismatch(format!("expected {} bytes, got {}", buffer.len(), bytes.len())));
+        }
+        buffer.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
@@ -0,0 +1,201 @@
+//! Per-instruction trace for diffing against other emulators.
+//!
+//! Every executed instruction produces one line with the state before its execution:
+//!
+//! ```text
+//! PC=0100 A=00 F=02 B=00 C=00 D=00 E=00 H=00 L=00 SP=0000 (SP)=0000 CYC=0 | 3E 9B    | MVI A,9BH
+//! ```
+//!
+//! `F` is the flags byte as PUSH PSW stores it, `(SP)` is the word at the top of the stack and
+//! `CYC` is `Machine::cycles`. Steps of a halted processor and interrupt acknowledges, which do not
+//! run the instruction at PC, are not traced.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use crate::cpu::{ExecutionError, Hook, StepContext, StepOutcome};
+use crate::disasm::disassemble_for;
+
+pub struct Tracer {
+    out: Box<dyn Write>,
+    ranges: Vec<RangeInclusive<u16>>,
+    skip: u64,
+    executed: u64,
+    error: Option<io::Error>,
+    /// Line of the instruction about to run, written once the step shows it did.
+    pending: Option<Option<String>>,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Self {
+            out,
+            ranges: Vec::new(),
+            skip: 0,
+            executed: 0,
+            error: None,
+            pending: None,
+        }
+    }
+
+    pub fn to_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(Box::new(BufWriter::new(File::create(path)?))))
+    }
+
+    /// Only trace instructions in the range. Several ranges may be added; without any all addresses are traced.
+    pub fn with_range(mut self, range: RangeInclusive<u16>) -> Self {
+        self.ranges.push(range);
+        self
+    }
+
+    /// Do not trace the first `count` instructions.
+    pub fn skip(mut self, count: u64) -> Self {
+        self.skip = count;
+        self
+    }
+
+    /// Number of instructions seen so far, traced or not.
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+
+    /// The first write error. Tracing stops after it.
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    fn wanted(&self, pc: u16) -> bool {
+        self.executed >= self.skip
+            && (self.ranges.is_empty() || self.ranges.iter().any(|range| range.contains(&pc)))
+    }
+}
+
+/// Formats one trace line, without the line end.
+pub fn trace_line(context: &StepContext) -> String {
+    let r = context.registers;
+    let instruction = disassemble_for(context.memory, r.pc, context.variant);
+    let bytes: Vec<String> = instruction.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    format!(
+        "PC={:04X} A={:02X} F={:02X} B={:02X} C={:02X} D={:02X} E={:02X} H={:02X} L={:02X} SP={:04X} (SP)={:04X} CYC={} | {:<8} | {}",
+        r.pc, r.a, context.variant.flags_byte(r), r.b, r.c, r.d, r.e, r.h, r.l, r.sp,
+        context.memory.get_u16(r.sp), context.cycles, bytes.join(" "), instruction,
+    )
+}
+
+impl Hook for Tracer {
+    fn before_step(&mut self, context: &StepContext) {
+        if context.halted || self.error.is_some() {
+            return;
+        }
+        let line = if self.wanted(context.registers.pc) { Some(trace_line(context)) } else { None };
+        self.pending = Some(line);
+    }
+
+    fn after_step(&mut self, _context: &StepContext, result: &Result<StepOutcome, ExecutionError>) {
+        let line = match self.pending.take() {
+            Some(line) => line,
+            None => return,
+        };
+        if let Ok(StepOutcome::Interrupted(_)) = result {
+            return;
+        }
+        self.executed += 1;
+        if let Some(line) = line {
+            if let Err(error) = writeln!(self.out, "{}", line) {
+                self.error = Some(error);
+            }
+        }
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::cpu::{InterruptAck, Machine};
+    use crate::memory::Memory;
+    use crate::ram::RAM;
+    use rs580_macros::asm8080;
+
+    /// Writer whose contents stay readable after the tracer took it.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trace() {
+        let program = asm8080! {
+                LXI SP,100h;
+                MVI A,9Bh;
+            again:
+                INR A;
+                JMP again
+        };
+        let mut m = Machine::new(RAM::default());
+        m.memory.set_range(program.origin, &program.bytes);
+        let out = Shared::default();
+        let tracer = Rc::new(RefCell::new(Tracer::new(Box::new(out.clone())).skip(1).with_range(0x0000..=0x0005)));
+        m.add_hook(Box::new(tracer.clone()));
+        for _ in 0..5 {
+            m.step().unwrap();
+        }
+        assert_eq!(tracer.borrow().executed(), 5);
+        assert_eq!(
+            String::from_utf8(out.0.borrow().clone()).unwrap(),
+            "PC=0003 A=00 F=02 B=00 C=00 D=00 E=00 H=00 L=00 SP=0100 (SP)=0000 CYC=10 | 3E 9B    | MVI A,9BH\n\
+             PC=0005 A=9B F=02 B=00 C=00 D=00 E=00 H=00 L=00 SP=0100 (SP)=0000 CYC=17 | 3C       | INR A\n\
+             PC=0005 A=9C F=86 B=00 C=00 D=00 E=00 H=00 L=00 SP=0100 (SP)=0000 CYC=32 | 3C       | INR A\n"
+        );
+    }
+
+    #[test]
+    fn test_interrupt_is_not_traced() {
+        let program = asm8080! {
+                LXI SP,100h;
+                MVI A,9Bh;
+                HLT;
+                ORG 38h;
+                RET
+        };
+        let mut m = Machine::new(RAM::default());
+        m.memory.set_range(program.origin, &program.bytes);
+        let out = Shared::default();
+        let tracer = Rc::new(RefCell::new(Tracer::new(Box::new(out.clone())).skip(1)));
+        m.add_hook(Box::new(tracer.clone()));
+        m.step().unwrap();
+        m.interrupt(InterruptAck::Rst(7));
+        assert_eq!(m.step(), Ok(StepOutcome::Interrupted(11)));
+        m.clear_interrupt();
+        while !m.halted {
+            m.step().unwrap();
+        }
+        assert_eq!(tracer.borrow().executed(), 4);
+        assert_eq!(
+            String::from_utf8(out.0.borrow().clone()).unwrap(),
+            "PC=0038 A=00 F=02 B=00 C=00 D=00 E=00 H=00 L=00 SP=00FE (SP)=0003 CYC=21 | C9       | RET\n\
+             PC=0003 A=00 F=02 B=00 C=00 D=00 E=00 H=00 L=00 SP=0100 (SP)=0000 CYC=31 | 3E 9B    | MVI A,9BH\n\
+             PC=0005 A=9B F=02 B=00 C=00 D=00 E=00 H=00 L=00 SP=0100 (SP)=0000 CYC=38 | 76       | HLT\n"
+        );
+    }
+}
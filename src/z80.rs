@@ -0,0 +1,1367 @@
+//! Zilog Z80 processor.
+//!
+//! `Z80` runs on the same `Memory` and `Io` devices as `cpu::Machine`, so `RAM`, `ROM`,
+//! `SegmentedMemory` and the rest are shared between both processors. All prefixed opcodes
+//! (CB, DD, ED, FD, DDCB, FDCB) are implemented including the undocumented ones, together with
+//! the undocumented flags X and Y (bits 3 and 5 of F) and the internal MEMPTR register.
+//!
+//! I/O devices see the low byte of the port address: C for `IN r,(C)` and the block
+//! instructions, the immediate operand for `IN A,(n)` and `OUT (n),A`.
+
+use crate::cpu::StepOutcome;
+use crate::io::{Io, NullIo};
+use crate::memory::Memory;
+
+pub const FLAG_C: u8 = 0x01;
+/// Set by subtractions, used by DAA.
+pub const FLAG_N: u8 = 0x02;
+/// Parity or overflow.
+pub const FLAG_PV: u8 = 0x04;
+/// Undocumented copy of bit 3 of the result.
+pub const FLAG_X: u8 = 0x08;
+pub const FLAG_H: u8 = 0x10;
+/// Undocumented copy of bit 5 of the result.
+pub const FLAG_Y: u8 = 0x20;
+pub const FLAG_Z: u8 = 0x40;
+pub const FLAG_S: u8 = 0x80;
+
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct Registers {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    /// AF', BC', DE' and HL', swapped in by `EX AF,AF'` and `EXX`.
+    pub af_alt: u16,
+    pub bc_alt: u16,
+    pub de_alt: u16,
+    pub hl_alt: u16,
+    pub ix: u16,
+    pub iy: u16,
+    pub sp: u16,
+    pub pc: u16,
+    /// High byte of the IM 2 vector table address.
+    pub i: u8,
+    /// Refresh counter. The low 7 bits count opcode fetches, bit 7 only changes by `LD R,A`.
+    pub r: u8,
+    /// Internal address latch (MEMPTR). It leaks into X and Y after `BIT n,(HL)`.
+    pub wz: u16,
+}
+
+impl Registers {
+    pub fn af(&self) -> u16 {
+        (self.a as u16) << 8 | self.f as u16
+    }
+
+    pub fn bc(&self) -> u16 {
+        (self.b as u16) << 8 | self.c as u16
+    }
+
+    pub fn de(&self) -> u16 {
+        (self.d as u16) << 8 | self.e as u16
+    }
+
+    pub fn hl(&self) -> u16 {
+        (self.h as u16) << 8 | self.l as u16
+    }
+
+    pub fn set_af(&mut self, value: u16) {
+        self.a = (value >> 8) as u8;
+        self.f = value as u8;
+    }
+
+    pub fn set_bc(&mut self, value: u16) {
+        self.b = (value >> 8) as u8;
+        self.c = value as u8;
+    }
+
+    pub fn set_de(&mut self, value: u16) {
+        self.d = (value >> 8) as u8;
+        self.e = value as u8;
+    }
+
+    pub fn set_hl(&mut self, value: u16) {
+        self.h = (value >> 8) as u8;
+        self.l = value as u8;
+    }
+}
+
+/// Register replacing HL after a DD or FD prefix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Index {
+    Hl,
+    Ix,
+    Iy,
+}
+
+/// Splits an opcode into the `x`, `y` and `z` fields: `xx yyy zzz`.
+#[inline]
+fn fields(opcode: u8) -> (u8, u8, u8) {
+    (opcode >> 6, (opcode >> 3) & 7, opcode & 7)
+}
+
+/// S, Z, X and Y of a result.
+#[inline]
+fn sz53(value: u8) -> u8 {
+    (value & (FLAG_S | FLAG_X | FLAG_Y)) | if value == 0 { FLAG_Z } else { 0 }
+}
+
+#[inline]
+fn parity(value: u8) -> u8 {
+    if value.count_ones().is_multiple_of(2) { FLAG_PV } else { 0 }
+}
+
+#[inline]
+fn sz53p(value: u8) -> u8 {
+    sz53(value) | parity(value)
+}
+
+/// Z80 processor attached to the memory `M`.
+pub struct Z80<M = Box<dyn Memory>> {
+    pub registers: Registers,
+    pub halted: bool,
+    /// Interrupt enable flip-flops. IFF2 keeps IFF1 while an NMI is handled.
+    pub iff1: bool,
+    pub iff2: bool,
+    /// 0, 1 or 2, as set by IM.
+    pub interrupt_mode: u8,
+    /// State of the INT line together with the byte put on the data bus on acknowledge:
+    /// the instruction in IM 0, ignored in IM 1 and the low byte of the vector address in IM 2.
+    pub interrupt_request: Option<u8>,
+    nmi_pending: bool,
+    /// Set by EI: interrupts are not accepted until one more instruction is executed.
+    interrupt_delay: bool,
+    /// F if the last instruction changed the flags, otherwise 0. SCF and CCF take X and Y from it.
+    q: u8,
+    flags_written: bool,
+    /// Total number of T-states executed since creation of the processor.
+    pub cycles: u64,
+    pub memory: M,
+    pub io: Box<dyn Io>,
+}
+
+impl<M: Memory> Z80<M> {
+    pub fn new(memory: M) -> Self {
+        Self {
+            registers: Registers::default(),
+            halted: false,
+            iff1: false,
+            iff2: false,
+            interrupt_mode: 0,
+            interrupt_request: None,
+            nmi_pending: false,
+            interrupt_delay: false,
+            q: 0,
+            flags_written: false,
+            cycles: 0,
+            memory,
+            io: Box::new(NullIo),
+        }
+    }
+
+    pub fn with_io(mut self, io: Box<dyn Io>) -> Self {
+        self.io = io;
+        self
+    }
+
+    /// RESET: clears PC, I, R, disables interrupts and selects IM 0.
+    pub fn reset(&mut self) {
+        self.registers.pc = 0;
+        self.registers.i = 0;
+        self.registers.r = 0;
+        self.iff1 = false;
+        self.iff2 = false;
+        self.interrupt_mode = 0;
+        self.halted = false;
+    }
+
+    /// Asserts INT with `data` on the data bus. The request stays pending until it is acknowledged or cleared.
+    ///
+    /// In IM 0 `data` is executed as an instruction. It is normally an RST; other instructions
+    /// take their operands from memory at PC rather than from the device.
+    pub fn interrupt(&mut self, data: u8) {
+        self.interrupt_request = Some(data);
+    }
+
+    pub fn clear_interrupt(&mut self) {
+        self.interrupt_request = None;
+    }
+
+    /// Falling edge on NMI. The interrupt is accepted after the current instruction, even with
+    /// interrupts disabled.
+    pub fn nmi(&mut self) {
+        self.nmi_pending = true;
+    }
+
+    /// Executes one instruction together with its prefixes (or acknowledges an interrupt).
+    /// A halted processor executes NOPs, spending 4 T-states per step.
+    pub fn step(&mut self) -> StepOutcome {
+        self.flags_written = false;
+        let delayed = std::mem::replace(&mut self.interrupt_delay, false);
+        let acknowledged = match self.interrupt_request {
+            _ if self.nmi_pending => Some(self.accept_nmi()),
+            Some(data) if self.iff1 && !delayed => Some(self.accept_interrupt(data)),
+            _ => None,
+        };
+        if let Some(cycles) = acknowledged {
+            self.q = 0;
+            self.cycles += cycles as u64;
+            return StepOutcome::Interrupted(cycles);
+        }
+        let cycles = if self.halted {
+            self.increment_r();
+            4
+        } else {
+            self.execute_next()
+        };
+        self.q = if self.flags_written { self.registers.f } else { 0 };
+        self.cycles += cycles as u64;
+        if self.halted {
+            StepOutcome::Halted(cycles)
+        } else {
+            StepOutcome::Executed(cycles)
+        }
+    }
+
+    fn accept_nmi(&mut self) -> u32 {
+        self.nmi_pending = false;
+        self.halted = false;
+        self.iff1 = false;
+        self.increment_r();
+        self.push(self.registers.pc);
+        self.registers.pc = 0x0066;
+        self.registers.wz = 0x0066;
+        11
+    }
+
+    fn accept_interrupt(&mut self, data: u8) -> u32 {
+        self.interrupt_request = None;
+        self.halted = false;
+        self.iff1 = false;
+        self.iff2 = false;
+        self.increment_r();
+        match self.interrupt_mode {
+            // Two wait states are added to the M1 cycle of the acknowledge.
+            0 => 2 + self.execute(data, Index::Hl),
+            1 => {
+                self.push(self.registers.pc);
+                self.registers.pc = 0x0038;
+                self.registers.wz = 0x0038;
+                13
+            },
+            _ => {
+                self.push(self.registers.pc);
+                let vector = (self.registers.i as u16) << 8 | data as u16;
+                self.registers.pc = self.read_u16(vector);
+                self.registers.wz = self.registers.pc;
+                19
+            },
+        }
+    }
+
+    fn execute_next(&mut self) -> u32 {
+        let mut opcode = self.fetch_opcode();
+        let mut index = Index::Hl;
+        let mut cycles = 0;
+        // Every DD or FD prefix takes 4 T-states; the last one wins.
+        while opcode == 0xDD || opcode == 0xFD {
+            index = if opcode == 0xDD { Index::Ix } else { Index::Iy };
+            cycles += 4;
+            opcode = self.fetch_opcode();
+        }
+        cycles + self.execute(opcode, index)
+    }
+
+    /// Executes an unprefixed opcode, with HL replaced according to `index`.
+    /// Returns T-states without the ones of the index prefix.
+    fn execute(&mut self, opcode: u8, index: Index) -> u32 {
+        let (x, y, z) = fields(opcode);
+        let (p, q) = (y >> 1, y & 1);
+        match x {
+            0 => match z {
+                0 => match y {
+                    0 => 4,
+                    1 => {
+                        let af = self.registers.af();
+                        self.registers.set_af(self.registers.af_alt);
+                        self.registers.af_alt = af;
+                        4
+                    },
+                    2 => {
+                        let offset = self.fetch_u8();
+                        self.registers.b = self.registers.b.wrapping_sub(1);
+                        if self.registers.b != 0 {
+                            self.jump_relative(offset);
+                            13
+                        } else {
+                            8
+                        }
+                    },
+                    3 => {
+                        let offset = self.fetch_u8();
+                        self.jump_relative(offset);
+                        12
+                    },
+                    _ => {
+                        let offset = self.fetch_u8();
+                        if self.condition(y - 4) {
+                            self.jump_relative(offset);
+                            12
+                        } else {
+                            7
+                        }
+                    },
+                },
+                1 => {
+                    if q == 0 {
+                        let value = self.fetch_u16();
+                        self.set_pair(p, index, value);
+                        10
+                    } else {
+                        self.add16(index, self.pair(p, index));
+                        11
+                    }
+                },
+                2 => {
+                    match (p, q) {
+                        (0, 0) | (1, 0) => {
+                            let address = if p == 0 { self.registers.bc() } else { self.registers.de() };
+                            self.memory.set_u8(address, self.registers.a);
+                            self.registers.wz = (self.registers.a as u16) << 8 | (address.wrapping_add(1) & 0xFF);
+                            7
+                        },
+                        (0, 1) | (1, 1) => {
+                            let address = if p == 0 { self.registers.bc() } else { self.registers.de() };
+                            self.registers.a = self.memory.get_u8(address);
+                            self.registers.wz = address.wrapping_add(1);
+                            7
+                        },
+                        (2, 0) => {
+                            let address = self.fetch_u16();
+                            self.write_u16(address, self.index_register(index));
+                            self.registers.wz = address.wrapping_add(1);
+                            16
+                        },
+                        (2, 1) => {
+                            let address = self.fetch_u16();
+                            let value = self.read_u16(address);
+                            self.set_index_register(index, value);
+                            self.registers.wz = address.wrapping_add(1);
+                            16
+                        },
+                        (3, 0) => {
+                            let address = self.fetch_u16();
+                            self.memory.set_u8(address, self.registers.a);
+                            self.registers.wz = (self.registers.a as u16) << 8 | (address.wrapping_add(1) & 0xFF);
+                            13
+                        },
+                        _ => {
+                            let address = self.fetch_u16();
+                            self.registers.a = self.memory.get_u8(address);
+                            self.registers.wz = address.wrapping_add(1);
+                            13
+                        },
+                    }
+                },
+                3 => {
+                    let value = self.pair(p, index);
+                    let value = if q == 0 { value.wrapping_add(1) } else { value.wrapping_sub(1) };
+                    self.set_pair(p, index, value);
+                    6
+                },
+                4 | 5 => {
+                    let (address, cycles) = if y == 6 {
+                        (Some(self.memory_operand(index)), if index == Index::Hl { 11 } else { 19 })
+                    } else {
+                        (None, 4)
+                    };
+                    let value = match address {
+                        Some(address) => self.memory.get_u8(address),
+                        None => self.register(y, index),
+                    };
+                    let result = if z == 4 { self.inc8(value) } else { self.dec8(value) };
+                    match address {
+                        Some(address) => self.memory.set_u8(address, result),
+                        None => self.set_register(y, index, result),
+                    }
+                    cycles
+                },
+                6 => {
+                    if y == 6 {
+                        let address = self.memory_operand(index);
+                        let value = self.fetch_u8();
+                        self.memory.set_u8(address, value);
+                        if index == Index::Hl { 10 } else { 15 }
+                    } else {
+                        let value = self.fetch_u8();
+                        self.set_register(y, index, value);
+                        7
+                    }
+                },
+                _ => {
+                    self.accumulator_operation(y);
+                    4
+                },
+            },
+            1 => {
+                if y == 6 && z == 6 {
+                    self.halted = true;
+                    4
+                } else if z == 6 {
+                    // With (IX+d) the other operand is H or L, not a half of IX.
+                    let address = self.memory_operand(index);
+                    let value = self.memory.get_u8(address);
+                    self.set_register(y, Index::Hl, value);
+                    if index == Index::Hl { 7 } else { 15 }
+                } else if y == 6 {
+                    let address = self.memory_operand(index);
+                    let value = self.register(z, Index::Hl);
+                    self.memory.set_u8(address, value);
+                    if index == Index::Hl { 7 } else { 15 }
+                } else {
+                    let value = self.register(z, index);
+                    self.set_register(y, index, value);
+                    4
+                }
+            },
+            2 => {
+                if z == 6 {
+                    let address = self.memory_operand(index);
+                    let value = self.memory.get_u8(address);
+                    self.alu(y, value);
+                    if index == Index::Hl { 7 } else { 15 }
+                } else {
+                    let value = self.register(z, index);
+                    self.alu(y, value);
+                    4
+                }
+            },
+            _ => match z {
+                0 => {
+                    if self.condition(y) {
+                        self.registers.pc = self.pop();
+                        self.registers.wz = self.registers.pc;
+                        11
+                    } else {
+                        5
+                    }
+                },
+                1 => match (q, p) {
+                    (0, _) => {
+                        let value = self.pop();
+                        self.set_pair_af(p, index, value);
+                        10
+                    },
+                    (_, 0) => {
+                        self.registers.pc = self.pop();
+                        self.registers.wz = self.registers.pc;
+                        10
+                    },
+                    (_, 1) => {
+                        let r = &mut self.registers;
+                        let (bc, de, hl) = (r.bc(), r.de(), r.hl());
+                        r.set_bc(r.bc_alt);
+                        r.set_de(r.de_alt);
+                        r.set_hl(r.hl_alt);
+                        r.bc_alt = bc;
+                        r.de_alt = de;
+                        r.hl_alt = hl;
+                        4
+                    },
+                    (_, 2) => {
+                        self.registers.pc = self.index_register(index);
+                        4
+                    },
+                    _ => {
+                        self.registers.sp = self.index_register(index);
+                        6
+                    },
+                },
+                2 => {
+                    let address = self.fetch_u16();
+                    if self.condition(y) {
+                        self.registers.pc = address;
+                    }
+                    self.registers.wz = address;
+                    10
+                },
+                3 => match y {
+                    0 => {
+                        let address = self.fetch_u16();
+                        self.registers.pc = address;
+                        self.registers.wz = address;
+                        10
+                    },
+                    1 => self.execute_cb(index),
+                    2 => {
+                        let port = self.fetch_u8();
+                        self.io.out(port, self.registers.a);
+                        self.registers.wz = (self.registers.a as u16) << 8 | port.wrapping_add(1) as u16;
+                        11
+                    },
+                    3 => {
+                        let port = self.fetch_u8();
+                        self.registers.wz = ((self.registers.a as u16) << 8 | port as u16).wrapping_add(1);
+                        self.registers.a = self.io.inp(port);
+                        11
+                    },
+                    4 => {
+                        let sp = self.registers.sp;
+                        let value = self.read_u16(sp);
+                        self.write_u16(sp, self.index_register(index));
+                        self.set_index_register(index, value);
+                        self.registers.wz = value;
+                        19
+                    },
+                    5 => {
+                        let r = &mut self.registers;
+                        std::mem::swap(&mut r.d, &mut r.h);
+                        std::mem::swap(&mut r.e, &mut r.l);
+                        4
+                    },
+                    6 => {
+                        self.iff1 = false;
+                        self.iff2 = false;
+                        4
+                    },
+                    _ => {
+                        self.iff1 = true;
+                        self.iff2 = true;
+                        self.interrupt_delay = true;
+                        4
+                    },
+                },
+                4 => {
+                    let address = self.fetch_u16();
+                    self.registers.wz = address;
+                    if self.condition(y) {
+                        self.push(self.registers.pc);
+                        self.registers.pc = address;
+                        17
+                    } else {
+                        10
+                    }
+                },
+                5 => match (q, p) {
+                    (0, _) => {
+                        let value = self.pair_af(p, index);
+                        self.push(value);
+                        11
+                    },
+                    (_, 0) => {
+                        let address = self.fetch_u16();
+                        self.push(self.registers.pc);
+                        self.registers.pc = address;
+                        self.registers.wz = address;
+                        17
+                    },
+                    (_, 2) => self.execute_ed(),
+                    // DD and FD are consumed by `execute_next`, here they come from IM 0.
+                    _ => 4,
+                },
+                6 => {
+                    let value = self.fetch_u8();
+                    self.alu(y, value);
+                    7
+                },
+                _ => {
+                    self.push(self.registers.pc);
+                    self.registers.pc = (y as u16) << 3;
+                    self.registers.wz = self.registers.pc;
+                    11
+                },
+            },
+        }
+    }
+
+    /// Rotations, shifts and bit operations. With an index prefix the operand is always
+    /// `(IX+d)`; the result is also copied to the register named by the opcode, if any.
+    fn execute_cb(&mut self, index: Index) -> u32 {
+        let (address, opcode) = if index == Index::Hl {
+            let opcode = self.fetch_opcode();
+            (self.registers.hl(), opcode)
+        } else {
+            // The displacement precedes the opcode, which is not an M1 cycle.
+            let address = self.memory_operand(index);
+            (address, self.fetch_u8())
+        };
+        let (x, y, z) = fields(opcode);
+        let indexed = index != Index::Hl;
+        let in_memory = indexed || z == 6;
+        let value = if in_memory { self.memory.get_u8(address) } else { self.register(z, Index::Hl) };
+
+        if x == 1 {
+            let xy_source = if in_memory { (self.registers.wz >> 8) as u8 } else { value };
+            self.bit(y, value, xy_source);
+            return match (indexed, in_memory) {
+                (true, _) => 16,
+                (false, true) => 12,
+                (false, false) => 8,
+            };
+        }
+        let result = match x {
+            0 => self.rotate(y, value),
+            2 => value & !(1 << y),
+            _ => value | (1 << y),
+        };
+        if in_memory {
+            self.memory.set_u8(address, result);
+        }
+        if !in_memory || (indexed && z != 6) {
+            self.set_register(z, Index::Hl, result);
+        }
+        match (indexed, in_memory) {
+            (true, _) => 19,
+            (false, true) => 15,
+            (false, false) => 8,
+        }
+    }
+
+    fn execute_ed(&mut self) -> u32 {
+        let opcode = self.fetch_opcode();
+        let (x, y, z) = fields(opcode);
+        let (p, q) = (y >> 1, y & 1);
+        match (x, z) {
+            (1, 0) => {
+                let value = self.io.inp(self.registers.c);
+                self.registers.wz = self.registers.bc().wrapping_add(1);
+                // ED 70 only sets the flags.
+                if y != 6 {
+                    self.set_register(y, Index::Hl, value);
+                }
+                self.set_f(sz53p(value) | (self.registers.f & FLAG_C));
+                12
+            },
+            (1, 1) => {
+                let value = if y == 6 { 0 } else { self.register(y, Index::Hl) };
+                self.io.out(self.registers.c, value);
+                self.registers.wz = self.registers.bc().wrapping_add(1);
+                12
+            },
+            (1, 2) => {
+                let value = self.pair(p, Index::Hl);
+                if q == 0 {
+                    self.sbc16(value);
+                } else {
+                    self.adc16(value);
+                }
+                15
+            },
+            (1, 3) => {
+                let address = self.fetch_u16();
+                if q == 0 {
+                    self.write_u16(address, self.pair(p, Index::Hl));
+                } else {
+                    let value = self.read_u16(address);
+                    self.set_pair(p, Index::Hl, value);
+                }
+                self.registers.wz = address.wrapping_add(1);
+                20
+            },
+            (1, 4) => {
+                let value = self.registers.a;
+                self.registers.a = 0;
+                self.registers.a = self.sub8(value, false);
+                8
+            },
+            (1, 5) => {
+                // RETN, and RETI which behaves the same
+                self.iff1 = self.iff2;
+                self.registers.pc = self.pop();
+                self.registers.wz = self.registers.pc;
+                14
+            },
+            (1, 6) => {
+                self.interrupt_mode = [0, 0, 1, 2][(y & 3) as usize];
+                8
+            },
+            (1, 7) => match y {
+                0 => {
+                    self.registers.i = self.registers.a;
+                    9
+                },
+                1 => {
+                    self.registers.r = self.registers.a;
+                    9
+                },
+                2 | 3 => {
+                    let value = if y == 2 { self.registers.i } else { self.registers.r };
+                    self.registers.a = value;
+                    let iff2 = if self.iff2 { FLAG_PV } else { 0 };
+                    self.set_f(sz53(value) | iff2 | (self.registers.f & FLAG_C));
+                    9
+                },
+                4 | 5 => {
+                    let hl = self.registers.hl();
+                    let value = self.memory.get_u8(hl);
+                    let a = self.registers.a;
+                    let (memory, a) = if y == 4 {
+                        (a << 4 | value >> 4, (a & 0xF0) | (value & 0x0F))
+                    } else {
+                        (value << 4 | (a & 0x0F), (a & 0xF0) | value >> 4)
+                    };
+                    self.memory.set_u8(hl, memory);
+                    self.registers.a = a;
+                    self.registers.wz = hl.wrapping_add(1);
+                    self.set_f(sz53p(a) | (self.registers.f & FLAG_C));
+                    18
+                },
+                _ => 8,
+            },
+            (2, 0..=3) if y >= 4 => self.block(y, z),
+            // Everything else is a two-byte NOP.
+            _ => 8,
+        }
+    }
+
+    /// LDI, CPI, INI, OUTI, their decrementing and repeating versions.
+    fn block(&mut self, y: u8, z: u8) -> u32 {
+        let step = if y & 1 == 0 { 1 } else { 0xFFFF };
+        let repeat = y >= 6;
+        let hl = self.registers.hl();
+        self.registers.set_hl(hl.wrapping_add(step));
+        let again = match z {
+            0 => {
+                let value = self.memory.get_u8(hl);
+                let de = self.registers.de();
+                self.memory.set_u8(de, value);
+                self.registers.set_de(de.wrapping_add(step));
+                let bc = self.registers.bc().wrapping_sub(1);
+                self.registers.set_bc(bc);
+                let n = value.wrapping_add(self.registers.a);
+                let pv = if bc != 0 { FLAG_PV } else { 0 };
+                self.set_f((self.registers.f & (FLAG_S | FLAG_Z | FLAG_C)) | (n & FLAG_X) | ((n << 4) & FLAG_Y) | pv);
+                repeat && bc != 0
+            },
+            1 => {
+                let value = self.memory.get_u8(hl);
+                let a = self.registers.a;
+                let result = a.wrapping_sub(value);
+                let half = (a ^ value ^ result) & FLAG_H;
+                let n = result.wrapping_sub((half != 0) as u8);
+                let bc = self.registers.bc().wrapping_sub(1);
+                self.registers.set_bc(bc);
+                self.registers.wz = self.registers.wz.wrapping_add(step);
+                let pv = if bc != 0 { FLAG_PV } else { 0 };
+                let sz = sz53(result) & (FLAG_S | FLAG_Z);
+                self.set_f(sz | half | FLAG_N | (n & FLAG_X) | ((n << 4) & FLAG_Y) | pv | (self.registers.f & FLAG_C));
+                repeat && bc != 0 && result != 0
+            },
+            2 => {
+                let value = self.io.inp(self.registers.c);
+                self.memory.set_u8(hl, value);
+                self.registers.wz = self.registers.bc().wrapping_add(step);
+                self.registers.b = self.registers.b.wrapping_sub(1);
+                let k = value as u16 + self.registers.c.wrapping_add(step as u8) as u16;
+                self.block_io_flags(value, k);
+                repeat && self.registers.b != 0
+            },
+            _ => {
+                let value = self.memory.get_u8(hl);
+                self.registers.b = self.registers.b.wrapping_sub(1);
+                self.registers.wz = self.registers.bc().wrapping_add(step);
+                self.io.out(self.registers.c, value);
+                let k = value as u16 + self.registers.l as u16;
+                self.block_io_flags(value, k);
+                repeat && self.registers.b != 0
+            },
+        };
+        if again {
+            self.registers.pc = self.registers.pc.wrapping_sub(2);
+            if z <= 1 {
+                self.registers.wz = self.registers.pc.wrapping_add(1);
+            }
+            21
+        } else {
+            16
+        }
+    }
+
+    fn block_io_flags(&mut self, value: u8, k: u16) {
+        let b = self.registers.b;
+        let n = if value & 0x80 != 0 { FLAG_N } else { 0 };
+        let hc = if k > 0xFF { FLAG_H | FLAG_C } else { 0 };
+        self.set_f(sz53(b) | n | hc | parity((k as u8 & 7) ^ b));
+    }
+
+    #[inline]
+    fn set_f(&mut self, f: u8) {
+        self.registers.f = f;
+        self.flags_written = true;
+    }
+
+    #[inline]
+    fn increment_r(&mut self) {
+        let r = self.registers.r;
+        self.registers.r = (r & 0x80) | (r.wrapping_add(1) & 0x7F);
+    }
+
+    /// Opcode fetch (M1 cycle), increments R.
+    #[inline]
+    fn fetch_opcode(&mut self) -> u8 {
+        self.increment_r();
+        self.fetch_u8()
+    }
+
+    #[inline]
+    fn fetch_u8(&mut self) -> u8 {
+        let value = self.memory.get_u8(self.registers.pc);
+        self.registers.pc = self.registers.pc.wrapping_add(1);
+        value
+    }
+
+    #[inline]
+    fn fetch_u16(&mut self) -> u16 {
+        let l = self.fetch_u8();
+        let h = self.fetch_u8();
+        (h as u16) << 8 | l as u16
+    }
+
+    fn read_u16(&self, address: u16) -> u16 {
+        self.memory.get_u16(address)
+    }
+
+    fn write_u16(&mut self, address: u16, value: u16) {
+        self.memory.set_u8(address, value as u8);
+        self.memory.set_u8(address.wrapping_add(1), (value >> 8) as u8);
+    }
+
+    /// Pushes a word, the high byte goes first.
+    fn push(&mut self, value: u16) {
+        let sp = self.registers.sp.wrapping_sub(1);
+        self.memory.set_u8(sp, (value >> 8) as u8);
+        let sp = sp.wrapping_sub(1);
+        self.memory.set_u8(sp, value as u8);
+        self.registers.sp = sp;
+    }
+
+    fn pop(&mut self) -> u16 {
+        let value = self.read_u16(self.registers.sp);
+        self.registers.sp = self.registers.sp.wrapping_add(2);
+        value
+    }
+
+    fn jump_relative(&mut self, offset: u8) {
+        self.registers.pc = self.registers.pc.wrapping_add(offset as i8 as u16);
+        self.registers.wz = self.registers.pc;
+    }
+
+    fn condition(&self, cc: u8) -> bool {
+        let f = self.registers.f;
+        let flag = match cc >> 1 {
+            0 => FLAG_Z,
+            1 => FLAG_C,
+            2 => FLAG_PV,
+            _ => FLAG_S,
+        };
+        (f & flag != 0) == (cc & 1 != 0)
+    }
+
+    fn index_register(&self, index: Index) -> u16 {
+        match index {
+            Index::Hl => self.registers.hl(),
+            Index::Ix => self.registers.ix,
+            Index::Iy => self.registers.iy,
+        }
+    }
+
+    fn set_index_register(&mut self, index: Index, value: u16) {
+        match index {
+            Index::Hl => self.registers.set_hl(value),
+            Index::Ix => self.registers.ix = value,
+            Index::Iy => self.registers.iy = value,
+        }
+    }
+
+    /// Address of the `(HL)` operand; reads the displacement of `(IX+d)` and `(IY+d)`.
+    fn memory_operand(&mut self, index: Index) -> u16 {
+        match index {
+            Index::Hl => self.registers.hl(),
+            _ => {
+                let offset = self.fetch_u8() as i8 as u16;
+                let address = self.index_register(index).wrapping_add(offset);
+                self.registers.wz = address;
+                address
+            },
+        }
+    }
+
+    /// Register by its 3-bit code other than 6 (`(HL)`). H and L stand for the halves of IX or IY.
+    fn register(&self, r: u8, index: Index) -> u8 {
+        match r {
+            0 => self.registers.b,
+            1 => self.registers.c,
+            2 => self.registers.d,
+            3 => self.registers.e,
+            4 => (self.index_register(index) >> 8) as u8,
+            5 => self.index_register(index) as u8,
+            7 => self.registers.a,
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_register(&mut self, r: u8, index: Index, value: u8) {
+        match r {
+            0 => self.registers.b = value,
+            1 => self.registers.c = value,
+            2 => self.registers.d = value,
+            3 => self.registers.e = value,
+            4 => {
+                let word = self.index_register(index);
+                self.set_index_register(index, (value as u16) << 8 | (word & 0xFF));
+            },
+            5 => {
+                let word = self.index_register(index);
+                self.set_index_register(index, (word & 0xFF00) | value as u16);
+            },
+            7 => self.registers.a = value,
+            _ => unreachable!(),
+        }
+    }
+
+    /// BC, DE, HL (or IX, IY) or SP by the 2-bit code.
+    fn pair(&self, p: u8, index: Index) -> u16 {
+        match p {
+            0 => self.registers.bc(),
+            1 => self.registers.de(),
+            2 => self.index_register(index),
+            _ => self.registers.sp,
+        }
+    }
+
+    fn set_pair(&mut self, p: u8, index: Index, value: u16) {
+        match p {
+            0 => self.registers.set_bc(value),
+            1 => self.registers.set_de(value),
+            2 => self.set_index_register(index, value),
+            _ => self.registers.sp = value,
+        }
+    }
+
+    /// Like `pair` with AF in place of SP, for PUSH and POP.
+    fn pair_af(&self, p: u8, index: Index) -> u16 {
+        if p == 3 { self.registers.af() } else { self.pair(p, index) }
+    }
+
+    fn set_pair_af(&mut self, p: u8, index: Index, value: u16) {
+        if p == 3 {
+            self.registers.set_af(value);
+        } else {
+            self.set_pair(p, index, value);
+        }
+    }
+
+    fn alu(&mut self, operation: u8, value: u8) {
+        let carry = self.registers.f & FLAG_C != 0;
+        match operation {
+            0 => self.add8(value, false),
+            1 => self.add8(value, carry),
+            2 => self.registers.a = self.sub8(value, false),
+            3 => self.registers.a = self.sub8(value, carry),
+            4 => {
+                self.registers.a &= value;
+                self.set_f(sz53p(self.registers.a) | FLAG_H);
+            },
+            5 => {
+                self.registers.a ^= value;
+                self.set_f(sz53p(self.registers.a));
+            },
+            6 => {
+                self.registers.a |= value;
+                self.set_f(sz53p(self.registers.a));
+            },
+            _ => {
+                // CP takes X and Y from the operand.
+                self.sub8(value, false);
+                self.set_f((self.registers.f & !(FLAG_X | FLAG_Y)) | (value & (FLAG_X | FLAG_Y)));
+            },
+        }
+    }
+
+    fn add8(&mut self, value: u8, carry: bool) {
+        let a = self.registers.a;
+        let sum = a as u16 + value as u16 + carry as u16;
+        let result = sum as u8;
+        let overflow = if (a ^ result) & (value ^ result) & 0x80 != 0 { FLAG_PV } else { 0 };
+        let c = if sum > 0xFF { FLAG_C } else { 0 };
+        self.set_f(sz53(result) | ((a ^ value ^ result) & FLAG_H) | overflow | c);
+        self.registers.a = result;
+    }
+
+    /// Sets the flags of `A - value - carry` and returns the difference without storing it.
+    fn sub8(&mut self, value: u8, carry: bool) -> u8 {
+        let a = self.registers.a;
+        let difference = (a as u16).wrapping_sub(value as u16).wrapping_sub(carry as u16);
+        let result = difference as u8;
+        let overflow = if (a ^ value) & (a ^ result) & 0x80 != 0 { FLAG_PV } else { 0 };
+        let c = if difference > 0xFF { FLAG_C } else { 0 };
+        self.set_f(sz53(result) | ((a ^ value ^ result) & FLAG_H) | overflow | FLAG_N | c);
+        result
+    }
+
+    fn inc8(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_add(1);
+        let half = if value & 0x0F == 0x0F { FLAG_H } else { 0 };
+        let overflow = if value == 0x7F { FLAG_PV } else { 0 };
+        self.set_f(sz53(result) | half | overflow | (self.registers.f & FLAG_C));
+        result
+    }
+
+    fn dec8(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_sub(1);
+        let half = if value & 0x0F == 0 { FLAG_H } else { 0 };
+        let overflow = if value == 0x80 { FLAG_PV } else { 0 };
+        self.set_f(sz53(result) | half | overflow | FLAG_N | (self.registers.f & FLAG_C));
+        result
+    }
+
+    fn add16(&mut self, index: Index, value: u16) {
+        let x = self.index_register(index);
+        let sum = x as u32 + value as u32;
+        let result = sum as u16;
+        self.registers.wz = x.wrapping_add(1);
+        let high = (result >> 8) as u8;
+        let half = ((x ^ value ^ result) >> 8) as u8 & FLAG_H;
+        let c = if sum > 0xFFFF { FLAG_C } else { 0 };
+        self.set_f((self.registers.f & (FLAG_S | FLAG_Z | FLAG_PV)) | (high & (FLAG_X | FLAG_Y)) | half | c);
+        self.set_index_register(index, result);
+    }
+
+    fn adc16(&mut self, value: u16) {
+        let hl = self.registers.hl();
+        let sum = hl as u32 + value as u32 + (self.registers.f & FLAG_C) as u32;
+        let result = sum as u16;
+        let overflow = if (hl ^ result) & (value ^ result) & 0x8000 != 0 { FLAG_PV } else { 0 };
+        self.set_flags16(hl, value, result, sum > 0xFFFF, overflow, 0);
+    }
+
+    fn sbc16(&mut self, value: u16) {
+        let hl = self.registers.hl();
+        let difference = (hl as u32).wrapping_sub(value as u32).wrapping_sub((self.registers.f & FLAG_C) as u32);
+        let result = difference as u16;
+        let overflow = if (hl ^ value) & (hl ^ result) & 0x8000 != 0 { FLAG_PV } else { 0 };
+        self.set_flags16(hl, value, result, difference > 0xFFFF, overflow, FLAG_N);
+    }
+
+    /// Flags of ADC HL and SBC HL, which store the result in HL.
+    fn set_flags16(&mut self, hl: u16, value: u16, result: u16, carry: bool, overflow: u8, n: u8) {
+        let high = (result >> 8) as u8;
+        let half = ((hl ^ value ^ result) >> 8) as u8 & FLAG_H;
+        let z = if result == 0 { FLAG_Z } else { 0 };
+        let c = if carry { FLAG_C } else { 0 };
+        self.set_f((high & (FLAG_S | FLAG_X | FLAG_Y)) | z | half | overflow | n | c);
+        self.registers.wz = hl.wrapping_add(1);
+        self.registers.set_hl(result);
+    }
+
+    /// RLCA, RRCA, RLA, RRA, DAA, CPL, SCF and CCF.
+    fn accumulator_operation(&mut self, y: u8) {
+        let a = self.registers.a;
+        let f = self.registers.f;
+        let carry = f & FLAG_C;
+        let keep = f & (FLAG_S | FLAG_Z | FLAG_PV);
+        match y {
+            0..=3 => {
+                let (result, c) = match y {
+                    0 => (a.rotate_left(1), a >> 7),
+                    1 => (a.rotate_right(1), a & 1),
+                    2 => (a << 1 | carry, a >> 7),
+                    _ => (a >> 1 | carry << 7, a & 1),
+                };
+                self.registers.a = result;
+                self.set_f(keep | (result & (FLAG_X | FLAG_Y)) | c);
+            },
+            4 => {
+                let low = a & 0x0F;
+                let mut correction = 0;
+                let mut c = 0;
+                if f & FLAG_H != 0 || low > 9 {
+                    correction |= 0x06;
+                }
+                if carry != 0 || a > 0x99 {
+                    correction |= 0x60;
+                    c = FLAG_C;
+                }
+                let (result, half) = if f & FLAG_N != 0 {
+                    (a.wrapping_sub(correction), f & FLAG_H != 0 && low < 6)
+                } else {
+                    (a.wrapping_add(correction), low > 9)
+                };
+                self.registers.a = result;
+                let half = if half { FLAG_H } else { 0 };
+                self.set_f(sz53p(result) | half | (f & FLAG_N) | c);
+            },
+            5 => {
+                self.registers.a = !a;
+                self.set_f((f & (FLAG_S | FLAG_Z | FLAG_PV | FLAG_C)) | (!a & (FLAG_X | FLAG_Y)) | FLAG_H | FLAG_N);
+            },
+            _ => {
+                let xy = ((self.q ^ f) | a) & (FLAG_X | FLAG_Y);
+                let flags = if y == 6 {
+                    FLAG_C
+                } else if carry != 0 {
+                    FLAG_H
+                } else {
+                    FLAG_C
+                };
+                self.set_f(keep | xy | flags);
+            },
+        }
+    }
+
+    /// RLC, RRC, RL, RR, SLA, SRA, SLL (undocumented, shifts in 1) and SRL.
+    fn rotate(&mut self, operation: u8, value: u8) -> u8 {
+        let carry = self.registers.f & FLAG_C;
+        let (result, c) = match operation {
+            0 => (value.rotate_left(1), value >> 7),
+            1 => (value.rotate_right(1), value & 1),
+            2 => (value << 1 | carry, value >> 7),
+            3 => (value >> 1 | carry << 7, value & 1),
+            4 => (value << 1, value >> 7),
+            5 => (value >> 1 | (value & 0x80), value & 1),
+            6 => (value << 1 | 1, value >> 7),
+            _ => (value >> 1, value & 1),
+        };
+        self.set_f(sz53p(result) | c);
+        result
+    }
+
+    /// BIT n: X and Y come from `xy_source`, which is the operand or MEMPTR for memory operands.
+    fn bit(&mut self, n: u8, value: u8, xy_source: u8) {
+        let result = value & (1 << n);
+        let zero = if result == 0 { FLAG_Z | FLAG_PV } else { 0 };
+        let sign = result & FLAG_S;
+        self.set_f((self.registers.f & FLAG_C) | FLAG_H | (xy_source & (FLAG_X | FLAG_Y)) | zero | sign);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ram::RAM;
+    use rs580_macros::asm8080;
+
+    fn z80(program: &[u8]) -> Z80<RAM> {
+        let mut m = Z80::new(RAM::default());
+        m.memory.set_range(0, program);
+        m
+    }
+
+    fn cycles(m: &mut Z80<RAM>, steps: usize) -> Vec<u32> {
+        (0..steps).map(|_| m.step().cycles()).collect()
+    }
+
+    #[test]
+    fn test_8080_program() {
+        let program = asm8080! {
+                LXI SP,100h;
+                MVI B,10;
+                XRA A;
+            again:
+                ADD B;
+                DCR B;
+                JNZ again;
+                STA 80h;
+                HLT
+        };
+        let mut m = Z80::new(RAM::default());
+        m.memory.set_range(program.origin, &program.bytes);
+        while !m.halted {
+            m.step();
+        }
+        assert_eq!(m.registers.a, 55);
+        assert_eq!(m.memory.get_u8(0x80), 55);
+        assert_eq!(m.cycles, 10 + 7 + 4 + 10 * (4 + 4 + 10) + 13 + 4);
+    }
+
+    #[test]
+    fn test_all_opcodes() {
+        let prefixes: [&[u8]; 7] = [&[], &[0xCB], &[0xDD], &[0xED], &[0xFD], &[0xDD, 0xCB, 0x01], &[0xFD, 0xCB, 0xFF]];
+        for prefix in &prefixes {
+            for opcode in 0..=255 {
+                let mut m = z80(prefix);
+                m.memory.set_range(prefix.len() as u16, &[opcode, 0x34, 0x12]);
+                let cycles = m.step().cycles();
+                // Up to DD DD INC (IX+d)
+                assert!((4..=27).contains(&cycles), "{:02X?} {:02X}: {}", prefix, opcode, cycles);
+            }
+        }
+    }
+
+    #[test]
+    fn test_index_registers() {
+        let mut m = z80(&[
+            0xDD, 0x21, 0x00, 0x20, // LD IX,2000h
+            0xDD, 0x36, 0x05, 0x7F, // LD (IX+5),7Fh
+            0xDD, 0x34, 0x05,       // INC (IX+5)
+            0xDD, 0x7E, 0x05,       // LD A,(IX+5)
+            0xFD, 0x21, 0x10, 0x20, // LD IY,2010h
+            0xFD, 0x77, 0xFB,       // LD (IY-5),A
+            0xDD, 0x26, 0x12,       // LD IXH,12h
+            0xDD, 0x6C,             // LD IXL,IXH
+            0xDD, 0x66, 0x05,       // LD H,(IX+5)
+        ]);
+        assert_eq!(cycles(&mut m, 9), [14, 19, 23, 19, 14, 19, 11, 8, 19]);
+        assert_eq!(m.memory.get_u8(0x2005), 0x80);
+        assert_eq!(m.registers.f, FLAG_S | FLAG_H | FLAG_PV);
+        assert_eq!(m.registers.a, 0x80);
+        assert_eq!(m.memory.get_u8(0x200B), 0x80);
+        assert_eq!(m.registers.ix, 0x1212);
+        assert_eq!(m.registers.h, 0x00);
+        assert_eq!(m.registers.wz, 0x1217);
+    }
+
+    #[test]
+    fn test_bit_operations() {
+        let mut m = z80(&[
+            0x06, 0x81,             // LD B,81h
+            0xCB, 0x00,             // RLC B
+            0xCB, 0x78,             // BIT 7,B
+            0x21, 0x00, 0x30,       // LD HL,3000h
+            0xCB, 0xC6,             // SET 0,(HL)
+            0xCB, 0x46,             // BIT 0,(HL)
+            0xDD, 0x21, 0xFF, 0x2F, // LD IX,2FFFh
+            0xDD, 0xCB, 0x01, 0x06, // RLC (IX+1)
+            0xDD, 0xCB, 0x01, 0x00, // RLC (IX+1),B
+            0xDD, 0xCB, 0x01, 0x56, // BIT 2,(IX+1)
+        ]);
+        assert_eq!(cycles(&mut m, 2), [7, 8]);
+        assert_eq!(m.registers.b, 0x03);
+        assert_eq!(m.registers.f, FLAG_PV | FLAG_C);
+        m.step();
+        assert_eq!(m.registers.f, FLAG_Z | FLAG_H | FLAG_PV | FLAG_C);
+        assert_eq!(cycles(&mut m, 3), [10, 15, 12]);
+        assert_eq!(m.registers.f, FLAG_H | FLAG_C);
+        assert_eq!(cycles(&mut m, 4), [14, 23, 23, 20]);
+        assert_eq!(m.memory.get_u8(0x3000), 0x04);
+        assert_eq!(m.registers.b, 0x04);
+        // X and Y come from the high byte of IX+1
+        assert_eq!(m.registers.f, FLAG_H | FLAG_Y);
+    }
+
+    #[test]
+    fn test_block_instructions() {
+        let mut m = z80(&[
+            0x21, 0x00, 0x10, // LD HL,1000h
+            0x11, 0x00, 0x20, // LD DE,2000h
+            0x01, 0x04, 0x00, // LD BC,4
+            0xED, 0xB0,       // LDIR
+            0x21, 0x00, 0x10, // LD HL,1000h
+            0x01, 0x10, 0x00, // LD BC,16
+            0x3E, 0x33,       // LD A,33h
+            0xED, 0xB1,       // CPIR
+        ]);
+        m.memory.set_range(0x1000, &[0x11, 0x22, 0x33, 0x44]);
+        assert_eq!(cycles(&mut m, 7), [10, 10, 10, 21, 21, 21, 16]);
+        assert_eq!(m.memory.get_range(0x2000, 0x2004), [0x11, 0x22, 0x33, 0x44]);
+        assert_eq!((m.registers.bc(), m.registers.de(), m.registers.hl()), (0, 0x2004, 0x1004));
+        assert_eq!(m.registers.f & FLAG_PV, 0);
+        assert_eq!(cycles(&mut m, 6), [10, 10, 7, 21, 21, 16]);
+        assert_eq!((m.registers.bc(), m.registers.hl()), (13, 0x1003));
+        assert_eq!(m.registers.f & (FLAG_Z | FLAG_PV | FLAG_N), FLAG_Z | FLAG_PV | FLAG_N);
+    }
+
+    #[test]
+    fn test_interrupts() {
+        let mut m = z80(&[
+            0x31, 0x00, 0x01, // LD SP,100h
+            0xED, 0x56,       // IM 1
+            0xFB,             // EI
+            0x00,             // NOP
+            0x76,             // HALT
+        ]);
+        m.memory.set_range(0x38, &[0xFB, 0xED, 0x4D]); // EI; RETI
+        m.memory.set_range(0x66, &[0xED, 0x45]); // RETN
+        m.memory.set_u16(0x0210, 0x0050);
+        for _ in 0..3 {
+            m.step();
+        }
+        m.interrupt(0xFF);
+        // Not accepted right after EI
+        assert_eq!(m.step(), StepOutcome::Executed(4));
+        assert_eq!(m.step(), StepOutcome::Interrupted(13));
+        assert_eq!((m.registers.pc, m.memory.get_u16(0xFE), m.iff1), (0x38, 7, false));
+        for _ in 0..2 {
+            m.step();
+        }
+        assert_eq!((m.registers.pc, m.iff1), (7, true));
+        assert_eq!(m.step(), StepOutcome::Halted(4));
+        assert_eq!(m.step(), StepOutcome::Halted(4));
+
+        m.interrupt_mode = 2;
+        m.registers.i = 0x02;
+        m.interrupt(0x10);
+        assert_eq!(m.step(), StepOutcome::Interrupted(19));
+        assert_eq!((m.registers.pc, m.memory.get_u16(0xFE), m.halted), (0x50, 8, false));
+
+        m.iff1 = true;
+        m.iff2 = true;
+        m.nmi();
+        assert_eq!(m.step(), StepOutcome::Interrupted(11));
+        assert_eq!((m.registers.pc, m.iff1, m.iff2), (0x66, false, true));
+        assert_eq!(m.step(), StepOutcome::Executed(14));
+        assert_eq!((m.registers.pc, m.iff1), (0x50, true));
+
+        m.interrupt_mode = 0;
+        m.interrupt(0xEF); // RST 28h
+        assert_eq!(m.step(), StepOutcome::Interrupted(13));
+        assert_eq!((m.registers.pc, m.memory.get_u16(m.registers.sp)), (0x28, 0x50));
+    }
+
+    #[test]
+    fn test_flags() {
+        let mut m = z80(&[
+            0x3E, 0x7F, // LD A,7Fh
+            0xC6, 0x01, // ADD A,1
+            0x3E, 0x00, // LD A,0
+            0xD6, 0x01, // SUB 1
+            0x3E, 0x15, // LD A,15h
+            0xD6, 0x06, // SUB 6
+            0x27,       // DAA
+            0x3E, 0x28, // LD A,28h
+            0x37,       // SCF
+            0xAF,       // XOR A
+            0x37,       // SCF
+        ]);
+        cycles(&mut m, 2);
+        assert_eq!((m.registers.a, m.registers.f), (0x80, FLAG_S | FLAG_H | FLAG_PV));
+        cycles(&mut m, 2);
+        assert_eq!((m.registers.a, m.registers.f), (0xFF, FLAG_S | FLAG_Y | FLAG_H | FLAG_X | FLAG_N | FLAG_C));
+        cycles(&mut m, 3);
+        assert_eq!((m.registers.a, m.registers.f), (0x09, FLAG_X | FLAG_PV | FLAG_N));
+        // SCF after an instruction not changing the flags ORs X and Y of F and A
+        cycles(&mut m, 2);
+        assert_eq!(m.registers.f, FLAG_Y | FLAG_X | FLAG_PV | FLAG_C);
+        // and takes them from A alone right after a flag change
+        cycles(&mut m, 2);
+        assert_eq!(m.registers.f, FLAG_Z | FLAG_PV | FLAG_C);
+    }
+
+    #[test]
+    fn test_alternate_registers_and_refresh() {
+        let mut m = z80(&[
+            0x01, 0x34, 0x12, // LD BC,1234h
+            0xD9,             // EXX
+            0x01, 0x78, 0x56, // LD BC,5678h
+            0x08,             // EX AF,AF'
+            0xD9,             // EXX
+            0xED, 0x5F,       // LD A,R
+            0xDD, 0x00,       // NOP with a prefix
+        ]);
+        m.registers.set_af(0x0101);
+        for _ in 0..6 {
+            m.step();
+        }
+        assert_eq!((m.registers.bc(), m.registers.bc_alt), (0x1234, 0x5678));
+        assert_eq!(m.registers.af_alt, 0x0101);
+        // LD A,R sees the fetches of ED and 5Fh
+        assert_eq!(m.registers.a, 7);
+        m.registers.r = 0xFF;
+        assert_eq!(m.step(), StepOutcome::Executed(8));
+        assert_eq!(m.registers.r, 0x81);
+    }
+}
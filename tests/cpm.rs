@@ -0,0 +1,203 @@
+//! Runs CP/M processor exercisers: TST8080, 8080PRE, CPUTEST and 8080EXM on the 8080,
+//! ZEXDOC and ZEXALL on the Z80.
+//!
+//! The `.COM` files are not distributed with the crate, so the exercisers are ignored by default
+//! and only the harness itself is checked. Put the files into `tests/cpm/` or a directory named
+//! by the `RS580_CPM_DIR` environment variable and run them with
+//!
+//! ```text
+//! cargo test --release --test cpm -- --include-ignored
+//! ```
+//!
+//! 8080EXM, ZEXDOC and ZEXALL run for billions of T-states.
+
+use std::path::PathBuf;
+use rs580::{Machine, Memory, Z80, RAM};
+use rs580_macros::asm8080;
+
+/// BDOS entry point. Programs call it and read the top of the TPA from the jump at it.
+const BDOS: u16 = 0x0005;
+/// Where the jump at `BDOS` leads: a RET, as console output is handled before it.
+const BDOS_RET: u16 = 0xFE00;
+const TPA: u16 = 0x0100;
+
+/// Memory with the image loaded into the TPA and a BDOS stub.
+fn cpm_memory(image: &[u8]) -> RAM {
+    let mut memory = RAM::default();
+    memory.set_u8(0x0000, 0x76); // HLT, only reached on a stray RST 0
+    memory.set_range(BDOS, &[0xC3, BDOS_RET as u8, (BDOS_RET >> 8) as u8]);
+    memory.set_u8(BDOS_RET, 0xC9);
+    memory.set_range(TPA, image);
+    memory
+}
+
+/// Runs a `.COM` image until it jumps to the warm boot at 0000h. Returns the console output.
+fn run_com(image: &[u8], max_cycles: u64) -> Result<String, String> {
+    let mut m = Machine::new(cpm_memory(image));
+    m.registers.pc = TPA;
+    m.registers.sp = BDOS_RET;
+
+    let mut output = String::new();
+    while m.cycles < max_cycles {
+        if m.registers.pc == BDOS {
+            let r = &m.registers;
+            bdos(&m.memory, r.c, (r.d as u16) << 8 | r.e as u16, &mut output)?;
+        }
+        m.step().map_err(|error| format!("{}\n{}", error, output))?;
+        if m.registers.pc == 0x0000 {
+            return Ok(output);
+        }
+        if m.halted {
+            return Err(format!("halted at {:04X}\n{}", m.registers.pc.wrapping_sub(1), output));
+        }
+    }
+    Err(format!("no warm boot after {} T-states\n{}", max_cycles, output))
+}
+
+/// `run_com` for the Z80.
+fn run_z80_com(image: &[u8], max_cycles: u64) -> Result<String, String> {
+    let mut m = Z80::new(cpm_memory(image));
+    m.registers.pc = TPA;
+    m.registers.sp = BDOS_RET;
+
+    let mut output = String::new();
+    while m.cycles < max_cycles {
+        if m.registers.pc == BDOS {
+            bdos(&m.memory, m.registers.c, m.registers.de(), &mut output)?;
+        }
+        m.step();
+        if m.registers.pc == 0x0000 {
+            return Ok(output);
+        }
+        if m.halted {
+            return Err(format!("halted at {:04X}\n{}", m.registers.pc.wrapping_sub(1), output));
+        }
+    }
+    Err(format!("no warm boot after {} T-states\n{}", max_cycles, output))
+}
+
+/// Console output functions: 2 writes E, 9 writes the string at DE up to `$`.
+fn bdos(memory: &RAM, function: u8, de: u16, output: &mut String) -> Result<(), String> {
+    match function {
+        2 => output.push(de as u8 as char),
+        9 => {
+            let mut address = de;
+            loop {
+                let c = memory.get_u8(address);
+                if c == b'$' {
+                    break;
+                }
+                output.push(c as char);
+                address = address.wrapping_add(1);
+            }
+        },
+        function => return Err(format!("unsupported BDOS function {}", function)),
+    }
+    Ok(())
+}
+
+fn load(name: &str) -> Vec<u8> {
+    let dir = std::env::var_os("RS580_CPM_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("cpm"));
+    let path = dir.join(name);
+    std::fs::read(&path).unwrap_or_else(|error| panic!("{}: {}", path.display(), error))
+}
+
+fn run_exerciser(name: &str, max_cycles: u64, passed: impl Fn(&str) -> bool) {
+    run_exerciser_with(run_com, name, max_cycles, passed);
+}
+
+fn run_exerciser_with(
+    run: fn(&[u8], u64) -> Result<String, String>,
+    name: &str, max_cycles: u64, passed: impl Fn(&str) -> bool,
+) {
+    let image = load(name);
+    let output = run(&image, max_cycles).unwrap_or_else(|message| panic!("{}: {}", name, message));
+    assert!(passed(&output), "{} failed:\n{}", name, output);
+}
+
+#[test]
+fn test_harness() {
+    let program = asm8080! {
+            ORG 100h;
+            MVI A,12h;
+            STC;
+            PUSH PSW;
+            POP B;
+            MOV A,B;
+            CPI 12h;
+            JNZ fail;
+            MOV A,C;
+            CPI 03h;
+            JNZ fail;
+            MVI C,9;
+            LXI D,ok;
+            CALL 5;
+            MVI C,2;
+            MVI E,"!";
+            CALL 5;
+            JMP 0;
+        fail:
+            MVI C,9;
+            LXI D,error;
+            CALL 5;
+            JMP 0;
+        ok:
+            DB "PUSH PSW OK$";
+        error:
+            DB "PUSH PSW ERROR$"
+    };
+    assert_eq!(program.origin, TPA);
+    assert_eq!(run_com(&program.bytes, 10_000), Ok("PUSH PSW OK!".to_string()));
+}
+
+#[test]
+#[ignore]
+fn test_tst8080() {
+    run_exerciser("TST8080.COM", 10_000_000, |output| output.contains("CPU IS OPERATIONAL"));
+}
+
+#[test]
+#[ignore]
+fn test_8080pre() {
+    run_exerciser("8080PRE.COM", 10_000_000, |output| output.contains("Preliminary tests complete"));
+}
+
+#[test]
+#[ignore]
+fn test_cputest() {
+    run_exerciser("CPUTEST.COM", 1_000_000_000, |output| output.contains("CPU TESTS OK"));
+}
+
+#[test]
+#[ignore]
+fn test_8080exm() {
+    run_exerciser("8080EXM.COM", 100_000_000_000, |output| !output.contains("ERROR") && output.contains("Tests complete"));
+}
+
+#[test]
+fn test_z80_harness() {
+    let program = asm8080! {
+            ORG 100h;
+            MVI C,9;
+            LXI D,message;
+            CALL 5;
+            JMP 0;
+        message:
+            DB "Z80 OK$"
+    };
+    assert_eq!(run_z80_com(&program.bytes, 10_000), Ok("Z80 OK".to_string()));
+}
+
+#[test]
+#[ignore]
+fn test_zexdoc() {
+    run_exerciser_with(run_z80_com, "ZEXDOC.COM", 100_000_000_000, |output| !output.contains("ERROR") && output.contains("Tests complete"));
+}
+
+#[test]
+#[ignore]
+fn test_zexall() {
+    run_exerciser_with(run_z80_com, "ZEXALL.COM", 100_000_000_000, |output| !output.contains("ERROR") && output.contains("Tests complete"));
+}
@@ -0,0 +1,256 @@
+//! Runs per-instruction JSON test vectors in the style of SingleStepTests.
+//!
+//! A vector file holds an array of tests:
+//!
+//! ```text
+//! {
+//!   "name": "80 0001",
+//!   "initial": { "pc": 256, "sp": 0, "a": 1, "b": 2, "c": 0, "d": 0, "e": 0, "f": 2, "h": 0, "l": 0,
+//!                "ram": [[256, 128]] },
+//!   "final":   { ... same fields ... },
+//!   "cycles":  [[256, 128, "r-m-"], ...],
+//!   "ports":   [[16, 255, "r"]]
+//! }
+//! ```
+//!
+//! `f` is the flags byte, compared flag by flag. `cycles` is optional and lists bus transactions;
+//! entries without data are idle T-states and skipped. The type string has `r` or `w` in the first
+//! two positions and `m` (memory) or `i` (I/O) in the third or fourth. `ports` gives the values
+//! returned by IN.
+//!
+//! Besides the vectors below, every `*.json` file in `tests/single_step/` or the directory named
+//! by `RS580_SINGLE_STEP_DIR` is run.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use rs580::cpu::Registers;
+use rs580::{BusAccess, Io, Machine, Memory, RAM};
+use serde_json::Value;
+
+struct PortValues(HashMap<u8, u8>);
+
+impl Io for PortValues {
+    fn inp(&mut self, port: u8) -> u8 {
+        self.0.get(&port).cloned().unwrap_or(0xFF)
+    }
+
+    fn out(&mut self, _port: u8, _value: u8) {
+    }
+}
+
+fn number(value: &Value, field: &str) -> Result<u16, String> {
+    value.get(field)
+        .and_then(Value::as_u64)
+        .map(|n| n as u16)
+        .ok_or_else(|| format!("missing {}", field))
+}
+
+fn registers(state: &Value) -> Result<Registers, String> {
+    let byte = |field| number(state, field).map(|n| n as u8);
+    let mut registers = Registers {
+        a: byte("a")?,
+        b: byte("b")?,
+        c: byte("c")?,
+        d: byte("d")?,
+        e: byte("e")?,
+        h: byte("h")?,
+        l: byte("l")?,
+        pc: number(state, "pc")?,
+        sp: number(state, "sp")?,
+        ..Registers::default()
+    };
+    registers.set_flags_byte(byte("f")?);
+    Ok(registers)
+}
+
+fn pairs(value: Option<&Value>) -> Vec<&Vec<Value>> {
+    value.and_then(Value::as_array)
+        .map(|items| items.iter().filter_map(Value::as_array).collect())
+        .unwrap_or_default()
+}
+
+fn ram(state: &Value) -> Vec<(u16, u8)> {
+    pairs(state.get("ram")).into_iter()
+        .filter_map(|pair| Some((pair.first()?.as_u64()? as u16, pair.get(1)?.as_u64()? as u8)))
+        .collect()
+}
+
+/// Expected bus transactions as they appear in `Machine::bus_log`.
+fn bus_cycles(test: &Value) -> Option<Vec<BusAccess>> {
+    let cycles = test.get("cycles")?;
+    let accesses = pairs(Some(cycles)).into_iter().filter_map(|cycle| {
+        let address = cycle.first()?.as_u64()? as u16;
+        let value = cycle.get(1)?.as_u64()? as u8;
+        let kind = cycle.get(2)?.as_str()?.as_bytes();
+        let read = kind.first() == Some(&b'r');
+        let write = kind.get(1) == Some(&b'w');
+        let io = kind.contains(&b'i');
+        Some(match (read, write, io) {
+            (true, _, false) => BusAccess::Read { address, value },
+            (_, true, false) => BusAccess::Write { address, value, old: 0 },
+            (true, _, true) => BusAccess::In { port: address as u8, value },
+            (_, true, true) => BusAccess::Out { port: address as u8, value },
+            _ => return None,
+        })
+    });
+    Some(accesses.collect())
+}
+
+/// Fetches count as reads and the overwritten value is not part of the vectors.
+fn comparable(access: &BusAccess) -> BusAccess {
+    match *access {
+        BusAccess::Fetch { address, value } => BusAccess::Read { address, value },
+        BusAccess::Write { address, value, .. } => BusAccess::Write { address, value, old: 0 },
+        access => access,
+    }
+}
+
+/// Runs one test and describes every mismatch.
+fn run_test(test: &Value) -> Result<Vec<String>, String> {
+    let initial = test.get("initial").ok_or("missing initial")?;
+    let expected = test.get("final").ok_or("missing final")?;
+
+    let ports: HashMap<u8, u8> = pairs(test.get("ports")).into_iter()
+        .filter_map(|port| Some((port.first()?.as_u64()? as u8, port.get(1)?.as_u64()? as u8)))
+        .collect();
+    let mut m = Machine::new(RAM::default()).with_io(Box::new(PortValues(ports)));
+    m.registers = registers(initial)?;
+    for (address, value) in ram(initial) {
+        m.memory.set_u8(address, value);
+    }
+    let expected_bus = bus_cycles(test);
+    if expected_bus.is_some() {
+        m.bus_log = Some(Vec::new());
+    }
+    m.step().map_err(|error| error.to_string())?;
+
+    let mut mismatches = Vec::new();
+    let want = registers(expected)?;
+    let got = &m.registers;
+    macro_rules! compare {
+        ($($field:ident),*) => {
+            $(
+                if want.$field != got.$field {
+                    mismatches.push(format!("{}: expected {:X?}, got {:X?}", stringify!($field), want.$field, got.$field));
+                }
+            )*
+        };
+    }
+    compare!(a, b, c, d, e, h, l, pc, sp, flag_s, flag_z, flag_ac, flag_p, flag_c);
+    for (address, value) in ram(expected) {
+        let actual = m.memory.get_u8(address);
+        if actual != value {
+            mismatches.push(format!("ram[{:04X}]: expected {:02X}, got {:02X}", address, value, actual));
+        }
+    }
+    if let Some(expected_bus) = expected_bus {
+        let bus: Vec<BusAccess> = m.bus_log.unwrap_or_default().iter().map(comparable).collect();
+        if bus != expected_bus {
+            mismatches.push(format!("bus: expected {:X?}, got {:X?}", expected_bus, bus));
+        }
+    }
+    Ok(mismatches)
+}
+
+/// Runs all tests of a vector file and returns failure descriptions.
+fn run_vectors(json: &str) -> Vec<String> {
+    let tests: Value = match serde_json::from_str(json) {
+        Ok(tests) => tests,
+        Err(error) => return vec![format!("bad JSON: {}", error)],
+    };
+    let tests = match tests.as_array() {
+        Some(tests) => tests,
+        None => return vec!["an array of tests expected".to_string()],
+    };
+    let mut failures = Vec::new();
+    for (index, test) in tests.iter().enumerate() {
+        let name = test.get("name").and_then(Value::as_str).map_or_else(|| format!("#{}", index), str::to_string);
+        match run_test(test) {
+            Ok(mismatches) if mismatches.is_empty() => {},
+            Ok(mismatches) => failures.push(format!("{}: {}", name, mismatches.join("; "))),
+            Err(error) => failures.push(format!("{}: {}", name, error)),
+        }
+    }
+    failures
+}
+
+/// ADD B, SBB M, PUSH PSW, DAA and IN covering flags, memory writes and I/O.
+const SAMPLE: &str = r#"[
+    {
+        "name": "80 half carry",
+        "initial": { "pc": 256, "sp": 0, "a": 58, "b": 6, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0,
+                     "ram": [[256, 128]] },
+        "final":   { "pc": 257, "sp": 0, "a": 64, "b": 6, "c": 0, "d": 0, "e": 0, "f": 16, "h": 0, "l": 0,
+                     "ram": [[256, 128]] },
+        "cycles": [[256, 128, "r-m-"], [null, null, "----"]]
+    },
+    {
+        "name": "9E borrow",
+        "initial": { "pc": 256, "sp": 0, "a": 16, "b": 0, "c": 0, "d": 0, "e": 0, "f": 1, "h": 2, "l": 0,
+                     "ram": [[256, 158], [512, 255]] },
+        "final":   { "pc": 257, "sp": 0, "a": 16, "b": 0, "c": 0, "d": 0, "e": 0, "f": 1, "h": 2, "l": 0,
+                     "ram": [[256, 158], [512, 255]] },
+        "cycles": [[256, 158, "r-m-"], [512, 255, "r-m-"]]
+    },
+    {
+        "name": "F5 wraps the stack",
+        "initial": { "pc": 256, "sp": 1, "a": 18, "b": 0, "c": 0, "d": 0, "e": 0, "f": 193, "h": 0, "l": 0,
+                     "ram": [[256, 245]] },
+        "final":   { "pc": 257, "sp": 65535, "a": 18, "b": 0, "c": 0, "d": 0, "e": 0, "f": 193, "h": 0, "l": 0,
+                     "ram": [[0, 18], [65535, 195]] },
+        "cycles": [[256, 245, "r-m-"], [0, 18, "-wm-"], [65535, 195, "-wm-"]]
+    },
+    {
+        "name": "27 keeps carry",
+        "initial": { "pc": 256, "sp": 0, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 1, "h": 0, "l": 0,
+                     "ram": [[256, 39]] },
+        "final":   { "pc": 257, "sp": 0, "a": 96, "b": 0, "c": 0, "d": 0, "e": 0, "f": 5, "h": 0, "l": 0 }
+    },
+    {
+        "name": "DB 10",
+        "initial": { "pc": 256, "sp": 0, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0,
+                     "ram": [[256, 219], [257, 16]] },
+        "final":   { "pc": 258, "sp": 0, "a": 90, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0 },
+        "ports": [[16, 90, "r"]],
+        "cycles": [[256, 219, "r-m-"], [257, 16, "r-m-"], [4112, 90, "r--i"]]
+    }
+]"#;
+
+#[test]
+fn test_sample_vectors() {
+    assert_eq!(run_vectors(SAMPLE), Vec::<String>::new());
+}
+
+#[test]
+fn test_mismatch_report() {
+    let broken = SAMPLE.replacen(r#""a": 64, "b": 6, "c": 0, "d": 0, "e": 0, "f": 16"#, r#""a": 65, "b": 6, "c": 0, "d": 0, "e": 0, "f": 17"#, 1);
+    assert_eq!(run_vectors(&broken), vec!["80 half carry: a: expected 41, got 40; flag_c: expected true, got false".to_string()]);
+}
+
+#[test]
+fn test_local_vectors() {
+    let dir = std::env::var_os("RS580_SINGLE_STEP_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("single_step"));
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(_) => {
+            eprintln!("skipping local vectors: {} not found", dir.display());
+            return;
+        },
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+        .collect();
+    paths.sort();
+
+    let mut failures = Vec::new();
+    for path in paths {
+        let json = std::fs::read_to_string(&path).unwrap_or_else(|error| panic!("{}: {}", path.display(), error));
+        for failure in run_vectors(&json) {
+            failures.push(format!("{}: {}", path.display(), failure));
+        }
+    }
+    assert!(failures.is_empty(), "{} failures, first ones:\n{}", failures.len(), failures[..failures.len().min(20)].join("\n"));
+}